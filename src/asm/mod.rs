@@ -0,0 +1,563 @@
+mod tests;
+
+use std::collections::HashMap;
+use std::fmt::{Debug, Display};
+
+use num_traits::FromPrimitive;
+
+use crate::object::Object;
+use crate::parser::{Loc, Range};
+use crate::vm::{varint, Code, Const, Global, Op};
+
+// Assembles a listing, as disasm::listing writes them, into a module object.
+// Everything survives the trip both ways.
+//
+//   module app              ; the name, if not the default
+//   file "app.lia"          ; optional, as are:
+//   deps app.util
+//   exports main
+//   consts:
+//          0 "main"         ; the index is optional, but must be right
+//          1 (a \-3 "b" 1.5)
+//   globals:
+//          0 builtins/print
+//   code:
+//            at 1:1-1:20        ; source map entries: (defn main [] ...)
+//   00000000 MakeFunction 0 0 @end    ; addresses are ignored
+//            at 1:15-1:19
+//            LoadGlobal 0
+//            Return
+//   end:     Drop
+//
+// Jump operands (and MakeFunction's body length) are either the distance
+// as encoded, or @label.  Jumps are padded as the compiler pads them.  An
+// at line maps the next op on (or the end, if none follows) to a range in
+// the source, from line:col up to line:col, 1-based.
+pub(crate) fn assemble(text: &str, name: &str) -> Result<Object, Error> {
+    let mut object = Object {
+        name: name.to_string(),
+        deps: vec![],
+        exports: None,
+        code: Code::new(vec![]),
+    };
+    let mut section = None;
+    let mut items = vec![];
+    for (i, text) in text.lines().enumerate() {
+        let line = i + 1;
+        let err = |message: String| Error { line, message };
+        let text = strip_comment(text).trim();
+        if text.is_empty() {
+            continue;
+        }
+        if let Some(s) = text.strip_suffix(':') {
+            if matches!(s, "consts" | "globals" | "code") {
+                section = Some(s.to_string());
+                continue;
+            }
+        }
+        match section.as_deref() {
+            None => header(&mut object, text).map_err(err)?,
+            Some("consts") => {
+                let c = constant(text, object.code.consts.len()).map_err(err)?;
+                object.code.consts.push(c);
+            }
+            Some("globals") => {
+                let g = global(text, object.code.globals.len()).map_err(err)?;
+                object.code.globals.push(g);
+            }
+            _ => items.extend(code_line(text, line)?),
+        }
+    }
+    (object.code.bytes, object.code.source_map) = Assembler::new(&items)?.assemble()?;
+    Ok(object)
+}
+
+pub(crate) struct Error {
+    // 1-based.
+    pub(crate) line: usize,
+    pub(crate) message: String,
+}
+
+impl Display for Error {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "line {}: {}", self.line, self.message)
+    }
+}
+
+impl Debug for Error {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        Display::fmt(self, f)
+    }
+}
+
+impl std::error::Error for Error {}
+
+enum Item {
+    Label(String, usize),
+    At(Range),
+    Op(Op, Vec<Operand>, usize),
+}
+
+enum Operand {
+    Number(u64),
+    Signed(i64),
+    Label(String),
+    // (callee slot, slot)
+    Capture(usize, usize),
+}
+
+// Everything from a ; that isn't in a string.
+fn strip_comment(line: &str) -> &str {
+    let mut in_string = false;
+    let mut escaped = false;
+    for (i, c) in line.char_indices() {
+        match c {
+            _ if escaped => escaped = false,
+            '\\' if in_string => escaped = true,
+            '"' => in_string = !in_string,
+            ';' if !in_string => return &line[..i],
+            _ => {}
+        }
+    }
+    line
+}
+
+fn header(object: &mut Object, text: &str) -> Result<(), String> {
+    let (keyword, rest) = text.split_once(char::is_whitespace).unwrap_or((text, ""));
+    let words = || {
+        rest.split_whitespace()
+            .map(str::to_string)
+            .collect::<Vec<_>>()
+    };
+    match keyword {
+        "module" => match rest.split_whitespace().collect::<Vec<_>>()[..] {
+            [name] => object.name = name.to_string(),
+            _ => return Err("module takes one name".to_string()),
+        },
+        "file" => match Reader::new(rest).all()?[..] {
+            [Const::String(ref file)] => object.code.file = Some(file.clone()),
+            _ => return Err("file takes one string".to_string()),
+        },
+        "deps" => object.deps = words(),
+        "exports" => object.exports = Some(words()),
+        _ => return Err(format!("unknown header {keyword}")),
+    }
+    Ok(())
+}
+
+// [index] datum
+fn constant(text: &str, index: usize) -> Result<Const, String> {
+    match Reader::new(text).all()? {
+        cs if cs.len() == 1 => Ok(cs.into_iter().next().unwrap()),
+        cs if cs.len() == 2 => match cs[0] {
+            Const::Integer(i) if usize::try_from(i) == Ok(index) => {
+                Ok(cs.into_iter().nth(1).unwrap())
+            }
+            _ => Err(format!("expected constant {index}")),
+        },
+        _ => Err("expected one constant".to_string()),
+    }
+}
+
+// [index] name, or [index] module/name
+fn global(text: &str, index: usize) -> Result<Global, String> {
+    let name = match text.split_whitespace().collect::<Vec<_>>()[..] {
+        [name] => name,
+        [i, name] if i.parse() == Ok(index) => name,
+        [_, _] => return Err(format!("expected global {index}")),
+        _ => return Err("expected one global".to_string()),
+    };
+    let (module, name) = qualified(name)?;
+    Ok(Global {
+        module: module.map(str::to_string),
+        name: name.to_string(),
+    })
+}
+
+// name, or module/name: the one / there can be, with something either side.
+fn qualified(name: &str) -> Result<(Option<&str>, &str), String> {
+    match name.split('/').collect::<Vec<_>>()[..] {
+        [s] => Ok((None, s)),
+        [m, s] if !m.is_empty() && !s.is_empty() => Ok((Some(m), s)),
+        _ => Err(format!("bad name {name}")),
+    }
+}
+
+// [address] [label:]... [Op operand...]
+fn code_line(text: &str, line: usize) -> Result<Vec<Item>, Error> {
+    let err = |message: String| Error { line, message };
+    let mut words = text.split_whitespace().peekable();
+    if let Some(w) = words.peek() {
+        if w.len() == 8 && w.chars().all(|c| c.is_ascii_hexdigit()) {
+            words.next();
+        }
+    }
+    let mut items = vec![];
+    while let Some(label) = words.peek().and_then(|w| w.strip_suffix(':')) {
+        items.push(Item::Label(label.to_string(), line));
+        words.next();
+    }
+    let Some(name) = words.next() else {
+        return Ok(items);
+    };
+    if name == "at" {
+        let range = match words.collect::<Vec<_>>()[..] {
+            [w] => {
+                range(w).ok_or_else(|| err(format!("bad range {w}; expected line:col-line:col")))?
+            }
+            _ => return Err(err("at takes one range".to_string())),
+        };
+        items.push(Item::At(range));
+        return Ok(items);
+    }
+    let op = (0..=u8::MAX)
+        .filter_map(Op::from_u8)
+        .find(|op| op.to_string() == name)
+        .ok_or_else(|| err(format!("unknown op {name}")))?;
+    let words = words.collect::<Vec<_>>();
+    let number = |w: &str| {
+        w.parse::<u64>()
+            .map(Operand::Number)
+            .map_err(|_| err(format!("bad operand {w} to {op}")))
+    };
+    let target = |w: &str| match w.strip_prefix('@') {
+        Some(label) => Ok(Operand::Label(label.to_string())),
+        None => number(w),
+    };
+    let arity = |n: usize| {
+        if words.len() == n {
+            Ok(())
+        } else {
+            Err(err(format!("{op} takes {n} operand(s)")))
+        }
+    };
+    let operands = match op {
+        Op::Nop
+        | Op::ImmediateBooleanTrue
+        | Op::ImmediateBooleanFalse
+        | Op::Drop
+        | Op::Eval
+        | Op::Return
+        | Op::Dup
+        | Op::Define
+        | Op::DefineMacro
+        | Op::PopHandler
+        | Op::Throw
+        | Op::ReceiveStart
+        | Op::ReceiveAccept => {
+            arity(0)?;
+            vec![]
+        }
+        Op::ImmediateInteger => {
+            arity(1)?;
+            let i = words[0]
+                .parse::<i64>()
+                .map_err(|_| err(format!("bad operand {} to {op}", words[0])))?;
+            vec![Operand::Signed(i)]
+        }
+        Op::Constant
+        | Op::ConsList
+        | Op::ConsVec
+        | Op::ConcatList
+        | Op::ConcatVec
+        | Op::Call
+        | Op::LoadLocal
+        | Op::StoreLocal
+        | Op::LoadGlobal
+        | Op::StoreGlobal => {
+            arity(1)?;
+            vec![number(words[0])?]
+        }
+        Op::JumpRelative
+        | Op::JumpForward
+        | Op::JumpIfFalse
+        | Op::PushHandler
+        | Op::ReceiveNext => {
+            arity(1)?;
+            vec![target(words[0])?]
+        }
+        Op::Match => {
+            arity(2)?;
+            vec![number(words[0])?, number(words[1])?]
+        }
+        Op::MakeFunction => {
+            // name nparams callee_slot:slot... len
+            let [name, nparams, captures @ .., len] = &words[..] else {
+                return Err(err(format!("{op} takes at least 3 operands")));
+            };
+            let mut operands = vec![
+                number(name)?,
+                number(nparams)?,
+                Operand::Number(captures.len() as u64),
+            ];
+            for c in captures {
+                let capture = c
+                    .split_once(':')
+                    .and_then(|(a, b)| Some(Operand::Capture(a.parse().ok()?, b.parse().ok()?)))
+                    .ok_or_else(|| err(format!("bad capture {c}; expected callee_slot:slot")))?;
+                operands.push(capture);
+            }
+            operands.push(target(len)?);
+            operands
+        }
+    };
+    items.push(Item::Op(op, operands, line));
+    Ok(items)
+}
+
+// (offset, range), as in Code.
+type SourceMap = Vec<(usize, Range)>;
+
+// What a pass makes: the bytes, the source map, and where the labels fell.
+type Pass<'i> = (Vec<u8>, SourceMap, HashMap<&'i str, usize>);
+
+// line:col-line:col, 1-based.
+fn range(w: &str) -> Option<Range> {
+    let loc = |l: &str| {
+        let (line, col) = l.split_once(':')?;
+        let (line, col) = (line.parse::<usize>().ok()?, col.parse::<usize>().ok()?);
+        Some(Loc(line.checked_sub(1)?, col.checked_sub(1)?))
+    };
+    let (start, end) = w.split_once('-')?;
+    Some(Range(loc(start)?, loc(end)?))
+}
+
+struct Assembler<'i> {
+    items: &'i [Item],
+    labels: HashMap<&'i str, usize>,
+}
+
+impl<'i> Assembler<'i> {
+    fn new(items: &'i [Item]) -> Result<Self, Error> {
+        let mut labels = HashMap::new();
+        for item in items {
+            if let Item::Label(label, line) = item {
+                if labels.insert(label.as_str(), 0).is_some() {
+                    return Err(Error {
+                        line: *line,
+                        message: format!("duplicate label {label}"),
+                    });
+                }
+            }
+        }
+        Ok(Assembler { items, labels })
+    }
+
+    // MakeFunction's length isn't padded, so where the labels fall can
+    // depend on where they fall.  Go round until they stop moving.
+    fn assemble(mut self) -> Result<(Vec<u8>, SourceMap), Error> {
+        for _ in 0..16 {
+            let (_, _, labels) = self.pass(false)?;
+            if labels == self.labels {
+                return self.pass(true).map(|(out, map, _)| (out, map));
+            }
+            self.labels = labels;
+        }
+        Err(Error {
+            line: 1,
+            message: "labels won't settle".to_string(),
+        })
+    }
+
+    // Until `check`, the labels are guesses, and bad distances are let go.
+    fn pass(&self, check: bool) -> Result<Pass<'i>, Error> {
+        let mut out = vec![];
+        let mut map = vec![];
+        let mut labels = HashMap::new();
+        for item in self.items {
+            let (op, operands, line) = match item {
+                Item::Label(label, _) => {
+                    labels.insert(label.as_str(), out.len());
+                    continue;
+                }
+                Item::At(range) => {
+                    map.push((out.len(), *range));
+                    continue;
+                }
+                Item::Op(op, operands, line) => (*op, operands, *line),
+            };
+            let err = |message: String| Error { line, message };
+            let at = out.len();
+            out.push(op as u8);
+            for operand in operands {
+                match operand {
+                    Operand::Number(n) if is_jump(op) => put_jump(&mut out, *n).map_err(err)?,
+                    Operand::Number(n) => varint::put(&mut out, *n),
+                    Operand::Signed(i) => varint::put_signed(&mut out, *i),
+                    Operand::Capture(a, b) => {
+                        varint::put(&mut out, *a as u64);
+                        varint::put(&mut out, *b as u64);
+                    }
+                    Operand::Label(label) => {
+                        let target = *self
+                            .labels
+                            .get(label.as_str())
+                            .ok_or_else(|| err(format!("no label {label}")))?;
+                        let ok = match op {
+                            Op::MakeFunction => put_length(&mut out, target),
+                            Op::JumpRelative => at
+                                .checked_sub(target)
+                                .map(|d| put_jump(&mut out, d as u64))
+                                .transpose()
+                                .map_err(err)?,
+                            _ => target
+                                .checked_sub(at)
+                                .map(|d| put_jump(&mut out, d as u64))
+                                .transpose()
+                                .map_err(err)?,
+                        };
+                        match ok {
+                            Some(()) => {}
+                            None if check => {
+                                return Err(err(format!("{op} can't reach {label} from here")))
+                            }
+                            None if is_jump(op) => put_jump(&mut out, 0).map_err(err)?,
+                            None => varint::put(&mut out, 0),
+                        }
+                    }
+                }
+            }
+        }
+        Ok((out, map, labels))
+    }
+}
+
+fn is_jump(op: Op) -> bool {
+    matches!(
+        op,
+        Op::JumpRelative | Op::JumpForward | Op::JumpIfFalse | Op::PushHandler | Op::ReceiveNext
+    )
+}
+
+fn put_jump(out: &mut Vec<u8>, n: u64) -> Result<(), String> {
+    let at = out.len();
+    out.resize(at + varint::JUMP_WIDTH, 0);
+    varint::put_padded(&mut out[at..], n).ok_or_else(|| format!("jump of {n} is too far"))
+}
+
+// MakeFunction's body runs from just after its length up to end, so how long
+// the length is depends on how long the body is.  Right on the boundary
+// there's no answer, and it's padded instead.
+fn put_length(out: &mut Vec<u8>, end: usize) -> Option<()> {
+    let at = out.len();
+    for width in 1..=10 {
+        let len = end.checked_sub(at + width)?;
+        let mut minimal = vec![];
+        varint::put(&mut minimal, len as u64);
+        if minimal.len() <= width {
+            out.resize(at + width, 0);
+            return varint::put_padded(&mut out[at..], len as u64);
+        }
+    }
+    None
+}
+
+// Constants, as Const's Display writes them.
+struct Reader<'t> {
+    text: &'t str,
+    at: usize,
+}
+
+impl<'t> Reader<'t> {
+    fn new(text: &'t str) -> Self {
+        Reader { text, at: 0 }
+    }
+
+    fn all(&mut self) -> Result<Vec<Const>, String> {
+        let mut cs = vec![];
+        while self.skip_space().is_some() {
+            cs.push(self.datum()?);
+        }
+        Ok(cs)
+    }
+
+    fn rest(&self) -> &'t str {
+        &self.text[self.at..]
+    }
+
+    fn skip_space(&mut self) -> Option<char> {
+        let rest = self.rest();
+        self.at += rest.len() - rest.trim_start().len();
+        self.rest().chars().next()
+    }
+
+    fn datum(&mut self) -> Result<Const, String> {
+        match self.skip_space() {
+            None => Err("expected a constant".to_string()),
+            Some('(') => self.seq(')').map(Const::List),
+            Some('[') => self.seq(']').map(Const::Vec),
+            Some('"') => self.string().map(Const::String),
+            Some(c @ (')' | ']')) => Err(format!("unexpected {c}")),
+            Some(_) => {
+                let rest = self.rest();
+                let len = rest
+                    .find(|c: char| c.is_whitespace() || "()[]\"".contains(c))
+                    .unwrap_or(rest.len());
+                self.at += len;
+                let atom = &rest[..len];
+                if let Some(s) = atom.strip_prefix('\\') {
+                    if s.contains('/') {
+                        return Err(format!("bad name {atom}"));
+                    }
+                    return Ok(Const::Symbol(None, s.to_string()));
+                }
+                if let Some(c) = Const::number(atom) {
+                    return Ok(c);
+                }
+                let (m, s) = qualified(atom)?;
+                Ok(Const::Symbol(m.map(str::to_string), s.to_string()))
+            }
+        }
+    }
+
+    fn seq(&mut self, close: char) -> Result<Vec<Const>, String> {
+        self.at += 1;
+        let mut cs = vec![];
+        loop {
+            match self.skip_space() {
+                None => return Err(format!("expected {close}")),
+                Some(c) if c == close => {
+                    self.at += 1;
+                    return Ok(cs);
+                }
+                Some(_) => cs.push(self.datum()?),
+            }
+        }
+    }
+
+    // Escaped as {:?} does it.
+    fn string(&mut self) -> Result<String, String> {
+        let mut chars = self.rest().char_indices().skip(1);
+        let mut s = String::new();
+        while let Some((i, c)) = chars.next() {
+            match c {
+                '"' => {
+                    self.at += i + 1;
+                    return Ok(s);
+                }
+                '\\' => s.push(match chars.next().map(|(_, c)| c) {
+                    Some('n') => '\n',
+                    Some('r') => '\r',
+                    Some('t') => '\t',
+                    Some('0') => '\0',
+                    Some(c @ ('\\' | '"' | '\'')) => c,
+                    Some('u') => {
+                        let hex = chars
+                            .by_ref()
+                            .map(|(_, c)| c)
+                            .skip_while(|&c| c == '{')
+                            .take_while(|&c| c != '}')
+                            .collect::<String>();
+                        u32::from_str_radix(&hex, 16)
+                            .ok()
+                            .and_then(char::from_u32)
+                            .ok_or_else(|| format!("bad escape \\u{{{hex}}}"))?
+                    }
+                    c => return Err(format!("bad escape \\{}", c.unwrap_or(' '))),
+                }),
+                c => s.push(c),
+            }
+        }
+        Err("unterminated string".to_string())
+    }
+}
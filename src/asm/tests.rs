@@ -0,0 +1,123 @@
+#![cfg(test)]
+
+use super::assemble;
+use crate::compiler::Compiler;
+use crate::disasm::listing;
+use crate::object::Object;
+use crate::parser::Document;
+use crate::vm::Vm;
+
+fn compiled(source: &str) -> Object {
+    let doc = source.parse::<Document>().unwrap();
+    let mut c = Compiler::new();
+    c.doc(&doc);
+    let mut code = c.finish().unwrap();
+    code.file = Some("test.lia".to_string());
+    Object {
+        name: "app".to_string(),
+        deps: vec!["app.util".to_string()],
+        exports: Some(vec![]),
+        code,
+    }
+}
+
+#[test]
+fn listings_roundtrip() {
+    for source in [
+        "(print \"hi\\n\\t\\\"there\\\"\")",
+        "(defn f [x] (fn [y] [x y])) ((f 1) 2)",
+        "(cond false 1 true (if true 2 3))",
+        "(and 1 (or false 2))",
+        "(try (throw 'oops) (catch e e) (finally 1))",
+        "`(a ~(list 1.5 -3) ~@[b/c])",
+        "(defmacro m [x] x) '(0.0 [\"s\"] (((x))))",
+        "(receive [a 'b] a _ 1 (after 10 2))",
+    ] {
+        let object = compiled(source);
+        assert!(!object.code.source_map.is_empty());
+        let text = listing(&object, None);
+        let back = assemble(&text, "other").unwrap_or_else(|e| panic!("{e}\n{text}"));
+        assert_eq!(object.code.bytes, back.code.bytes, "{text}");
+        assert_eq!(object.code.consts, back.code.consts, "{text}");
+        assert_eq!(object.code.globals, back.code.globals, "{text}");
+        assert_eq!(object.code.source_map, back.code.source_map, "{text}");
+        assert_eq!(object.code.file, back.code.file);
+        assert_eq!(object.name, back.name);
+        assert_eq!(object.deps, back.deps);
+        assert_eq!(object.exports, back.exports);
+        assert_eq!(text, listing(&back, None));
+        // The lot, byte for byte, whether or not the source was to hand.
+        assert_eq!(object.write(), back.write(), "{text}");
+        let text = listing(&object, Some(source));
+        let back = assemble(&text, "other").unwrap_or_else(|e| panic!("{e}\n{text}"));
+        assert_eq!(object.write(), back.write(), "{text}");
+    }
+}
+
+#[test]
+fn labels() {
+    let text = "
+        consts:
+            \"two\"
+        code:
+            MakeFunction 0 0 @end   ; (fn two [] (if false 1 2))
+              ImmediateBooleanFalse
+              JumpIfFalse @else
+              ImmediateInteger 1
+              JumpForward @done
+        else: ImmediateInteger 2
+        done: Return
+        end:
+            Call 1
+            Drop
+    ";
+    let object = assemble(text, "two").unwrap();
+    assert!(object.code.source_map.is_empty());
+    let mut vm = Vm::new();
+    let module = vm.anonymous_module("two");
+    let result = vm.run_to_completion(module, object.code).unwrap();
+    assert_eq!("2", result.format(&vm));
+}
+
+#[test]
+fn errors_are_located() {
+    for (text, expected) in [
+        ("code:\n\nPush 1", "line 3: unknown op Push"),
+        ("code:\nJumpForward @nowhere", "line 2: no label nowhere"),
+        ("code:\na: Nop\na: Nop", "line 3: duplicate label a"),
+        ("code:\nCall", "line 2: Call takes 1 operand(s)"),
+        ("consts:\n1 \"x\"", "line 2: expected constant 0"),
+        ("consts:\n\"x", "line 2: unterminated string"),
+        ("modul x", "line 1: unknown header modul"),
+        ("globals:\na/b/c", "line 2: bad name a/b/c"),
+        ("globals:\n0 /f", "line 2: bad name /f"),
+        (
+            "code:\nat 0:1-1:1",
+            "line 2: bad range 0:1-1:1; expected line:col-line:col",
+        ),
+        ("code:\nat 1:1-1:2 Nop", "line 2: at takes one range"),
+        (
+            "code:\nl: Nop\nJumpForward @l",
+            "line 3: JumpForward can't reach l from here",
+        ),
+    ] {
+        match assemble(text, "x") {
+            Ok(_) => panic!("{text:?} should fail"),
+            Err(err) => assert_eq!(expected, err.to_string()),
+        }
+    }
+}
+
+#[test]
+fn names_split_once_at_most() {
+    let text = listing(&compiled("'(a m/b)"), None);
+    assert!(text.contains("(a m/b)"), "{text}");
+    assert!(assemble(&text, "x").is_ok());
+    for bad in ["m/b/c", "\\m/b", "/b", "m/", "/"] {
+        let text = text.replace("m/b", bad);
+        match assemble(&text, "x") {
+            Ok(_) => panic!("{bad} should be refused"),
+            Err(err) => assert!(err.message.ends_with(&format!("bad name {bad}")), "{err}"),
+        }
+    }
+}
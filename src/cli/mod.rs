@@ -0,0 +1,199 @@
+use std::error::Error;
+use std::fs;
+use std::io::{self, IsTerminal};
+use std::path::Path;
+use std::process::ExitCode;
+
+use crate::compiler::Compiler;
+use crate::loader::Loader;
+use crate::object::{self, Object};
+use crate::parser::Document;
+use crate::report::Report;
+use crate::vm::{Code, ErrorKind, Val, Vm};
+use crate::{asm, disasm};
+
+// alia check <file>...
+// Parses and compiles each file, reporting every diagnostic found.
+pub(crate) fn check(args: Vec<String>) -> Result<(), Box<dyn Error + Send + Sync>> {
+    if args.is_empty() {
+        return Err("usage: alia check <file>...".into());
+    }
+
+    let color = io::stderr().is_terminal();
+    let mut errors = 0;
+    for path in &args {
+        let source = fs::read_to_string(path).map_err(|err| format!("{path}: {err}"))?;
+        errors += compile_source(path, &source, color).1;
+    }
+    match errors {
+        0 => Ok(()),
+        1 => Err("1 error".into()),
+        n => Err(format!("{n} errors").into()),
+    }
+}
+
+// alia compile <file>
+// Compiles the project rooted at file, without running any of it, and writes
+// each module out alongside its source: main.lia to main.liac and so on.  Run
+// main.liac to load the lot without parsing.
+pub(crate) fn compile(args: Vec<String>) -> Result<(), Box<dyn Error + Send + Sync>> {
+    let [path] = args.as_slice() else {
+        return Err("usage: alia compile <file>".into());
+    };
+
+    let color = io::stderr().is_terminal();
+    let mut vm = Vm::new();
+    let mut loader = Loader::new(&mut vm);
+    loader.compile_only();
+    let module = loader.load_entry(Path::new(path));
+    eprint!("{}", loader.render(color));
+    module.ok_or_else(|| format!("{path} failed to load"))?;
+    for (path, bytes) in loader.take_objects() {
+        fs::write(&path, bytes).map_err(|err| format!("{}: {err}", path.display()))?;
+    }
+    Ok(())
+}
+
+// alia asm <file>
+// Assembles a listing into a module object alongside it: f.lias to f.liac.
+// The module is named f unless the listing says otherwise.
+pub(crate) fn asm(args: Vec<String>) -> Result<(), Box<dyn Error + Send + Sync>> {
+    let [path] = args.as_slice() else {
+        return Err("usage: alia asm <file>".into());
+    };
+
+    let text = fs::read_to_string(path).map_err(|err| format!("{path}: {err}"))?;
+    let path = Path::new(path);
+    let name = path.file_stem().unwrap_or_default().to_string_lossy();
+    let object = asm::assemble(&text, &name)
+        .map_err(|err| format!("{}:{}: {}", path.display(), err.line, err.message))?;
+    let out = path.with_extension(object::EXTENSION);
+    fs::write(&out, object.write()).map_err(|err| format!("{}: {err}", out.display()))?;
+    Ok(())
+}
+
+// alia disasm <file>
+// Lists a module object in the form alia asm takes, or, given source, the
+// code it compiles to.  Either way, source is shown above the code it
+// became, if we have it.
+pub(crate) fn disasm(args: Vec<String>) -> Result<(), Box<dyn Error + Send + Sync>> {
+    let [path] = args.as_slice() else {
+        return Err("usage: alia disasm <file>".into());
+    };
+
+    if Path::new(path)
+        .extension()
+        .is_some_and(|e| e == object::EXTENSION)
+    {
+        let bytes = fs::read(path).map_err(|err| format!("{path}: {err}"))?;
+        let object = Object::read(&bytes).map_err(|err| format!("{path}: {err}"))?;
+        // Shown alongside, if it's still there.
+        let source = object
+            .code
+            .file
+            .as_ref()
+            .and_then(|f| fs::read_to_string(f).ok());
+        print!("{}", disasm::listing(&object, source.as_deref()));
+        return Ok(());
+    }
+    let source = fs::read_to_string(path).map_err(|err| format!("{path}: {err}"))?;
+    match compile_source(path, &source, io::stderr().is_terminal()) {
+        (Some((_, code)), _) => Ok(disasm::disasm(&code, Some(&source))?),
+        (None, _) => Err(format!("{path} failed to compile").into()),
+    }
+}
+
+// alia run <file> [args...]
+// Loads the project rooted at file, then calls the main function of the module
+// it declares, which may take the remaining arguments as a vector of strings.
+// main's return value, if any, is the exit code.
+pub(crate) fn run(args: Vec<String>) -> Result<ExitCode, Box<dyn Error + Send + Sync>> {
+    let Some((path, args)) = args.split_first() else {
+        return Err("usage: alia run <file> [args...]".into());
+    };
+
+    let color = io::stderr().is_terminal();
+    let mut vm = Vm::new();
+    let mut loader = Loader::new(&mut vm);
+    let module = loader.load_entry(Path::new(path));
+    eprint!("{}", loader.render(color));
+    let module = module.ok_or_else(|| format!("{path} failed to load"))?;
+    let name = module.borrow().name.clone();
+
+    let smain = loader.vm().intern("main");
+    let main = module.borrow().lookup(loader.vm(), smain);
+    let f = match main {
+        Some(Val::Function(f)) => f,
+        Some(v) => {
+            let v = v.format(loader.vm());
+            return Err(format!("{name}/main isn't a function: {v}").into());
+        }
+        None => return Err(format!("{name} has no main").into()),
+    };
+    let args = if f.nparams == 0 {
+        vec![]
+    } else {
+        vec![Val::Vec(
+            args.iter().map(|a| Val::String(a.clone())).collect(),
+        )]
+    };
+    // Scheduled like any other process, so whatever main spawns gets a turn
+    // while it runs; the program ends when main does.
+    let pid = loader.vm().spawn(&f, args);
+    let result = loader.vm().run_until(pid);
+    loader.crashes();
+    let result = match result {
+        Ok(v) => v,
+        // (exit reason) from main: reason is the exit code.
+        Err(err) if err.kind == ErrorKind::Exit => *err.value.expect("exit should have a reason"),
+        Err(err) => {
+            loader.runtime(&err);
+            eprint!("{}", loader.render(color));
+            return Err(format!("{path} failed to run").into());
+        }
+    };
+    eprint!("{}", loader.render(color));
+    match result {
+        Val::Integer(i) => u8::try_from(i)
+            .map(ExitCode::from)
+            .map_err(|_| format!("{name}/main returned {i}, which isn't a u8").into()),
+        v if v == Val::unit() => Ok(ExitCode::SUCCESS),
+        v => {
+            let v = v.format(loader.vm());
+            Err(format!("{name}/main should return a u8, not {v}").into())
+        }
+    }
+}
+
+// Parses and compiles source, reporting diagnostics to stderr as it goes.
+// Returns the result (if there were no errors) and the error count.
+fn compile_source(path: &str, source: &str, color: bool) -> (Option<(Document, Code)>, usize) {
+    let report = |r: Report| eprint!("{}", r.render(source, Some(path), color));
+
+    let doc = match source.parse::<Document>() {
+        Ok(doc) => doc,
+        Err(err) => {
+            report(Report::parse(&err, source));
+            return (None, 1);
+        }
+    };
+    let mut c = Compiler::new();
+    c.doc(&doc);
+    match c.finish() {
+        Ok(mut code) => {
+            c.warnings().iter().map(Report::compile).for_each(report);
+            code.file = Some(path.to_string());
+            (Some((doc, code)), 0)
+        }
+        Err(err) => {
+            let mut errors = 0;
+            for r in err.diagnostics.iter().map(Report::compile) {
+                if r.is_error() {
+                    errors += 1;
+                }
+                report(r);
+            }
+            (None, errors)
+        }
+    }
+}
@@ -0,0 +1,101 @@
+use super::{Diagnostic, Severity};
+use crate::parser::{Node, NodeValue, Range};
+
+// (mod name submods: ['a 'b] exports: [f g])
+pub(crate) struct ModDecl {
+    pub(crate) name: String,
+    // Of the name.
+    pub(crate) range: Range,
+    pub(crate) submods: Vec<(String, Range)>,
+    // None if there's no list: then everything is exported.
+    pub(crate) exports: Option<Vec<(String, Range)>>,
+}
+
+impl ModDecl {
+    // None if n isn't a (mod ...) form at all.
+    pub(crate) fn from_node(n: &Node) -> Option<Result<ModDecl, Diagnostic>> {
+        let NodeValue::List(ns) = &n.value else {
+            return None;
+        };
+        match ns.first().map(|n| &n.value) {
+            Some(NodeValue::Symbol(None, s)) if s == "mod" => {}
+            _ => return None,
+        }
+        Some(Self::parse(n.range, &ns[1..]))
+    }
+
+    fn parse(range: Range, ns: &[Node]) -> Result<ModDecl, Diagnostic> {
+        let error = |range, message: &str| Diagnostic::new(Severity::Error, range, message);
+
+        let (name, name_range) = match ns.first().map(|n| (&n.value, n.range)) {
+            Some((NodeValue::Symbol(None, s), range)) => (s.clone(), range),
+            _ => {
+                let at = ns.first().map_or(range, |n| n.range);
+                return Err(error(at, "mod should be followed by a module name"));
+            }
+        };
+
+        let mut submods = vec![];
+        let mut exports = None;
+        // The rest are keyword pairs; `key:` reads as (quote key).
+        let mut kvs = ns[1..].iter();
+        while let Some(k) = kvs.next() {
+            let Some(key) = quoted_symbol(k) else {
+                return Err(error(k.range, "expected a keyword like submods:"));
+            };
+            let Some(v) = kvs.next() else {
+                return Err(error(k.range, &format!("{key}: has no value")));
+            };
+            match key {
+                "submods" => submods = Self::names(key, "submodule", v)?,
+                "exports" => exports = Some(Self::names(key, "bind", v)?),
+                _ => return Err(error(k.range, &format!("unknown mod option {key}:"))),
+            }
+        }
+
+        Ok(ModDecl {
+            name,
+            range: name_range,
+            submods,
+            exports,
+        })
+    }
+
+    // ['a 'b], or [a b].
+    fn names(key: &str, what: &str, v: &Node) -> Result<Vec<(String, Range)>, Diagnostic> {
+        let error = |range| {
+            Diagnostic::new(
+                Severity::Error,
+                range,
+                format!("{key}: should be a vector of {what} names"),
+            )
+        };
+        let NodeValue::Vec(ns) = &v.value else {
+            return Err(error(v.range));
+        };
+        ns.iter()
+            .map(|n| match &n.value {
+                NodeValue::Symbol(None, s) => Ok((s.clone(), n.range)),
+                _ => quoted_symbol(n)
+                    .map(|s| (s.to_string(), n.range))
+                    .ok_or_else(|| error(n.range)),
+            })
+            .collect()
+    }
+}
+
+// 'a => Some("a")
+fn quoted_symbol(n: &Node) -> Option<&str> {
+    let NodeValue::List(q) = &n.value else {
+        return None;
+    };
+    match q.as_slice() {
+        [quote, s] => match (&quote.value, &s.value) {
+            (NodeValue::Symbol(None, quote), NodeValue::Symbol(None, s)) if quote == "quote" => {
+                Some(s)
+            }
+            _ => None,
+        },
+        _ => None,
+    }
+}
@@ -1,11 +1,26 @@
 use std::fmt::{Debug, Display};
 
+use crate::parser::Range;
+
+// Returned when compilation fails; carries every diagnostic collected,
+// warnings included.
 #[derive(PartialEq)]
-pub(crate) struct Error {}
+pub(crate) struct Error {
+    pub(crate) diagnostics: Vec<Diagnostic>,
+}
 
 impl Display for Error {
-    fn fmt(&self, _f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-        todo!()
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let mut first = true;
+        for d in &self.diagnostics {
+            if first {
+                first = false;
+            } else {
+                writeln!(f)?;
+            }
+            write!(f, "{d}")?;
+        }
+        Ok(())
     }
 }
 
@@ -16,3 +31,74 @@ impl Debug for Error {
 }
 
 impl std::error::Error for Error {}
+
+#[derive(PartialEq)]
+pub(crate) struct Diagnostic {
+    pub(crate) severity: Severity,
+    pub(crate) range: Range,
+    pub(crate) message: String,
+    pub(crate) notes: Vec<Note>,
+}
+
+impl Diagnostic {
+    pub(crate) fn new<R: Into<Range>, S: Into<String>>(
+        severity: Severity,
+        range: R,
+        message: S,
+    ) -> Self {
+        Diagnostic {
+            severity,
+            range: range.into(),
+            message: message.into(),
+            notes: vec![],
+        }
+    }
+
+    pub(crate) fn note<S: Into<String>>(mut self, range: Option<Range>, message: S) -> Self {
+        self.notes.push(Note {
+            range,
+            message: message.into(),
+        });
+        self
+    }
+}
+
+impl Display for Diagnostic {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}: {} at [{}]", self.severity, self.message, self.range)?;
+        for note in &self.notes {
+            write!(f, "\n  note: {}", note.message)?;
+            if let Some(range) = note.range {
+                write!(f, " at [{range}]")?;
+            }
+        }
+        Ok(())
+    }
+}
+
+impl Debug for Diagnostic {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        Display::fmt(self, f)
+    }
+}
+
+#[derive(PartialEq)]
+pub(crate) struct Note {
+    pub(crate) range: Option<Range>,
+    pub(crate) message: String,
+}
+
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub(crate) enum Severity {
+    Error,
+    Warning,
+}
+
+impl Display for Severity {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Error => f.write_str("error"),
+            Self::Warning => f.write_str("warning"),
+        }
+    }
+}
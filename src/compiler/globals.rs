@@ -0,0 +1,111 @@
+use super::macros::Env;
+use super::Compiler;
+use crate::parser::{Node, NodeValue};
+use crate::vm::{Const, Global, Op};
+
+impl Compiler<'_> {
+    // Top-level definitions get their slots up front, so code before them
+    // refers to them rather than to whatever they'd shadow.
+    pub(super) fn declare(&mut self, toplevels: &[Node]) {
+        let Some(Env::Borrowed(vm, module)) = &mut self.env else {
+            return;
+        };
+        for n in toplevels {
+            let NodeValue::List(ns) = &n.value else {
+                continue;
+            };
+            if let [head, name, ..] = ns.as_slice() {
+                match (&head.value, &name.value) {
+                    (NodeValue::Symbol(None, form), NodeValue::Symbol(None, s))
+                        if matches!(form.as_str(), "defn" | "defmacro" | "set") =>
+                    {
+                        let s = vm.intern(s);
+                        module.borrow_mut().slot(s);
+                    }
+                    _ => {}
+                }
+            }
+        }
+    }
+
+    // Pushes the value of global [m/]s: straight from its slot if we can
+    // tell now where it lives, otherwise looked up by name when it runs.
+    pub(super) fn load_global(&mut self, m: Option<&str>, s: &str) {
+        match self.global(m, s) {
+            Some(g) => {
+                let i = self.global_index(g);
+                self.op(Op::LoadGlobal);
+                self.n(i);
+            }
+            None => {
+                self.constant(Const::Symbol(m.map(str::to_string), s.to_string()));
+                self.op(Op::Eval);
+            }
+        }
+    }
+
+    // Binds s in our own module to whatever value compiles to, leaving the
+    // value on the stack: (set s ...), (defn s ...).
+    pub(super) fn store_global<F: FnOnce(&mut Self)>(&mut self, s: &str, value: F) {
+        value(self);
+        let i = self.global_index(Global {
+            module: None,
+            name: s.to_string(),
+        });
+        self.op(Op::StoreGlobal);
+        self.n(i);
+    }
+
+    fn global_index(&mut self, g: Global) -> usize {
+        match self.globals.iter().position(|h| *h == g) {
+            Some(i) => i,
+            None => {
+                self.globals.push(g);
+                self.globals.len() - 1
+            }
+        }
+    }
+
+    // Where [m/]s lives, resolved the way Module::lookup would.  None when we
+    // don't know which module the code will run in, for module names, and
+    // for m/s that m doesn't declare public; all of those are left until
+    // runtime.
+    fn global(&mut self, m: Option<&str>, s: &str) -> Option<Global> {
+        let Some(Env::Borrowed(vm, module)) = &mut self.env else {
+            return None;
+        };
+        let name = s.to_string();
+        let s = vm.intern(s);
+        if let Some(m) = m {
+            let m = vm.intern(m);
+            let target = vm.resolve_module(&module.borrow(), m)?;
+            let target = target.borrow();
+            if !target.declares_public(s) {
+                return None;
+            }
+            return Some(Global {
+                module: Some(target.name.clone()),
+                name,
+            });
+        }
+
+        let own = module.borrow();
+        if own.find_slot(s).is_some() {
+            return Some(Global { module: None, name });
+        }
+        if vm.lookup_module(s).is_some() {
+            return None;
+        }
+        let elsewhere = own.referrer(s).or_else(|| {
+            own.prelude
+                .clone()
+                .filter(|p| p.borrow().lookup_public(s).is_some())
+        });
+        // Not defined anywhere yet?  If nothing else claims it by the time
+        // this runs, it had better be ours.
+        Some(Global {
+            module: elsewhere.map(|m| m.borrow().name.clone()),
+            name,
+        })
+    }
+}
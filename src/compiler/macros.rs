@@ -0,0 +1,191 @@
+use std::cell::RefCell;
+use std::mem;
+use std::rc::Rc;
+
+use super::Compiler;
+use crate::parser::{Node, NodeValue, Range};
+use crate::vm::{Code, Module, Op, Val, Vm};
+
+// A macro expanding to a call to itself would otherwise never stop.
+const MAX_EXPANSION_DEPTH: usize = 256;
+
+// Nor would one that loops.  Each run of compile-time code in a scratch VM
+// gets this many reductions; the editor compiles with one on every keystroke.
+const SCRATCH_BUDGET: usize = 100_000;
+
+// Where macros are defined and expanded: the VM and module the code being
+// compiled will run in, or a scratch pair if we weren't given any.
+pub(super) enum Env<'v> {
+    Borrowed(&'v mut Vm, Rc<RefCell<Module>>),
+    Owned(Box<Vm>, Rc<RefCell<Module>>),
+}
+
+impl Env<'_> {
+    fn scratch() -> Self {
+        let mut vm = Vm::new();
+        let module = vm.anonymous_module("*macros*");
+        Env::Owned(Box::new(vm), module)
+    }
+
+    fn get(&mut self) -> (&mut Vm, Rc<RefCell<Module>>) {
+        match self {
+            Env::Borrowed(vm, module) => (vm, module.clone()),
+            Env::Owned(vm, module) => {
+                vm.set_budget(Some(SCRATCH_BUDGET));
+                (vm, module.clone())
+            }
+        }
+    }
+}
+
+impl Compiler<'_> {
+    // (defmacro name [params] body...)
+    // The definition runs as soon as it's compiled, so the rest of the file
+    // can use it, and then again with everything else at runtime.
+    pub(super) fn defmacro_form(&mut self, ns: &[Node]) {
+        // A top-level let binds without a scope of its own.
+        if self.scopes.len() > 1 || !self.scope().binds.is_empty() {
+            self.error_here("defmacro should be at the top level");
+            return self.unit();
+        }
+        self.now("defining macro", |c| c.function_form("defmacro", ns));
+    }
+
+    // Top-level (refer ...), (alias ...) and (import ...) change what names
+    // mean for the rest of the file, macros included.  Only done when we know
+    // the module we're compiling for; a scratch one has nothing to refer to.
+    pub(super) fn scope_form(&mut self, n: &Node) -> bool {
+        let NodeValue::List(ns) = &n.value else {
+            return false;
+        };
+        match ns.first().map(|h| &h.value) {
+            Some(NodeValue::Symbol(None, s))
+                if matches!(s.as_str(), "refer" | "alias" | "import")
+                    && matches!(self.env, Some(Env::Borrowed(..))) =>
+            {
+                self.now(s, |c| c.expr(n));
+                true
+            }
+            _ => false,
+        }
+    }
+
+    // Compiles with f, runs the result in our environment straight away, and
+    // then emits it as usual.
+    fn now<F: FnOnce(&mut Self)>(&mut self, what: &str, f: F) {
+        let outer = mem::take(&mut self.out);
+        let outer_map = mem::take(&mut self.source_map);
+        let errors = self.errors();
+        f(self);
+        let bytes = mem::replace(&mut self.out, outer);
+        let map = mem::replace(&mut self.source_map, outer_map);
+
+        if self.errors() == errors {
+            let mut code = Code::new(bytes.clone());
+            code.bytes.push(Op::Drop as u8);
+            code.consts = self.consts.clone();
+            code.globals = self.globals.clone();
+            code.source_map = map.clone();
+            let (vm, module) = self.env.get_or_insert_with(Env::scratch).get();
+            if let Err(err) = vm.run_to_completion(module, code) {
+                self.error_here(format!("{what} failed: {err}"));
+            }
+        }
+
+        let base = self.out.len();
+        self.out.extend_from_slice(&bytes);
+        self.source_map.extend(
+            map.into_iter()
+                .map(|(offset, range)| (base + offset, range)),
+        );
+    }
+
+    // If (head args...) is a macro call, compiles its expansion and returns
+    // true.
+    pub(super) fn expand(&mut self, n: &Node, ns: &[Node]) -> bool {
+        let head = &ns[0];
+        if let NodeValue::Symbol(None, s) = &head.value {
+            if self.is_local(s) {
+                return false;
+            }
+        }
+        // No environment means nothing's defined a macro yet.
+        let Some(env) = self.env.as_mut() else {
+            return false;
+        };
+        let (vm, module) = env.get();
+        let form = to_val(vm, n);
+        let Val::List(forms) = &form else {
+            unreachable!("a list should convert to a list");
+        };
+        let Some(f) = vm.lookup_macro(&module.borrow(), &forms[0]) else {
+            return false;
+        };
+        let expanded = vm
+            .call(&f, forms[1..].to_vec())
+            .map_err(|err| format!("expanding {head}: {err}"))
+            .and_then(|v| {
+                to_node(vm, &v, n.range)
+                    .map_err(|what| format!("{head} expanded to {what}, which can't be compiled"))
+            });
+
+        match expanded {
+            _ if self.expansions == MAX_EXPANSION_DEPTH => {
+                self.error(n.range, format!("expanding {head} doesn't stop"));
+                self.unit();
+            }
+            Ok(expanded) => {
+                self.expansions += 1;
+                self.expr(&expanded);
+                self.expansions -= 1;
+            }
+            Err(message) => {
+                self.error(n.range, message);
+                self.unit();
+            }
+        }
+        true
+    }
+}
+
+// A form as the data a macro receives.
+pub(crate) fn to_val(vm: &mut Vm, n: &Node) -> Val {
+    match &n.value {
+        NodeValue::Symbol(None, s) => Val::Symbol(None, vm.intern(s)),
+        NodeValue::Symbol(Some(m), s) => Val::Symbol(Some(vm.intern(m)), vm.intern(s)),
+        NodeValue::Integer(i) => Val::Integer(*i),
+        NodeValue::Float(f) => Val::Float(*f),
+        NodeValue::String(s) => Val::String(s.clone()),
+        NodeValue::List(ns) => Val::List(ns.iter().map(|n| to_val(vm, n)).collect()),
+        NodeValue::Vec(ns) => Val::Vec(ns.iter().map(|n| to_val(vm, n)).collect()),
+    }
+}
+
+// And back again; all of it is attributed to range, the macro call.  Err
+// names the part with no source form: a function, a module, etc.
+fn to_node(vm: &Vm, v: &Val, range: Range) -> Result<Node, String> {
+    let value = match v {
+        &Val::Symbol(None, s) => NodeValue::Symbol(None, vm.resolve(s).to_string()),
+        &Val::Symbol(Some(m), s) => {
+            NodeValue::Symbol(Some(vm.resolve(m).to_string()), vm.resolve(s).to_string())
+        }
+        Val::Boolean(b) => NodeValue::Symbol(None, b.to_string()),
+        Val::Integer(i) => NodeValue::Integer(*i),
+        Val::Float(f) => NodeValue::Float(*f),
+        Val::String(s) => NodeValue::String(s.clone()),
+        Val::List(vs) => NodeValue::List(
+            vs.iter()
+                .map(|v| to_node(vm, v, range))
+                .collect::<Result<_, _>>()?,
+        ),
+        Val::Vec(vs) => NodeValue::Vec(
+            vs.iter()
+                .map(|v| to_node(vm, v, range))
+                .collect::<Result<_, _>>()?,
+        ),
+        Val::Builtin(..) | Val::Function(..) | Val::Macro(..) | Val::Module(..) | Val::Pid(..) => {
+            return Err(v.format(vm))
+        }
+    };
+    Ok(Node::new(value, range))
+}
@@ -1,111 +1,241 @@
+mod decl;
 mod error;
+mod globals;
+mod macros;
 mod tests;
 
-use num_traits::ToBytes;
+use std::cell::RefCell;
 use std::mem;
+use std::rc::Rc;
 
-pub(crate) use self::error::Error;
-use crate::parser::{Document, Node, NodeValue};
-use crate::vm::Op;
-
-macro_rules! guard {
-    ($self:ident.$lhs:tt = $rhs:expr; $body:tt) => {
-        let old_lhs = $self.$lhs;
-        $self.$lhs = $rhs;
-        $body;
-        $self.$lhs = old_lhs;
-    };
-}
+pub(crate) use self::decl::ModDecl;
+pub(crate) use self::error::{Diagnostic, Error, Severity};
+pub(crate) use self::macros::to_val;
+use self::macros::Env;
+use crate::parser::{Document, Node, NodeValue, Range};
+use crate::vm::{varint, Code, Const, Global, Module, Op, Vm};
+
+// Forms the compiler handles itself rather than compiling as a call.  They
+// get their arguments as written, unevaluated.
+type SpecialForm = fn(&mut Compiler<'_>, &[Node]);
 
-pub(crate) struct Compiler {
+const SPECIAL_FORMS: &[(&str, SpecialForm)] = &[
+    ("quote", |c, ns| c.quote_form(ns)),
+    ("quasiquote", |c, ns| c.quasiquote_form(ns)),
+    ("unquote", |c, _| c.unquote_form("unquote")),
+    ("unquote-splicing", |c, _| {
+        c.unquote_form("unquote-splicing")
+    }),
+    ("set", |c, ns| c.set_form(ns)),
+    ("do", |c, ns| c.body(&ns.iter().collect::<Vec<_>>())),
+    ("defn", |c, ns| c.function_form("defn", ns)),
+    ("defmacro", |c, ns| c.defmacro_form(ns)),
+    ("fn", |c, ns| c.function_form("fn", ns)),
+    ("let", |c, ns| c.let_form(ns)),
+    ("if", |c, ns| c.if_form(ns)),
+    ("cond", |c, ns| c.cond_form(ns)),
+    ("when", |c, ns| c.when_form(true, ns)),
+    ("unless", |c, ns| c.when_form(false, ns)),
+    ("and", |c, ns| c.and_or_form(true, ns)),
+    ("or", |c, ns| c.and_or_form(false, ns)),
+    ("try", |c, ns| c.try_form(ns)),
+    ("throw", |c, ns| c.throw_form(ns)),
+    ("receive", |c, ns| c.receive_form(ns)),
+    ("refer", |c, ns| c.builtin_form("refer", ns)),
+    ("alias", |c, ns| c.builtin_form("alias", ns)),
+    ("import", |c, ns| c.builtin_form("import", ns)),
+];
+
+pub(crate) struct Compiler<'v> {
     out: Vec<u8>,
-    omit_evals: bool,
+    consts: Vec<Const>,
+    globals: Vec<Global>,
+    source_map: Vec<(usize, Range)>,
+    ranges: Vec<Range>,
+    scopes: Vec<Scope>,
+    diagnostics: Vec<Diagnostic>,
+    env: Option<Env<'v>>,
+    // How many macro expansions deep we are.
+    expansions: usize,
+}
+
+// One per function being compiled; the bottom one is the top-level code.
+struct Scope {
+    binds: Vec<(String, usize)>,
+    // (name, slot here, slot in the enclosing scope)
+    captures: Vec<(String, usize, usize)>,
+    next_slot: usize,
+}
+
+impl Scope {
+    fn new() -> Self {
+        Scope {
+            binds: vec![],
+            captures: vec![],
+            next_slot: 0,
+        }
+    }
+
+    fn alloc(&mut self) -> usize {
+        self.next_slot += 1;
+        self.next_slot - 1
+    }
+
+    fn bind(&mut self, name: &str) -> usize {
+        let slot = self.alloc();
+        self.binds.push((name.to_string(), slot));
+        slot
+    }
 }
 
-impl Compiler {
+impl Compiler<'static> {
     pub(crate) fn new() -> Self {
+        Self::with_env(None)
+    }
+}
+
+impl<'v> Compiler<'v> {
+    // For code that'll run in module: macros are looked up there, and
+    // defmacro defines them there as it goes.
+    pub(crate) fn in_module(vm: &'v mut Vm, module: Rc<RefCell<Module>>) -> Self {
+        Self::with_env(Some(Env::Borrowed(vm, module)))
+    }
+
+    fn with_env(env: Option<Env<'v>>) -> Self {
         Compiler {
             out: vec![],
-            omit_evals: false,
+            consts: vec![],
+            globals: vec![],
+            source_map: vec![],
+            ranges: vec![],
+            scopes: vec![Scope::new()],
+            diagnostics: vec![],
+            env,
+            expansions: 0,
+        }
+    }
+
+    // Any error fails the whole compile; warnings alone don't, and can be
+    // fetched with warnings() afterwards.
+    pub(crate) fn finish(&mut self) -> Result<Code, Error> {
+        if self
+            .diagnostics
+            .iter()
+            .any(|d| d.severity == Severity::Error)
+        {
+            self.out.clear();
+            self.consts.clear();
+            self.globals.clear();
+            self.source_map.clear();
+            return Err(Error {
+                diagnostics: mem::take(&mut self.diagnostics),
+            });
         }
+        let mut code = Code::new(mem::take(&mut self.out));
+        code.consts = mem::take(&mut self.consts);
+        code.globals = mem::take(&mut self.globals);
+        code.source_map = mem::take(&mut self.source_map);
+        Ok(code)
     }
 
-    pub(crate) fn finish(&mut self) -> Vec<u8> {
-        mem::take(&mut self.out)
+    pub(crate) fn warnings(&self) -> &[Diagnostic] {
+        &self.diagnostics
     }
 
     pub(crate) fn doc(&mut self, doc: &Document) {
-        for toplevel in &doc.toplevels {
+        self.declare(&doc.toplevels);
+        for (i, toplevel) in doc.toplevels.iter().enumerate() {
+            if i > 0 && ModDecl::from_node(toplevel).is_some() {
+                self.error(toplevel.range, "mod should be the first form in a file");
+                continue;
+            }
             self.toplevel(toplevel);
         }
     }
 
-    fn toplevel(&mut self, n: &Node) {
+    pub(crate) fn toplevel(&mut self, n: &Node) {
+        // (mod ...) is for whoever loads the file; it compiles to nothing.
+        match ModDecl::from_node(n) {
+            Some(Ok(_)) => return,
+            Some(Err(d)) => return self.diagnostics.push(d),
+            None => {}
+        }
         match n.value {
-            NodeValue::Symbol(..)
-            | NodeValue::Integer(_)
-            | NodeValue::Float(_)
-            | NodeValue::String(_)
-            | NodeValue::Vec(_) => {
-                // warn: side-effects only
-                // (and int/float/string can't even do that).
+            NodeValue::Integer(_) | NodeValue::Float(_) | NodeValue::String(_) => {
+                self.warn(n.range, format!("top-level {} has no effect", n.kind()));
+                self.expr(n);
+                self.op(Op::Drop);
+            }
+            NodeValue::Symbol(..) | NodeValue::Vec(_) => {
+                self.warn(
+                    n.range,
+                    format!("top-level {} is evaluated only for side-effects", n.kind()),
+                );
                 self.expr(n);
                 self.op(Op::Drop);
             }
             NodeValue::List(_) => {
                 // XXX for now, side-effects only
-                self.expr(n);
+                self.ranges.push(n.range);
+                if !self.scope_form(n) {
+                    self.expr_inner(n);
+                }
+                self.ranges.pop();
                 self.op(Op::Drop);
             }
         }
     }
 
     fn expr(&mut self, n: &Node) {
+        // Every op emitted is attributed to the innermost node being compiled.
+        self.ranges.push(n.range);
+        self.expr_inner(n);
+        self.ranges.pop();
+    }
+
+    fn expr_inner(&mut self, n: &Node) {
         match &n.value {
             NodeValue::Symbol(None, s) => {
-                // TODO: proper compile-time resolution! not this shit!
-                // XXX: local binds are ignored
-                if s == "true" {
+                if let Some(slot) = self.resolve(s) {
+                    self.op(Op::LoadLocal);
+                    self.n(slot);
+                } else if s == "true" {
                     self.op(Op::ImmediateBooleanTrue)
                 } else if s == "false" {
                     self.op(Op::ImmediateBooleanFalse)
                 } else {
-                    self.op(Op::ImmediateSymbolBare);
-                    self.bytes(s);
-                    self.op(Op::Eval);
+                    self.load_global(None, s);
                 }
             }
-            NodeValue::Symbol(Some(m), s) => {
-                self.op(Op::ImmediateSymbolWithModule);
-                self.bytes(m);
-                self.bytes(s);
-                self.op(Op::Eval);
-            }
+            NodeValue::Symbol(Some(m), s) => self.load_global(Some(m), s),
             NodeValue::Integer(i) => {
                 self.op(Op::ImmediateInteger);
-                self.n(*i);
-            }
-            NodeValue::Float(f) => {
-                self.op(Op::ImmediateFloat);
-                self.n(*f);
-            }
-            NodeValue::String(s) => {
-                self.op(Op::ImmediateString);
-                self.bytes(s);
+                varint::put_signed(&mut self.out, *i);
             }
+            NodeValue::Float(f) => self.constant(Const::Float(*f)),
+            NodeValue::String(s) => self.constant(Const::String(s.clone())),
             NodeValue::List(ns) => {
-                let mut ns = ns.iter();
-                let head = ns.next().expect("list should have a head");
-                self.expr(head); // <- resolves
-                let mut i: usize = 1;
-                guard!(self.omit_evals = true; ({
-                    for n in ns {
-                        self.expr(n);
-                        i += 1;
+                // () evaluates to itself, as it does at runtime.
+                let Some(head) = ns.first() else {
+                    self.unit();
+                    return;
+                };
+                if let NodeValue::Symbol(None, s) = &head.value {
+                    if let Some((_, form)) = SPECIAL_FORMS.iter().find(|(name, _)| name == s) {
+                        return form(self, &ns[1..]);
                     }
-                }));
+                }
+                if self.expand(n, ns) {
+                    return;
+                }
+
+                // An ordinary call: the callee, then its arguments, all
+                // evaluated in order.
+                for n in ns {
+                    self.expr(n);
+                }
                 self.op(Op::Call);
-                self.n(i);
+                self.n(ns.len());
             }
             NodeValue::Vec(ns) => {
                 for n in ns {
@@ -117,21 +247,771 @@ impl Compiler {
         }
     }
 
+    // Compiles n as plain data, as if quoted.
+    fn literal(&mut self, n: &Node) {
+        match &n.value {
+            NodeValue::Integer(_) => self.expr(n),
+            _ => self.constant(Self::datum(n)),
+        }
+    }
+
+    fn datum(n: &Node) -> Const {
+        match &n.value {
+            NodeValue::Symbol(m, s) => Const::Symbol(m.clone(), s.clone()),
+            NodeValue::Integer(i) => Const::Integer(*i),
+            NodeValue::Float(f) => Const::Float(*f),
+            NodeValue::String(s) => Const::String(s.clone()),
+            NodeValue::List(ns) => Const::List(ns.iter().map(Self::datum).collect()),
+            NodeValue::Vec(ns) => Const::Vec(ns.iter().map(Self::datum).collect()),
+        }
+    }
+
+    // (quote x), or 'x
+    fn quote_form(&mut self, ns: &[Node]) {
+        match ns {
+            [n] => self.literal(n),
+            _ => {
+                self.error_here("quote takes one argument");
+                self.unit();
+            }
+        }
+    }
+
+    fn unquote_form(&mut self, form: &str) {
+        self.error_here(format!("{form} should be inside a quasiquote"));
+        self.unit();
+    }
+
+    // (set x v)
+    // Binds x in the module; evaluates to v.
+    fn set_form(&mut self, ns: &[Node]) {
+        let [name, v] = ns else {
+            self.error_here("set takes a name and a value");
+            return self.unit();
+        };
+        let NodeValue::Symbol(None, s) = &name.value else {
+            self.error(
+                name.range,
+                format!("set can only set symbols, not {}", name.kind()),
+            );
+            return self.unit();
+        };
+        self.store_global(s, |c| c.expr(v));
+    }
+
+    // (refer kcx.util only: [p]), etc.
+    // Calls builtins/name with the arguments as data: they name modules and
+    // binds, rather than being values themselves.
+    fn builtin_form(&mut self, name: &str, ns: &[Node]) {
+        self.load_global(Some("builtins"), name);
+        for n in ns {
+            self.literal(n);
+        }
+        self.op(Op::Call);
+        self.n(ns.len() + 1);
+    }
+
+    // (if c then else?)
+    fn if_form(&mut self, ns: &[Node]) {
+        let (c, then, els) = match ns {
+            [c, then] => (c, then, None),
+            [c, then, els] => (c, then, Some(els)),
+            _ => {
+                self.error_here("if takes a condition and one or two branches");
+                return self.unit();
+            }
+        };
+        self.expr(c);
+        let jf = self.jump_forward(Op::JumpIfFalse);
+        self.expr(then);
+        let jend = self.jump_forward(Op::JumpForward);
+        self.patch(jf);
+        match els {
+            Some(els) => self.expr(els),
+            None => self.unit(),
+        }
+        self.patch(jend);
+    }
+
+    // (cond c1 e1 c2 e2 ...)
+    // Falls out the bottom with () if nothing matches.
+    fn cond_form(&mut self, ns: &[Node]) {
+        if !ns.len().is_multiple_of(2) {
+            self.error(
+                ns[ns.len() - 1].range,
+                "cond takes condition/expression pairs; this condition has no expression",
+            );
+            return self.unit();
+        }
+        let mut jends = vec![];
+        for pair in ns.chunks(2) {
+            self.expr(&pair[0]);
+            let jf = self.jump_forward(Op::JumpIfFalse);
+            self.expr(&pair[1]);
+            jends.push(self.jump_forward(Op::JumpForward));
+            self.patch(jf);
+        }
+        self.unit();
+        for jend in jends {
+            self.patch(jend);
+        }
+    }
+
+    // (when c body...), (unless c body...)
+    fn when_form(&mut self, when: bool, ns: &[Node]) {
+        let Some(c) = ns.first() else {
+            let form = if when { "when" } else { "unless" };
+            self.error_here(format!("{form} should have a condition"));
+            return self.unit();
+        };
+        let body = ns[1..].iter().collect::<Vec<_>>();
+        self.expr(c);
+        let jf = self.jump_forward(Op::JumpIfFalse);
+        if when {
+            self.body(&body);
+        } else {
+            self.unit();
+        }
+        let jend = self.jump_forward(Op::JumpForward);
+        self.patch(jf);
+        if when {
+            self.unit();
+        } else {
+            self.body(&body);
+        }
+        self.patch(jend);
+    }
+
+    // (and a b ...), (or a b ...)
+    // Short-circuits, evaluating to the last form evaluated.
+    fn and_or_form(&mut self, and: bool, ns: &[Node]) {
+        let Some((last, init)) = ns.split_last() else {
+            self.op(if and {
+                Op::ImmediateBooleanTrue
+            } else {
+                Op::ImmediateBooleanFalse
+            });
+            return;
+        };
+        let mut jends = vec![];
+        for n in init {
+            self.expr(n);
+            self.op(Op::Dup);
+            if and {
+                jends.push(self.jump_forward(Op::JumpIfFalse));
+            } else {
+                let jf = self.jump_forward(Op::JumpIfFalse);
+                jends.push(self.jump_forward(Op::JumpForward));
+                self.patch(jf);
+            }
+            self.op(Op::Drop);
+        }
+        self.expr(last);
+        for jend in jends {
+            self.patch(jend);
+        }
+    }
+
+    // (try body... (catch e handler...) (finally cleanup...))
+    // Both clauses are optional; finally's value is discarded.
+    fn try_form(&mut self, ns: &[Node]) {
+        let mut body = ns;
+        let finally = match body.split_last() {
+            Some((last, init)) => Self::clause(last, "finally").inspect(|_| body = init),
+            None => None,
+        };
+        let catch = match body.split_last() {
+            Some((last, init)) => Self::clause(last, "catch").inspect(|_| body = init),
+            None => None,
+        };
+
+        let h = self.jump_forward(Op::PushHandler);
+        self.body(&body.iter().collect::<Vec<_>>());
+        self.op(Op::PopHandler);
+        self.finally(finally);
+        let jend = self.jump_forward(Op::JumpForward);
+
+        // The handler lands here with the error on the stack.
+        self.patch(h);
+        match catch {
+            Some(clause) => {
+                let name = match clause.first().map(|n| &n.value) {
+                    Some(NodeValue::Symbol(None, s)) => s.as_str(),
+                    _ => {
+                        let range = clause.first().map(|n| n.range);
+                        self.error(
+                            range.unwrap_or_else(|| self.here()),
+                            "catch should be followed by a symbol to bind",
+                        );
+                        "_"
+                    }
+                };
+                let depth = self.scope().binds.len();
+                let slot = self.scope().bind(name);
+                self.op(Op::StoreLocal);
+                self.n(slot);
+                let handler = clause[1..].iter().collect::<Vec<_>>();
+                if finally.is_some() {
+                    // Errors in the handler still need to run finally.
+                    let h = self.jump_forward(Op::PushHandler);
+                    self.body(&handler);
+                    self.op(Op::PopHandler);
+                    self.finally(finally);
+                    let jend = self.jump_forward(Op::JumpForward);
+                    self.patch(h);
+                    self.finally(finally);
+                    self.op(Op::Throw);
+                    self.patch(jend);
+                } else {
+                    self.body(&handler);
+                }
+                self.scope().binds.truncate(depth);
+            }
+            None => {
+                self.finally(finally);
+                self.op(Op::Throw);
+            }
+        }
+        self.patch(jend);
+    }
+
+    // (catch ...) => Some([...])
+    fn clause<'n>(n: &'n Node, name: &str) -> Option<&'n [Node]> {
+        match &n.value {
+            NodeValue::List(ns) => match ns.first().map(|n| &n.value) {
+                Some(NodeValue::Symbol(None, s)) if s == name => Some(&ns[1..]),
+                _ => None,
+            },
+            _ => None,
+        }
+    }
+
+    fn finally(&mut self, ns: Option<&[Node]>) {
+        for n in ns.unwrap_or_default() {
+            self.expr(n);
+            self.op(Op::Drop);
+        }
+    }
+
+    // (throw x)
+    fn throw_form(&mut self, ns: &[Node]) {
+        match ns {
+            [n] => self.expr(n),
+            _ => {
+                self.error_here("throw takes one argument");
+                return self.unit();
+            }
+        }
+        self.op(Op::Throw);
+    }
+
+    // (receive pattern e pattern e ... (after ms timeout...))
+    // Takes the oldest message in the mailbox matching any pattern (see
+    // Val::matches), binds its names, and evaluates the e beside it; until
+    // there is one, the process waits.  With after, it gives up waiting after
+    // ms milliseconds and evaluates timeout instead.
+    fn receive_form(&mut self, ns: &[Node]) {
+        let mut clauses = ns;
+        let after = match clauses.split_last() {
+            Some((last, init)) => Self::clause(last, "after").inspect(|_| clauses = init),
+            None => None,
+        };
+        if clauses.is_empty() && after.is_none() {
+            self.error_here("receive should have a pattern to match, or an after");
+            return self.unit();
+        }
+        if !clauses.len().is_multiple_of(2) {
+            self.error(
+                clauses[clauses.len() - 1].range,
+                "receive takes pattern/expression pairs; this pattern has no expression",
+            );
+            return self.unit();
+        }
+        match after {
+            Some([ms, ..]) => self.expr(ms),
+            Some([]) => {
+                self.error_here("after should be followed by a timeout in milliseconds");
+                return self.unit();
+            }
+            None => self.op(Op::ImmediateBooleanFalse),
+        }
+        self.op(Op::ReceiveStart);
+
+        // Each message in turn, until one matches.
+        let next = self.out.len();
+        let jtimeout = self.jump_forward(Op::ReceiveNext);
+        let mut jends = vec![];
+        for pair in clauses.chunks(2) {
+            let mut names = vec![];
+            self.pattern(&pair[0], &mut names);
+            let depth = self.scope().binds.len();
+            let slots = names
+                .iter()
+                .map(|name| self.scope().bind(name))
+                .collect::<Vec<_>>();
+            self.op(Op::Dup);
+            self.op(Op::Match);
+            let pattern = self.const_index(Self::datum(&pair[0]));
+            self.n(pattern);
+            self.n(slots.first().copied().unwrap_or(0));
+            let jf = self.jump_forward(Op::JumpIfFalse);
+            self.op(Op::ReceiveAccept);
+            self.expr(&pair[1]);
+            jends.push(self.jump_forward(Op::JumpForward));
+            self.patch(jf);
+            self.scope().binds.truncate(depth);
+        }
+        self.op(Op::Drop);
+        self.jump_back(Op::JumpRelative, next);
+
+        self.patch(jtimeout);
+        match after {
+            Some([_, timeout @ ..]) => self.body(&timeout.iter().collect::<Vec<_>>()),
+            // Never taken.
+            _ => self.unit(),
+        }
+        for jend in jends {
+            self.patch(jend);
+        }
+    }
+
+    // Checks a receive pattern, collecting the names it binds in order.
+    fn pattern(&mut self, n: &Node, names: &mut Vec<String>) {
+        match &n.value {
+            NodeValue::Symbol(None, s) if s == "_" || s == "true" || s == "false" => {}
+            NodeValue::Symbol(None, s) if names.contains(s) => {
+                self.error(n.range, format!("{s} appears twice in this pattern"));
+            }
+            NodeValue::Symbol(None, s) => names.push(s.clone()),
+            NodeValue::Integer(_) | NodeValue::Float(_) | NodeValue::String(_) => {}
+            NodeValue::Vec(ns) => {
+                for n in ns {
+                    self.pattern(n, names);
+                }
+            }
+            NodeValue::List(q)
+                if q.len() == 2
+                    && matches!(&q[0].value, NodeValue::Symbol(None, s) if s == "quote") => {}
+            _ => self.error(
+                n.range,
+                format!("can't match on a {}; quote it to match it as is", n.kind()),
+            ),
+        }
+    }
+
+    // (quasiquote x), or `x
+    // x as data, except that (unquote e) (~e) is evaluated, and the elements
+    // of (unquote-splicing e) (~@e) are spliced into the enclosing list or
+    // vector.  Nested quasiquotes need unquoting as many times over.
+    fn quasiquote_form(&mut self, ns: &[Node]) {
+        match ns {
+            [n] => self.quasi(n, 0),
+            _ => {
+                self.error_here("quasiquote takes one argument");
+                self.unit();
+            }
+        }
+    }
+
+    fn quasi(&mut self, n: &Node, depth: usize) {
+        self.ranges.push(n.range);
+        match (Self::quasi_part(n), &n.value) {
+            (Some(("unquote", e)), _) if depth == 0 => self.expr(e),
+            (Some(("unquote-splicing", _)), _) if depth == 0 => {
+                self.error_here("unquote-splicing should be inside a list or vector");
+                self.unit();
+            }
+            (Some((name, e)), _) => {
+                self.constant(Const::Symbol(None, name.to_string()));
+                let depth = if name == "quasiquote" {
+                    depth + 1
+                } else {
+                    depth - 1
+                };
+                self.quasi(e, depth);
+                self.op(Op::ConsList);
+                self.n(2);
+            }
+            (None, NodeValue::List(ns)) => self.quasi_seq(ns, Op::ConsList, Op::ConcatList, depth),
+            (None, NodeValue::Vec(ns)) => self.quasi_seq(ns, Op::ConsVec, Op::ConcatVec, depth),
+            (None, _) => self.literal(n),
+        }
+        self.ranges.pop();
+    }
+
+    // Runs of plain elements are consed up; with anything to splice, those
+    // and the spliced values are then concatenated.
+    fn quasi_seq(&mut self, ns: &[Node], cons: Op, concat: Op, depth: usize) {
+        let splices =
+            |n: &Node| depth == 0 && matches!(Self::quasi_part(n), Some(("unquote-splicing", _)));
+        if !ns.iter().any(splices) {
+            for n in ns {
+                self.quasi(n, depth);
+            }
+            self.op(cons);
+            self.n(ns.len());
+            return;
+        }
+        let mut parts = 0usize;
+        let mut run = 0usize;
+        for n in ns {
+            match Self::quasi_part(n) {
+                Some(("unquote-splicing", e)) if depth == 0 => {
+                    if run > 0 {
+                        self.op(Op::ConsList);
+                        self.n(run);
+                        parts += 1;
+                        run = 0;
+                    }
+                    self.expr(e);
+                    parts += 1;
+                }
+                _ => {
+                    self.quasi(n, depth);
+                    run += 1;
+                }
+            }
+        }
+        if run > 0 {
+            self.op(Op::ConsList);
+            self.n(run);
+            parts += 1;
+        }
+        self.op(concat);
+        self.n(parts);
+    }
+
+    // (unquote x) => Some(("unquote", x)), and likewise quasiquote and
+    // unquote-splicing.
+    fn quasi_part(n: &Node) -> Option<(&'static str, &Node)> {
+        let NodeValue::List(ns) = &n.value else {
+            return None;
+        };
+        let [head, e] = ns.as_slice() else {
+            return None;
+        };
+        match &head.value {
+            NodeValue::Symbol(None, s) => ["quasiquote", "unquote", "unquote-splicing"]
+                .into_iter()
+                .find(|q| q == s)
+                .map(|q| (q, e)),
+            _ => None,
+        }
+    }
+
+    // Emits a forward jump to be patched; returns the offset of its opcode.
+    fn jump_forward(&mut self, op: Op) -> usize {
+        let at = self.out.len();
+        self.op(op);
+        self.out.extend_from_slice(&[0; varint::JUMP_WIDTH]);
+        at
+    }
+
+    // Emits a jump back to `to`, which is already behind us.
+    fn jump_back(&mut self, op: Op, to: usize) {
+        let at = self.out.len();
+        self.op(op);
+        let mut slot = [0; varint::JUMP_WIDTH];
+        if varint::put_padded(&mut slot, (at - to) as u64).is_none() {
+            self.error_here("this form is too big to jump over");
+        }
+        self.out.extend_from_slice(&slot);
+    }
+
+    // Points the forward jump at `at` to here.
+    fn patch(&mut self, at: usize) {
+        let distance = self.out.len() - at;
+        let slot = &mut self.out[at + 1..at + 1 + varint::JUMP_WIDTH];
+        if varint::put_padded(slot, distance as u64).is_none() {
+            self.error_here("this form is too big to jump over");
+        }
+    }
+
+    fn unit(&mut self) {
+        self.op(Op::ConsList);
+        self.n(0);
+    }
+
+    // (let [a 1 b a ...] body...)
+    // Binds are sequential, and later ones shadow earlier (and outer) ones.
+    fn let_form(&mut self, ns: &[Node]) {
+        let binds = match ns.first().map(|n| &n.value) {
+            Some(NodeValue::Vec(bs)) if bs.len().is_multiple_of(2) => bs,
+            Some(NodeValue::Vec(bs)) => {
+                self.error(
+                    bs[bs.len() - 1].range,
+                    "let binding has no value; let takes a vector of pairs",
+                );
+                return self.unit();
+            }
+            _ => {
+                let range = ns.first().map(|n| n.range);
+                self.error(
+                    range.unwrap_or_else(|| self.here()),
+                    "let should be followed by a vector of pairs",
+                );
+                return self.unit();
+            }
+        };
+        let depth = self.scope().binds.len();
+        for pair in binds.chunks(2) {
+            let name = match &pair[0].value {
+                NodeValue::Symbol(None, s) => s,
+                _ => {
+                    self.error(
+                        pair[0].range,
+                        format!("let can only bind symbols, not {}", pair[0].kind()),
+                    );
+                    continue;
+                }
+            };
+            self.expr(&pair[1]);
+            // Slots aren't reused once the let ends; a closure may still
+            // capture them, and this way we don't need to know.
+            let slot = self.scope().bind(name);
+            self.op(Op::StoreLocal);
+            self.n(slot);
+        }
+        self.body(&ns[1..].iter().collect::<Vec<_>>());
+        self.scope().binds.truncate(depth);
+    }
+
+    // (defn name [params] -> ty "doc" body...)
+    // (defmacro name [params] body...)
+    // (fn [params] body...)
+    fn function_form(&mut self, form: &str, ns: &[Node]) {
+        let named = form != "fn";
+        let mut ns = ns.iter().peekable();
+        let name = if named {
+            match ns.peek().map(|n| &n.value) {
+                Some(NodeValue::Symbol(None, s)) => {
+                    ns.next();
+                    s.clone()
+                }
+                _ => {
+                    self.error_at(ns.peek(), format!("{form} should be followed by a name"));
+                    return self.unit();
+                }
+            }
+        } else {
+            "fn".to_string()
+        };
+        let params = match ns.peek().map(|n| &n.value) {
+            Some(NodeValue::Vec(ps)) => {
+                ns.next();
+                self.params(ps)
+            }
+            _ => {
+                self.error_at(ns.peek(), format!("{name} should have a parameter vector"));
+                return self.unit();
+            }
+        };
+        if let Some(NodeValue::Symbol(None, s)) = ns.peek().map(|n| &n.value) {
+            if s == "->" {
+                // return type: not checked (yet).
+                ns.next();
+                ns.next();
+            }
+        }
+        let mut body = ns.collect::<Vec<_>>();
+        if body.len() > 1 {
+            if let NodeValue::String(_) = body[0].value {
+                // docstring
+                body.remove(0);
+            }
+        }
+
+        match form {
+            "defn" => self.store_global(&name, |c| c.function(&name, &params, &body)),
+            "defmacro" => {
+                self.constant(Const::Symbol(None, name.clone()));
+                self.function(&name, &params, &body);
+                self.op(Op::DefineMacro);
+            }
+            _ => self.function(&name, &params, &body),
+        }
+    }
+
+    // [a b] or [a: ty b: ty]; the latter reads as [(quote a) ty (quote b) ty].
+    fn params(&mut self, ps: &[Node]) -> Vec<String> {
+        let mut params: Vec<(String, Range)> = vec![];
+        let mut ps = ps.iter();
+        while let Some(p) = ps.next() {
+            let name = match &p.value {
+                NodeValue::Symbol(None, s) => s,
+                NodeValue::List(q) if q.len() == 2 => match (&q[0].value, &q[1].value) {
+                    (NodeValue::Symbol(None, quote), NodeValue::Symbol(None, s))
+                        if quote == "quote" =>
+                    {
+                        // type: not checked (yet).
+                        ps.next();
+                        s
+                    }
+                    _ => {
+                        self.error(p.range, format!("bad parameter {p}"));
+                        continue;
+                    }
+                },
+                _ => {
+                    self.error(p.range, format!("bad parameter {p}"));
+                    continue;
+                }
+            };
+            if let Some((_, first)) = params.iter().find(|(n, _)| n == name) {
+                let d = Diagnostic::new(
+                    Severity::Error,
+                    p.range,
+                    format!("duplicate parameter {name}"),
+                )
+                .note(Some(*first), "first declared here");
+                self.diagnostics.push(d);
+                continue;
+            }
+            params.push((name.clone(), p.range));
+        }
+        params.into_iter().map(|(n, _)| n).collect()
+    }
+
+    fn function(&mut self, name: &str, params: &[String], body: &[&Node]) {
+        let mut scope = Scope::new();
+        for p in params {
+            scope.bind(p);
+        }
+        self.scopes.push(scope);
+        let outer = mem::take(&mut self.out);
+        let outer_map = mem::take(&mut self.source_map);
+
+        self.body(body);
+        self.op(Op::Return);
+
+        let code = mem::replace(&mut self.out, outer);
+        let map = mem::replace(&mut self.source_map, outer_map);
+        let scope = self.scopes.pop().unwrap();
+
+        self.op(Op::MakeFunction);
+        let name = self.const_index(Const::String(name.to_string()));
+        self.n(name);
+        self.n(params.len());
+        self.n(scope.captures.len());
+        for (_, slot, outer_slot) in scope.captures {
+            self.n(slot);
+            self.n(outer_slot);
+        }
+        self.n(code.len());
+        let base = self.out.len();
+        self.out.extend_from_slice(&code);
+        self.source_map.extend(
+            map.into_iter()
+                .map(|(offset, range)| (base + offset, range)),
+        );
+    }
+
+    fn body(&mut self, body: &[&Node]) {
+        match body.split_last() {
+            None => self.unit(),
+            Some((last, init)) => {
+                for n in init {
+                    self.expr(n);
+                    self.op(Op::Drop);
+                }
+                self.expr(last);
+            }
+        }
+    }
+
+    fn error<S: Into<String>>(&mut self, range: Range, message: S) {
+        self.diagnostics
+            .push(Diagnostic::new(Severity::Error, range, message));
+    }
+
+    // At the form currently being compiled.
+    fn error_here<S: Into<String>>(&mut self, message: S) {
+        self.error(self.here(), message);
+    }
+
+    // At n, or the enclosing form if the node we wanted isn't there.
+    fn error_at<S: Into<String>>(&mut self, n: Option<&&Node>, message: S) {
+        let range = n.map_or_else(|| self.here(), |n| n.range);
+        self.error(range, message);
+    }
+
+    fn errors(&self) -> usize {
+        self.diagnostics
+            .iter()
+            .filter(|d| d.severity == Severity::Error)
+            .count()
+    }
+
+    fn warn<S: Into<String>>(&mut self, range: Range, message: S) {
+        self.diagnostics
+            .push(Diagnostic::new(Severity::Warning, range, message));
+    }
+
+    fn here(&self) -> Range {
+        *self.ranges.last().expect("should be compiling something")
+    }
+
+    fn scope(&mut self) -> &mut Scope {
+        self.scopes.last_mut().expect("should always have a scope")
+    }
+
+    // Like resolve, but without capturing anything.
+    fn is_local(&self, name: &str) -> bool {
+        self.scopes.iter().any(|scope| {
+            scope.binds.iter().any(|(n, _)| n == name)
+                || scope.captures.iter().any(|(n, _, _)| n == name)
+        })
+    }
+
+    fn resolve(&mut self, name: &str) -> Option<usize> {
+        self.resolve_in(self.scopes.len() - 1, name)
+    }
+
+    fn resolve_in(&mut self, depth: usize, name: &str) -> Option<usize> {
+        let scope = &self.scopes[depth];
+        if let Some((_, slot)) = scope.binds.iter().rev().find(|(n, _)| n == name) {
+            return Some(*slot);
+        }
+        if let Some((_, slot, _)) = scope.captures.iter().find(|(n, _, _)| n == name) {
+            return Some(*slot);
+        }
+        if depth == 0 {
+            return None;
+        }
+        let outer_slot = self.resolve_in(depth - 1, name)?;
+        let scope = &mut self.scopes[depth];
+        let slot = scope.alloc();
+        scope.captures.push((name.to_string(), slot, outer_slot));
+        Some(slot)
+    }
+
     fn op(&mut self, op: Op) {
-        match (&op, self.omit_evals) {
-            (&Op::Eval, true) => {}
-            (&Op::Call, true) => self.out.push(Op::ConsList as u8),
-            _ => self.out.push(op as u8),
+        if let Some(&range) = self.ranges.last() {
+            if self.source_map.last().map(|&(_, r)| r) != Some(range) {
+                self.source_map.push((self.out.len(), range));
+            }
         }
+        self.out.push(op as u8);
+    }
+
+    fn n(&mut self, n: usize) {
+        varint::put(&mut self.out, n as u64);
     }
 
-    fn n<T: ToBytes<Bytes = [u8; 8]>>(&mut self, u: T) {
-        self.out.extend_from_slice(&u.to_le_bytes());
+    // Pushes c, from the constant pool.
+    fn constant(&mut self, c: Const) {
+        let i = self.const_index(c);
+        self.op(Op::Constant);
+        self.n(i);
     }
 
-    fn bytes<S: AsRef<[u8]>>(&mut self, s: S) {
-        let s = s.as_ref();
-        self.n(s.len());
-        self.out.extend_from_slice(s);
+    fn const_index(&mut self, c: Const) -> usize {
+        match self.consts.iter().position(|d| *d == c) {
+            Some(i) => i,
+            None => {
+                self.consts.push(c);
+                self.consts.len() - 1
+            }
+        }
     }
 }
@@ -2,7 +2,9 @@
 
 use std::collections::HashMap;
 
+use crate::compiler::Compiler;
 use crate::parser::Document;
+use crate::vm::{Const, Global, Vm};
 
 struct AsmState {
     out: Vec<u8>,
@@ -56,7 +58,8 @@ macro_rules! asm_into {
         asm_into!(=> $state, { rip &$label (-1); $( $rest )* });
     };
     (=> $state:ident, { rip &$label:ident (-$n:literal); $( $rest:tt )* }) => {
-        $state.out.extend_from_slice(&($state.since(stringify!($label)) - $n).to_le_bytes());
+        let distance = $state.since(stringify!($label)) - $n;
+        asm_into!(=> $state, j distance);
         asm_into!(=> $state, { $( $rest )* });
     };
 
@@ -68,17 +71,26 @@ macro_rules! asm_into {
         $state.out.push($crate::vm::Op::$op as u8);
     };
     (=> $state:ident, n $value:literal) => {
-        $state.out.extend_from_slice(&($value as u64).to_le_bytes());
+        $crate::vm::varint::put(&mut $state.out, $value);
     };
-
-    (=> $state:ident, str $value:literal) => {
-        $state.out.extend_from_slice($value.as_bytes());
+    (=> $state:ident, i $value:literal) => {
+        $crate::vm::varint::put_signed(&mut $state.out, $value);
+    };
+    // A jump operand, padded like the compiler's.
+    (=> $state:ident, j $value:expr) => {
+        let at = $state.out.len();
+        $state.out.resize(at + $crate::vm::varint::JUMP_WIDTH, 0);
+        $crate::vm::varint::put_padded(&mut $state.out[at..], $value).unwrap();
     };
+
 }
 
 fn assert_compiles<C: AsRef<[u8]>>(code: &str, expected: C) {
     let doc = code.parse::<Document>().unwrap();
-    assert_eq!(Ok(expected.as_ref()), doc.compile().as_deref());
+    assert_eq!(
+        Ok(expected.as_ref()),
+        doc.compile().map(|c| c.bytes).as_deref()
+    );
 }
 
 #[test]
@@ -94,9 +106,8 @@ fn loop_compiles_just_fine() {
         "(loop (awawa))",
         asm! {
         begin:
-            op  ImmediateSymbolBare;
-            n   5;
-            str "awawa";
+            op  Constant;
+            n   0;
 
             op  ConsList;
             n   1;
@@ -108,3 +119,268 @@ fn loop_compiles_just_fine() {
         },
     );
 }
+
+#[test]
+fn fn_compiles_inline() {
+    assert_compiles(
+        "(fn [x] x)",
+        asm! {
+            op  MakeFunction;
+            n   0;
+            n   1;
+            n   0;
+            n   3;
+
+            op  LoadLocal;
+            n   0;
+            op  Return;
+
+            op  Drop;
+        },
+    );
+}
+
+#[test]
+fn quasiquote_conses() {
+    assert_compiles(
+        "`[a ~b ~@c]",
+        asm! {
+            op  Constant;
+            n   0;
+            op  Constant;
+            n   1;
+            op  Eval;
+            op  ConsList;
+            n   2;
+
+            op  Constant;
+            n   2;
+            op  Eval;
+
+            op  ConcatVec;
+            n   2;
+
+            op  Drop;
+        },
+    );
+}
+
+#[test]
+fn if_jumps_forward() {
+    assert_compiles(
+        "(if true 1 2)",
+        asm! {
+            op  ImmediateBooleanTrue;
+            op  JumpIfFalse;
+            j   12;
+            op  ImmediateInteger;
+            i   1;
+            op  JumpForward;
+            j   7;
+            op  ImmediateInteger;
+            i   2;
+            op  Drop;
+        },
+    );
+}
+
+#[test]
+fn constants_are_pooled() {
+    let code = "[\"a\" 'a \"a\" 1.5 0.0 1.5 '(x \"y\" 2) 'x]"
+        .parse::<Document>()
+        .unwrap()
+        .compile()
+        .unwrap();
+    assert_eq!(
+        vec![
+            Const::String("a".to_string()),
+            Const::Symbol(None, "a".to_string()),
+            Const::Float(1.5),
+            Const::Float(0.0),
+            Const::List(vec![
+                Const::Symbol(None, "x".to_string()),
+                Const::String("y".to_string()),
+                Const::Integer(2),
+            ]),
+            Const::Symbol(None, "x".to_string()),
+        ],
+        code.consts
+    );
+}
+
+fn diagnostics(code: &str) -> Vec<String> {
+    let doc = code.parse::<Document>().unwrap();
+    let mut c = Compiler::new();
+    c.doc(&doc);
+    match c.finish() {
+        Ok(_) => c.warnings().iter().map(|d| d.to_string()).collect(),
+        Err(err) => err.diagnostics.iter().map(|d| d.to_string()).collect(),
+    }
+}
+
+#[test]
+fn errors_are_collected() {
+    assert_eq!(
+        vec![
+            "error: if takes a condition and one or two branches at [0:0-0:4]",
+            "error: let should be followed by a vector of pairs at [1:5-1:6]",
+            "warning: top-level integer has no effect at [2:0-2:1]",
+            "error: duplicate parameter x at [3:11-3:12]\n  note: first declared here at [3:9-3:10]",
+        ],
+        diagnostics("(if)\n(let x)\n1\n(defn f [x x] x)")
+    );
+}
+
+#[test]
+fn empty_list_is_unit() {
+    assert_compiles(
+        "(print ())",
+        asm! {
+            op  Constant;
+            n   0;
+            op  Eval;
+            op  ConsList;
+            n   0;
+            op  Call;
+            n   2;
+            op  Drop;
+        },
+    );
+}
+
+#[test]
+fn mod_decl() {
+    let doc = "(mod kcx submods: ['util x])\n(defn main [] 1)"
+        .parse::<Document>()
+        .unwrap();
+    let decl = doc.mod_decl().unwrap().unwrap();
+    assert_eq!("kcx", decl.name);
+    assert_eq!(
+        vec!["util", "x"],
+        decl.submods.iter().map(|(s, _)| s).collect::<Vec<_>>()
+    );
+    assert!(doc.compile().is_ok());
+
+    assert_eq!(
+        vec!["error: mod should be the first form in a file at [1:0-1:9]"],
+        diagnostics("(print 1)\n(mod kcx)")
+    );
+    assert_eq!(
+        vec!["error: unknown mod option subs: at [0:9-0:14]"],
+        diagnostics("(mod kcx subs: [])")
+    );
+}
+
+#[test]
+fn macro_errors() {
+    assert_eq!(
+        vec!["error: expanding bad: wrong number of arguments: bad takes 1 argument(s), got 0 at [0:21-0:26]"],
+        diagnostics("(defmacro bad [x] x) (bad)")
+    );
+    assert_eq!(
+        vec!["error: expanding forever doesn't stop at [0:38-0:47]"],
+        diagnostics("(defmacro forever [] (list 'forever)) (forever)")
+    );
+    assert_eq!(
+        vec![
+            "error: f expanded to <builtin builtins/print>, which can't be compiled at [0:22-0:25]"
+        ],
+        diagnostics("(defmacro f [] print) (f)")
+    );
+    assert_eq!(
+        vec!["error: defmacro should be at the top level at [0:11-0:28]"],
+        diagnostics("(defn f [] (defmacro g [] 1))")
+    );
+    assert_eq!(
+        vec!["error: defmacro should be at the top level at [0:11-0:28]"],
+        diagnostics("(let [x 1] (defmacro m [] x))")
+    );
+    // However it loops, and whatever it does about it.
+    for body in [
+        "((fn [f] (f f)) (fn [f] (f f)))",
+        "(defn l [] (try (l) (catch e (l)))) (l)",
+        "(spawn (fn [] ((fn [f] (f f)) (fn [f] (f f))))) (receive x x)",
+    ] {
+        let found = diagnostics(&format!("(defmacro m [] {body}) (m)"));
+        assert!(
+            found.iter().any(|d| d.contains("out of reductions")),
+            "{body}: {found:?}"
+        );
+    }
+}
+
+#[test]
+fn unquote_outside_quasiquote() {
+    assert_eq!(
+        vec![
+            "error: unquote should be inside a quasiquote at [0:0-0:2]",
+            "error: unquote-splicing should be inside a list or vector at [0:4-0:7]",
+        ],
+        diagnostics("~a `~@b")
+    );
+}
+
+#[test]
+fn special_form_errors() {
+    assert_eq!(
+        vec![
+            "error: set can only set symbols, not integer at [0:5-0:6]",
+            "error: set takes a name and a value at [1:0-1:7]",
+            "error: quote takes one argument at [2:0-2:11]",
+        ],
+        diagnostics("(set 1 2)\n(set x)\n(quote a b)")
+    );
+}
+
+#[test]
+fn receive_errors() {
+    assert_eq!(
+        vec![
+            "error: receive should have a pattern to match, or an after at [0:0-0:9]",
+            "error: can't match on a list; quote it to match it as is at [1:9-1:14]",
+            "error: x appears twice in this pattern at [2:12-2:13]",
+            "error: receive takes pattern/expression pairs; this pattern has no expression at [3:9-3:10]",
+        ],
+        diagnostics("(receive)
+(receive (f x) 1)
+(receive [x x] x)
+(receive x)")
+    );
+}
+
+#[test]
+fn globals_are_resolved() {
+    let mut vm = Vm::new();
+    let module = vm.anonymous_module("*test*");
+    let doc = "(set x print) x".parse::<Document>().unwrap();
+    let mut c = Compiler::in_module(&mut vm, module);
+    c.doc(&doc);
+    let code = c.finish().unwrap();
+    assert_eq!(
+        asm! {
+            op  LoadGlobal;
+            n   0;
+            op  StoreGlobal;
+            n   1;
+            op  Drop;
+
+            op  LoadGlobal;
+            n   1;
+            op  Drop;
+        },
+        code.bytes
+    );
+    assert_eq!(
+        vec![
+            Global {
+                module: Some("builtins".to_string()),
+                name: "print".to_string(),
+            },
+            Global {
+                module: None,
+                name: "x".to_string(),
+            },
+        ],
+        code.globals
+    );
+}
@@ -1,95 +1,353 @@
+mod tests;
+
 use num_traits::FromPrimitive;
+use std::collections::{BTreeMap, BTreeSet, HashSet};
+use std::fmt::Write as _;
 use std::io::{self, Write};
-use std::str;
 
-use crate::vm::Op;
+use crate::object::Object;
+use crate::parser::Range;
+use crate::vm::{varint, Code, Op};
 
-pub(crate) fn disasm(code: &[u8]) -> io::Result<()> {
+// For the REPL: code as it was typed in.
+pub(crate) fn disasm(code: &Code, source: Option<&str>) -> io::Result<()> {
     let mut stdout = io::stdout().lock();
-    write!(stdout, "{}", Disassembler::new(code).disasm())?;
+    write!(stdout, "{}", disassemble(code).render(source))?;
     Ok(())
 }
 
-struct Disassembler<'c> {
-    code: &'c [u8],
-    ip: usize,
+// The whole module, in the form asm::assemble reads.  Given the source it
+// was compiled from, each form is shown above the code it became.
+pub(crate) fn listing(object: &Object, source: Option<&str>) -> String {
+    let mut out = String::new();
+    writeln!(out, "module {}", object.name).unwrap();
+    if let Some(file) = &object.code.file {
+        writeln!(out, "file {file:?}").unwrap();
+    }
+    if !object.deps.is_empty() {
+        writeln!(out, "deps {}", object.deps.join(" ")).unwrap();
+    }
+    if let Some(exports) = &object.exports {
+        write!(out, "exports").unwrap();
+        for e in exports {
+            write!(out, " {e}").unwrap();
+        }
+        writeln!(out).unwrap();
+    }
+    out.push_str(&disassemble(&object.code).render_with(source, true));
+    out
 }
 
-impl<'c> Disassembler<'c> {
-    fn new(code: &'c [u8]) -> Disassembler<'c> {
-        Disassembler { code, ip: 0 }
+// Never panics, however bad the code: a function body (or the top level)
+// that can't be decoded ends in a Line::Error, and decoding carries on after
+// it where it can.
+pub(crate) fn disassemble(code: &Code) -> Disassembly<'_> {
+    let mut d = Decoder {
+        code,
+        targets: BTreeSet::new(),
+        ops: HashSet::new(),
+    };
+    let lines = d.region(0, code.bytes.len());
+    // Only targets that are really ops (or the very end) can be labelled;
+    // the rest stay numeric.
+    let labels = d
+        .targets
+        .iter()
+        .filter(|t| d.ops.contains(t) || **t == code.bytes.len())
+        .enumerate()
+        .map(|(i, &t)| (t, format!("L{i}")))
+        .collect();
+    Disassembly {
+        code,
+        lines,
+        labels,
     }
+}
 
-    fn disasm(&mut self) -> String {
-        let mut out = vec![];
-
-        while self.ip < self.code.len() {
-            write!(out, "{:08x} ", self.ip).unwrap();
-            let op = Op::from_u8(self.code[self.ip])
-                .ok_or_else(|| format!("invalid opcode {}", self.code[self.ip]))
-                .unwrap();
-            self.ip += 1;
-
-            match op {
-                Op::Nop => writeln!(out, "{op}").unwrap(),
-                Op::ImmediateSymbolBare => {
-                    let s = self.bytes();
-                    writeln!(out, "{op} {s:?}").unwrap();
-                }
-                Op::ImmediateSymbolWithModule => {
-                    let m = self.bytes();
-                    let s = self.bytes();
-                    writeln!(out, "{op} {m:?} {s:?}").unwrap();
-                }
-                Op::ImmediateBooleanTrue => writeln!(out, "{op}").unwrap(),
-                Op::ImmediateBooleanFalse => writeln!(out, "{op}").unwrap(),
-                Op::ImmediateInteger => {
-                    let i = self.n();
-                    writeln!(out, "{op} {i:?}").unwrap();
-                }
-                Op::ImmediateFloat => {
-                    let f = self.n();
-                    writeln!(out, "{op} {f:?}").unwrap();
+pub(crate) struct Disassembly<'c> {
+    pub(crate) code: &'c Code,
+    pub(crate) lines: Vec<Line>,
+    // Offset => name, for everywhere something jumps to.
+    pub(crate) labels: BTreeMap<usize, String>,
+}
+
+pub(crate) enum Line {
+    Insn(Insn),
+    // (offset, message): nothing after offset in the same body could be
+    // decoded.
+    Error(usize, String),
+}
+
+pub(crate) struct Insn {
+    pub(crate) offset: usize,
+    pub(crate) op: Op,
+    pub(crate) operands: Vec<Operand>,
+    // Per the source map.
+    pub(crate) range: Option<Range>,
+    // MakeFunction's, which follows it inline.
+    pub(crate) body: Vec<Line>,
+}
+
+pub(crate) enum Operand {
+    Number(usize),
+    Integer(i64),
+    Constant(usize),
+    Global(usize),
+    // (callee_slot, slot)
+    Capture(usize, usize),
+    // (offset, as encoded): where a jump lands, or a function body ends.
+    Target(usize, usize),
+}
+
+struct Decoder<'c> {
+    code: &'c Code,
+    targets: BTreeSet<usize>,
+    // Offsets of every op decoded.
+    ops: HashSet<usize>,
+}
+
+impl<'c> Decoder<'c> {
+    fn region(&mut self, start: usize, end: usize) -> Vec<Line> {
+        let mut lines = vec![];
+        let mut at = start;
+        while at < end {
+            match self.insn(at, end) {
+                Ok((insn, next)) => {
+                    lines.push(Line::Insn(insn));
+                    at = next;
                 }
-                Op::ImmediateString => {
-                    let s = self.bytes();
-                    writeln!(out, "{op} {s:?}").unwrap();
+                Err(message) => {
+                    lines.push(Line::Error(at, message));
+                    break;
                 }
-                Op::ConsList => {
-                    let n = self.n();
-                    writeln!(out, "{op} {n:?}").unwrap();
+            }
+        }
+        lines
+    }
+
+    fn insn(&mut self, at: usize, end: usize) -> Result<(Insn, usize), String> {
+        let bytes = &self.code.bytes[..end];
+        let op = Op::from_u8(bytes[at]).ok_or_else(|| format!("invalid opcode {}", bytes[at]))?;
+        let mut ip = at + 1;
+        let mut n = || {
+            varint::get(bytes, &mut ip)
+                .and_then(|n| usize::try_from(n).ok())
+                .ok_or_else(|| format!("bad operand to {op}"))
+        };
+        let mut body = vec![];
+        let operands = match op {
+            Op::Nop
+            | Op::ImmediateBooleanTrue
+            | Op::ImmediateBooleanFalse
+            | Op::Drop
+            | Op::Eval
+            | Op::Return
+            | Op::Dup
+            | Op::Define
+            | Op::DefineMacro
+            | Op::PopHandler
+            | Op::Throw
+            | Op::ReceiveStart
+            | Op::ReceiveAccept => vec![],
+            Op::Constant => vec![Operand::Constant(n()?)],
+            Op::ImmediateInteger => {
+                let i = varint::get_signed(bytes, &mut ip)
+                    .ok_or_else(|| format!("bad operand to {op}"))?;
+                vec![Operand::Integer(i)]
+            }
+            Op::ConsList
+            | Op::ConsVec
+            | Op::ConcatList
+            | Op::ConcatVec
+            | Op::Call
+            | Op::LoadLocal
+            | Op::StoreLocal => vec![Operand::Number(n()?)],
+            Op::LoadGlobal | Op::StoreGlobal => vec![Operand::Global(n()?)],
+            Op::JumpRelative => {
+                let d = n()?;
+                let target = at.checked_sub(d).ok_or("jump out of range")?;
+                self.targets.insert(target);
+                vec![Operand::Target(target, d)]
+            }
+            Op::JumpForward | Op::JumpIfFalse | Op::PushHandler | Op::ReceiveNext => {
+                let d = n()?;
+                let target = at.checked_add(d).ok_or("jump out of range")?;
+                self.targets.insert(target);
+                vec![Operand::Target(target, d)]
+            }
+            // pattern, first slot bound
+            Op::Match => vec![Operand::Constant(n()?), Operand::Number(n()?)],
+            Op::MakeFunction => {
+                let mut operands = vec![Operand::Constant(n()?), Operand::Number(n()?)];
+                for _ in 0..n()? {
+                    operands.push(Operand::Capture(n()?, n()?));
                 }
-                Op::ConsVec => {
-                    let n = self.n();
-                    writeln!(out, "{op} {n:?}").unwrap();
+                let len = n()?;
+                let body_end = ip
+                    .checked_add(len)
+                    .filter(|&e| e <= end)
+                    .ok_or("function body out of range")?;
+                self.targets.insert(body_end);
+                operands.push(Operand::Target(body_end, len));
+                body = self.region(ip, body_end);
+                ip = body_end;
+                operands
+            }
+        };
+        self.ops.insert(at);
+        let insn = Insn {
+            offset: at,
+            op,
+            operands,
+            range: self.code.range_at(at),
+            body,
+        };
+        Ok((insn, ip))
+    }
+}
+
+impl Disassembly<'_> {
+    pub(crate) fn render(&self, source: Option<&str>) -> String {
+        self.render_with(source, false)
+    }
+
+    // With `map`, the source map is written out in full, as at lines.
+    fn render_with(&self, source: Option<&str>, map: bool) -> String {
+        let mut r = Renderer {
+            d: self,
+            source,
+            map,
+            last_range: None,
+            out: String::new(),
+        };
+        let code = self.code;
+        if !code.consts.is_empty() {
+            writeln!(r.out, "consts:").unwrap();
+            for (i, c) in code.consts.iter().enumerate() {
+                writeln!(r.out, "{i:>8} {c}").unwrap();
+            }
+        }
+        if !code.globals.is_empty() {
+            writeln!(r.out, "globals:").unwrap();
+            for (i, g) in code.globals.iter().enumerate() {
+                writeln!(r.out, "{i:>8} {g}").unwrap();
+            }
+        }
+        writeln!(r.out, "code:").unwrap();
+        r.lines(&self.lines, 0);
+        if let Some(label) = self.labels.get(&code.bytes.len()) {
+            writeln!(r.out, "{label}:").unwrap();
+        }
+        r.ats(code.bytes.len(), "");
+        r.out
+    }
+}
+
+struct Renderer<'d, 'c> {
+    d: &'d Disassembly<'c>,
+    source: Option<&'d str>,
+    map: bool,
+    last_range: Option<Range>,
+    out: String,
+}
+
+impl Renderer<'_, '_> {
+    fn lines(&mut self, lines: &[Line], depth: usize) {
+        let indent = "  ".repeat(depth);
+        for line in lines {
+            let insn = match line {
+                Line::Insn(insn) => insn,
+                Line::Error(offset, message) => {
+                    writeln!(self.out, "{offset:08x} {indent}; error: {message}").unwrap();
+                    continue;
                 }
-                Op::Drop => writeln!(out, "{op}").unwrap(),
-                Op::Eval => writeln!(out, "{op}").unwrap(),
-                Op::Call => {
-                    let n = self.n();
-                    writeln!(out, "{op} {n:?}").unwrap();
+            };
+            if let Some(label) = self.d.labels.get(&insn.offset) {
+                writeln!(self.out, "{label}:").unwrap();
+            }
+            if self.map {
+                self.ats(insn.offset, &indent);
+            } else if let (Some(source), Some(range)) = (self.source, insn.range) {
+                if self.last_range != Some(range) {
+                    self.last_range = Some(range);
+                    if let Some(form) = excerpt(source, range) {
+                        let (line, col) = (range.0 .0 + 1, range.0 .1 + 1);
+                        writeln!(self.out, "{:8} {indent}; {line}:{col} {form}", "").unwrap();
+                    }
                 }
-                Op::JumpRelative => {
-                    let n = self.n();
-                    writeln!(out, "{op} {n:?}").unwrap();
+            }
+
+            write!(self.out, "{:08x} {indent}{}", insn.offset, insn.op).unwrap();
+            let mut comment = None;
+            for operand in &insn.operands {
+                match *operand {
+                    Operand::Number(n) => write!(self.out, " {n}").unwrap(),
+                    Operand::Integer(i) => write!(self.out, " {i}").unwrap(),
+                    Operand::Constant(i) => {
+                        write!(self.out, " {i}").unwrap();
+                        comment = Some(match self.d.code.consts.get(i) {
+                            Some(c) => c.to_string(),
+                            None => format!("bad constant {i}"),
+                        });
+                    }
+                    Operand::Global(i) => {
+                        write!(self.out, " {i}").unwrap();
+                        comment = Some(match self.d.code.globals.get(i) {
+                            Some(g) => g.to_string(),
+                            None => format!("bad global {i}"),
+                        });
+                    }
+                    Operand::Capture(callee_slot, slot) => {
+                        write!(self.out, " {callee_slot}:{slot}").unwrap()
+                    }
+                    Operand::Target(offset, d) => match self.d.labels.get(&offset) {
+                        Some(label) => write!(self.out, " @{label}").unwrap(),
+                        None => {
+                            write!(self.out, " {d}").unwrap();
+                            comment = Some(format!("-> {offset:08x}, which isn't an op"));
+                        }
+                    },
                 }
             }
+            match comment {
+                Some(comment) => writeln!(self.out, " ; {comment}").unwrap(),
+                None => writeln!(self.out).unwrap(),
+            }
+            self.lines(&insn.body, depth + 1);
         }
-
-        String::from_utf8(out).unwrap()
     }
 
-    fn n(&mut self) -> usize {
-        let n = usize::from_le_bytes(self.code[self.ip..self.ip + 8].try_into().unwrap());
-        self.ip += 8;
-        n
+    // The source map entries at offset, 1-based like the source comments.
+    fn ats(&mut self, offset: usize, indent: &str) {
+        let map = &self.d.code.source_map;
+        let from = map.partition_point(|&(o, _)| o < offset);
+        for &(_, range) in map[from..].iter().take_while(|&&(o, _)| o == offset) {
+            let Range(start, end) = range;
+            write!(
+                self.out,
+                "{:8} {indent}at {}:{}-{}:{}",
+                "",
+                start.0 + 1,
+                start.1 + 1,
+                end.0 + 1,
+                end.1 + 1
+            )
+            .unwrap();
+            match self.source.and_then(|s| excerpt(s, range)) {
+                Some(form) => writeln!(self.out, " ; {form}").unwrap(),
+                None => writeln!(self.out).unwrap(),
+            }
+        }
     }
+}
 
-    fn bytes(&mut self) -> &'c str {
-        let n = self.n();
-        let s =
-            str::from_utf8(&self.code[self.ip..self.ip + n]).expect("source should be valid utf-8");
-        self.ip += n;
-        s
+// The first line of range in source.
+fn excerpt(source: &str, range: Range) -> Option<String> {
+    let Range(start, end) = range;
+    let line = source.lines().nth(start.0)?;
+    if start.0 == end.0 {
+        line.get(start.1..end.1).map(str::to_string)
+    } else {
+        line.get(start.1..).map(|l| format!("{} ...", l.trim_end()))
     }
 }
@@ -0,0 +1,84 @@
+#![cfg(test)]
+
+use super::{disassemble, Line};
+use crate::compiler::Compiler;
+use crate::parser::Document;
+use crate::vm::{Code, Const, Op};
+
+fn compiled(source: &str) -> Code {
+    let doc = source.parse::<Document>().unwrap();
+    let mut c = Compiler::new();
+    c.doc(&doc);
+    c.finish().unwrap()
+}
+
+#[test]
+fn labels_and_nesting() {
+    let code = compiled("(fn [x] (if x 1 2))");
+    assert_eq!(
+        "consts:
+       0 \"fn\"
+code:
+00000000 MakeFunction 0 1 @L2 ; \"fn\"
+00000005   LoadLocal 0
+00000007   JumpIfFalse @L0
+0000000c   ImmediateInteger 1
+0000000e   JumpForward @L1
+L0:
+00000013   ImmediateInteger 2
+L1:
+00000015   Return
+L2:
+00000016 Drop
+",
+        disassemble(&code).render(None)
+    );
+}
+
+#[test]
+fn source_is_interleaved() {
+    let source = "(print\n  (if true 1 2))";
+    let rendered = disassemble(&compiled(source)).render(Some(source));
+    for line in [
+        "         ; 1:1 (print ...\n",
+        "         ; 2:3 (if true 1 2)\n",
+        "         ; 2:7 true\n",
+    ] {
+        assert!(rendered.contains(line), "{rendered}");
+    }
+}
+
+#[test]
+fn malformed_code_is_reported() {
+    let mut code = Code::new(vec![
+        Op::MakeFunction as u8,
+        0,
+        0,
+        0,
+        2,
+        0xff,
+        Op::Return as u8,
+        Op::JumpForward as u8,
+        1,
+        Op::ImmediateInteger as u8,
+        0x80,
+    ]);
+    code.consts = vec![Const::String("f".to_string())];
+    let d = disassemble(&code);
+    let Some(Line::Insn(f)) = d.lines.first() else {
+        panic!("MakeFunction should decode");
+    };
+    assert!(matches!(f.body[..], [Line::Error(5, _)]));
+    assert_eq!(
+        "consts:
+       0 \"f\"
+code:
+00000000 MakeFunction 0 0 @L0 ; \"f\"
+00000005   ; error: invalid opcode 255
+L0:
+00000007 JumpForward 1 ; -> 00000008, which isn't an op
+00000009 ; error: bad operand to ImmediateInteger
+",
+        d.render(None)
+    );
+}
@@ -0,0 +1,456 @@
+mod tests;
+
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::fs;
+use std::mem;
+use std::path::{Path, PathBuf};
+use std::rc::Rc;
+
+use crate::compiler::{Compiler, Diagnostic, Severity};
+use crate::object::{self, Object};
+use crate::parser::{Document, Range};
+use crate::report::Report;
+use crate::vm::{Code, Error, Module, Vm};
+
+// Loads a project from disk.  The entry file declares the root module; each
+// (mod name submods: ['a]) then pulls in name.a from a.lia alongside it,
+// name.a.b from a/b.lia, and so on.
+//
+// If the entry is a compiled .liac instead, so is everything it loads, and
+// nothing is parsed.
+pub(crate) struct Loader<'v> {
+    vm: &'v mut Vm,
+    // Name of the root module, the directory it lives in, and the extension
+    // of the entry.
+    root: Option<(String, PathBuf, &'static str)>,
+    // (module, canonical path), outermost first.  Reaching one of these
+    // files again means we've gone in a circle.
+    loading: Vec<(String, PathBuf)>,
+    sources: HashMap<String, String>,
+    // (file, report), warnings included.
+    reports: Vec<(Option<String>, Report)>,
+    // (path, object) for each module compiled from source; see compile_only.
+    objects: Option<Vec<(PathBuf, Vec<u8>)>>,
+}
+
+impl<'v> Loader<'v> {
+    pub(crate) fn new(vm: &'v mut Vm) -> Self {
+        Loader {
+            vm,
+            root: None,
+            loading: vec![],
+            sources: HashMap::new(),
+            reports: vec![],
+            objects: None,
+        }
+    }
+
+    // Compiles modules from here on without running them, for alia compile,
+    // and serializes each to be written out.  Compiling still does what it
+    // must at compile time -- defmacro, refer and the like -- but nothing
+    // else at the top level happens.
+    pub(crate) fn compile_only(&mut self) {
+        self.objects = Some(vec![]);
+    }
+
+    // Where each object should go, and its bytes.
+    pub(crate) fn take_objects(&mut self) -> Vec<(PathBuf, Vec<u8>)> {
+        self.objects.take().unwrap_or_default()
+    }
+
+    // None if anything went wrong; see render().
+    pub(crate) fn load_entry(&mut self, path: &Path) -> Option<Rc<RefCell<Module>>> {
+        let file = path.display().to_string();
+        let (bytes, canonical) = match fs::read(path).and_then(|b| {
+            let canonical = fs::canonicalize(path)?;
+            Ok((b, canonical))
+        }) {
+            Ok(r) => r,
+            Err(err) => {
+                self.reports
+                    .push((None, Report::error(format!("{file}: {err}"))));
+                return None;
+            }
+        };
+        let dir = path.parent().unwrap_or(Path::new("")).to_path_buf();
+        if path.extension().is_some_and(|e| e == object::EXTENSION) {
+            let object = self.read_object(&file, &bytes)?;
+            let name = object.name.clone();
+            self.root = Some((name.clone(), dir, object::EXTENSION));
+            return self.load_object(&name, file, canonical, object);
+        }
+        let source = match String::from_utf8(bytes) {
+            Ok(s) => s,
+            Err(err) => {
+                self.reports
+                    .push((None, Report::error(format!("{file}: {err}"))));
+                return None;
+            }
+        };
+        // If this fails, load_source will say why.
+        let decl = source
+            .parse::<Document>()
+            .ok()
+            .and_then(|doc| doc.mod_decl()?.ok());
+        let name = match decl {
+            Some(decl) => decl.name,
+            None => path
+                .file_stem()
+                .map_or("main".to_string(), |s| s.to_string_lossy().into_owned()),
+        };
+        self.root = Some((name.clone(), dir, "lia"));
+        self.load_source(&name, file, canonical, source)
+    }
+
+    // Loads module `name`, as requested at `from`, unless it's loaded already.
+    // Objects don't know where in the source they asked, so their requests
+    // have no range.
+    fn load(&mut self, name: &str, from: (&str, Option<Range>)) -> Option<Rc<RefCell<Module>>> {
+        let sname = self.vm.intern(name);
+        if let Some(module) = self.vm.lookup_module(sname) {
+            return Some(module);
+        }
+
+        let Some(path) = self.path_for(name) else {
+            self.complain(from, format!("can't find module {name}"), vec![]);
+            return None;
+        };
+        let file = path.display().to_string();
+        let Ok(canonical) = fs::canonicalize(&path) else {
+            self.complain(
+                from,
+                format!("can't find module {name}"),
+                vec![format!("looked for {file}")],
+            );
+            return None;
+        };
+        if let Some(i) = self.loading.iter().position(|(_, p)| *p == canonical) {
+            let chain = self.loading[i..]
+                .iter()
+                .map(|(n, _)| n.as_str())
+                .chain([name])
+                .collect::<Vec<_>>()
+                .join(" -> ");
+            let notes = vec![
+                format!("cycle: {chain}"),
+                format!("{file} is where {} came from", self.loading[i].0),
+            ];
+            self.complain(
+                from,
+                format!("loading module {name} would be circular"),
+                notes,
+            );
+            return None;
+        }
+        if self.root.as_ref().is_some_and(|r| r.2 == object::EXTENSION) {
+            let bytes = match fs::read(&path) {
+                Ok(b) => b,
+                Err(err) => {
+                    self.reports
+                        .push((None, Report::error(format!("{file}: {err}"))));
+                    return None;
+                }
+            };
+            let object = self.read_object(&file, &bytes)?;
+            return self.load_object(name, file, canonical, object);
+        }
+        let source = match fs::read_to_string(&path) {
+            Ok(s) => s,
+            Err(err) => {
+                self.reports
+                    .push((None, Report::error(format!("{file}: {err}"))));
+                return None;
+            }
+        };
+        self.load_source(name, file, canonical, source)
+    }
+
+    fn load_source(
+        &mut self,
+        name: &str,
+        file: String,
+        canonical: PathBuf,
+        source: String,
+    ) -> Option<Rc<RefCell<Module>>> {
+        self.sources.insert(file.clone(), source.clone());
+        let doc = match source.parse::<Document>() {
+            Ok(doc) => doc,
+            Err(err) => {
+                self.reports
+                    .push((Some(file), Report::parse(&err, &source)));
+                return None;
+            }
+        };
+        let decl = match doc.mod_decl() {
+            Some(Ok(decl)) if decl.name != name => {
+                let d = Diagnostic::new(
+                    Severity::Error,
+                    decl.range,
+                    format!("expected module {name}, but this is {}", decl.name),
+                );
+                self.reports.push((Some(file), Report::compile(&d)));
+                return None;
+            }
+            Some(Ok(decl)) => Some(decl),
+            // The compiler reports on it.
+            Some(Err(_)) | None => None,
+        };
+
+        self.loading.push((name.to_string(), canonical));
+        // Registers it with its parent, too.
+        let module = self.vm.named_module(name);
+        let (submods, exports) = decl.map_or((vec![], None), |d| (d.submods, d.exports));
+        if let Some(exports) = &exports {
+            module.borrow_mut().exports =
+                Some(exports.iter().map(|(e, _)| self.vm.intern(e)).collect());
+        }
+        let submods = submods
+            .into_iter()
+            .map(|(sub, range)| (format!("{name}.{sub}"), range))
+            .collect::<Vec<_>>();
+        let mut ok = true;
+        for (sub, range) in &submods {
+            if self.load(sub, (&file, Some(*range))).is_none() {
+                ok = false;
+            }
+        }
+
+        // Submodules are in place before the parent's own code is compiled
+        // (so their macros can be used) and run.
+        let mut c = Compiler::in_module(self.vm, module.clone());
+        c.doc(&doc);
+        let result = c.finish();
+        let warnings = c
+            .warnings()
+            .iter()
+            .map(|d| (Some(file.clone()), Report::compile(d)))
+            .collect::<Vec<_>>();
+        self.reports.extend(warnings);
+        // From anything spawned at compile time.
+        self.crashes();
+        match result {
+            Ok(mut code) if ok => {
+                code.file = Some(file.clone());
+                if let Some(objects) = &mut self.objects {
+                    let object = Object {
+                        name: name.to_string(),
+                        deps: deps(name, &submods, &code),
+                        exports: exports
+                            .as_ref()
+                            .map(|es| es.iter().map(|(e, _)| e.clone()).collect()),
+                        code,
+                    };
+                    let path = Path::new(&file).with_extension(object::EXTENSION);
+                    objects.push((path, object.write()));
+                    code = object.code;
+                }
+                let exports = exports
+                    .unwrap_or_default()
+                    .into_iter()
+                    .map(|(e, range)| (e, Some(range)))
+                    .collect::<Vec<_>>();
+                ok = if self.objects.is_some() {
+                    self.check_exports(name, &file, &module, &exports)
+                } else {
+                    self.run(name, &file, &module, code, &exports)
+                };
+            }
+            Ok(_) => {}
+            Err(err) => {
+                for d in &err.diagnostics {
+                    self.reports.push((Some(file.clone()), Report::compile(d)));
+                }
+                ok = false;
+            }
+        }
+        self.loading.pop();
+
+        ok.then_some(module)
+    }
+
+    fn read_object(&mut self, file: &str, bytes: &[u8]) -> Option<Object> {
+        match Object::read(bytes) {
+            Ok(object) => Some(object),
+            Err(err) => {
+                self.reports
+                    .push((None, Report::error(format!("{file}: {err}"))));
+                None
+            }
+        }
+    }
+
+    fn load_object(
+        &mut self,
+        name: &str,
+        file: String,
+        canonical: PathBuf,
+        object: Object,
+    ) -> Option<Rc<RefCell<Module>>> {
+        if object.name != name {
+            let message = format!(
+                "{file}: expected module {name}, but this is {}",
+                object.name
+            );
+            self.reports.push((None, Report::error(message)));
+            return None;
+        }
+
+        self.loading.push((name.to_string(), canonical));
+        let module = self.vm.named_module(name);
+        if let Some(exports) = &object.exports {
+            module.borrow_mut().exports = Some(exports.iter().map(|e| self.vm.intern(e)).collect());
+        }
+        let mut ok = true;
+        for dep in &object.deps {
+            if self.load(dep, (&file, None)).is_none() {
+                ok = false;
+            }
+        }
+        if ok {
+            let exports = object
+                .exports
+                .unwrap_or_default()
+                .into_iter()
+                .map(|e| (e, None))
+                .collect::<Vec<_>>();
+            ok = self.run(name, &file, &module, object.code, &exports);
+        }
+        self.loading.pop();
+
+        ok.then_some(module)
+    }
+
+    // Runs a module's code, then checks it defined everything it exports.
+    fn run(
+        &mut self,
+        name: &str,
+        file: &str,
+        module: &Rc<RefCell<Module>>,
+        code: Code,
+        exports: &[(String, Option<Range>)],
+    ) -> bool {
+        let result = self.vm.run_to_completion(module.clone(), code);
+        self.crashes();
+        if let Err(err) = result {
+            self.runtime(&err);
+            return false;
+        }
+        self.check_exports(name, file, module, exports)
+    }
+
+    // Whether the module defines everything it exports -- or, if it hasn't
+    // run, at least declares it.
+    fn check_exports(
+        &mut self,
+        name: &str,
+        file: &str,
+        module: &Rc<RefCell<Module>>,
+        exports: &[(String, Option<Range>)],
+    ) -> bool {
+        let mut ok = true;
+        for (e, range) in exports {
+            let s = self.vm.intern(e);
+            let defined = match &self.objects {
+                Some(_) => module.borrow().declares_public(s),
+                None => module.borrow().get(s).is_some(),
+            };
+            if !defined {
+                self.complain(
+                    (file, *range),
+                    format!("{name} exports {e}, but never defines it"),
+                    vec![],
+                );
+                ok = false;
+            }
+        }
+        ok
+    }
+
+    // Against the source if we have a range in it, else against the file as
+    // a whole.
+    fn complain(&mut self, from: (&str, Option<Range>), message: String, notes: Vec<String>) {
+        let (file, range) = from;
+        let report = match range {
+            Some(range) => {
+                let d = notes
+                    .into_iter()
+                    .fold(Diagnostic::new(Severity::Error, range, message), |d, n| {
+                        d.note(None, n)
+                    });
+                Report::compile(&d)
+            }
+            None => notes
+                .into_iter()
+                .fold(Report::error(format!("{file}: {message}")), |r, n| {
+                    r.note(n)
+                }),
+        };
+        self.reports.push((range.map(|_| file.to_string()), report));
+    }
+
+    // Reports err against the innermost frame whose source we have.
+    pub(crate) fn runtime(&mut self, err: &Error) {
+        let file = self.file_of(err);
+        let report = Report::runtime(err, file.as_deref());
+        self.reports.push((file, report));
+    }
+
+    // Reports on any process that crashed with no one waiting on it.
+    pub(crate) fn crashes(&mut self) {
+        for (pid, err) in self.vm.take_crashes() {
+            let file = self.file_of(&err);
+            let report = Report::crash(pid, &err, file.as_deref());
+            self.reports.push((file, report));
+        }
+    }
+
+    // The innermost file in err's backtrace that we have the source of.
+    fn file_of(&self, err: &Error) -> Option<String> {
+        err.trace
+            .iter()
+            .filter_map(|f| f.file.as_ref())
+            .find(|f| self.sources.contains_key(*f))
+            .cloned()
+    }
+
+    pub(crate) fn vm(&mut self) -> &mut Vm {
+        self.vm
+    }
+
+    // Renders and forgets everything reported so far.
+    pub(crate) fn render(&mut self, color: bool) -> String {
+        mem::take(&mut self.reports)
+            .iter()
+            .map(|(file, report)| {
+                let source = file
+                    .as_ref()
+                    .and_then(|f| self.sources.get(f))
+                    .map_or("", |s| s.as_str());
+                report.render(source, file.as_deref(), color)
+            })
+            .collect()
+    }
+
+    // kcx.util.x => <root>/util/x.lia, or .liac if that's what the entry was.
+    // None if it's not under the root at all.
+    fn path_for(&self, name: &str) -> Option<PathBuf> {
+        let (root, dir, ext) = self.root.as_ref().expect("should have loaded an entry");
+        let rest = name
+            .strip_prefix(root.as_str())
+            .and_then(|r| r.strip_prefix('.'))?;
+        Some(dir.join(rest.replace('.', "/")).with_extension(ext))
+    }
+}
+
+// What an object of code compiled in name needs loaded first: its
+// submodules, and any module its globals live in.
+fn deps(name: &str, submods: &[(String, Range)], code: &Code) -> Vec<String> {
+    let mut deps = submods.iter().map(|(s, _)| s.clone()).collect::<Vec<_>>();
+    for m in code.globals.iter().filter_map(|g| g.module.as_ref()) {
+        if m != name && !deps.contains(m) {
+            deps.push(m.clone());
+        }
+    }
+    deps
+}
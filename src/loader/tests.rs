@@ -0,0 +1,360 @@
+#![cfg(test)]
+
+use std::fs;
+use std::path::PathBuf;
+
+use super::Loader;
+use crate::vm::{ErrorKind, Val, Vm};
+
+// A fresh directory of .lia files to load from.
+fn project(name: &str, files: &[(&str, &str)]) -> PathBuf {
+    let dir = std::env::temp_dir().join(format!("alia-loader-{}-{name}", std::process::id()));
+    _ = fs::remove_dir_all(&dir);
+    for (path, source) in files {
+        let path = dir.join(path);
+        fs::create_dir_all(path.parent().unwrap()).unwrap();
+        fs::write(path, source).unwrap();
+    }
+    dir
+}
+
+#[test]
+fn loads_submodules() {
+    let dir = project(
+        "submods",
+        &[
+            (
+                "main.lia",
+                "(mod app submods: ['util])\n(defn main [] (app.util/f))",
+            ),
+            ("util.lia", "(mod app.util submods: [x])\n(defn f [] 1)"),
+            ("util/x.lia", "(mod app.util.x)\n(defn g [] 2)"),
+        ],
+    );
+    let mut vm = Vm::new();
+    let mut loader = Loader::new(&mut vm);
+    let module = loader.load_entry(&dir.join("main.lia"));
+    assert_eq!("", loader.render(false));
+    let module = module.expect("should load");
+    assert_eq!("app", module.borrow().name);
+
+    let util = vm.intern("util");
+    let x = vm.intern("x");
+    let util = module.borrow().submodules[&util].clone();
+    assert_eq!("app.util", util.borrow().name);
+    assert_eq!("app.util.x", util.borrow().submodules[&x].borrow().name);
+    for name in ["app", "app.util", "app.util.x"] {
+        let s = vm.intern(name);
+        assert!(vm.lookup_module(s).is_some(), "{name} should be registered");
+    }
+}
+
+#[test]
+fn namespace_only_modules_load() {
+    let dir = project(
+        "namespace",
+        &[
+            (
+                "main.lia",
+                "(mod app submods: ['util])\n(defn main [] (app.util.x/g))",
+            ),
+            ("util.lia", "(mod app.util submods: [x])"),
+            ("util/x.lia", "(mod app.util.x)\n(defn g [] 2)"),
+        ],
+    );
+    let mut vm = Vm::new();
+    let mut loader = Loader::new(&mut vm);
+    let module = loader.load_entry(&dir.join("main.lia"));
+    assert_eq!("", loader.render(false));
+    let module = module.expect("should load");
+    let main = vm.intern("main");
+    let Some(Val::Function(f)) = module.borrow().lookup(&vm, main) else {
+        panic!("main should be a function");
+    };
+    let Ok(result) = vm.call(&f, vec![]) else {
+        panic!("main should call app.util.x/g");
+    };
+    assert_eq!("2", result.format(&vm));
+
+    let dir = project("solo", &[("main.lia", "(mod solo)")]);
+    let mut vm = Vm::new();
+    let mut loader = Loader::new(&mut vm);
+    assert!(loader.load_entry(&dir.join("main.lia")).is_some());
+    assert_eq!("", loader.render(false));
+}
+
+#[test]
+fn relative_module_refs() {
+    let dir = project(
+        "relative",
+        &[
+            (
+                "main.lia",
+                "(mod app submods: ['util])\n(defn main [] [(.util/f) (.util.x/g) (.x/g)])",
+            ),
+            (
+                "util.lia",
+                "(mod app.util submods: ['x])\n(defn f [] (.x/g))",
+            ),
+            ("util/x.lia", "(mod app.util.x)\n(defn g [] 2)"),
+        ],
+    );
+    let mut vm = Vm::new();
+    let mut loader = Loader::new(&mut vm);
+    let module = loader
+        .load_entry(&dir.join("main.lia"))
+        .expect("should load");
+    let smain = vm.intern("main");
+    let Some(Val::Function(main)) = module.borrow().lookup(&vm, smain) else {
+        panic!("main should be a function");
+    };
+    let err = vm
+        .call(&main, vec![])
+        .err()
+        .expect("app has no submodule x");
+    assert_eq!(ErrorKind::UnboundSymbol, err.kind);
+    assert_eq!("unknown module .x", err.message);
+
+    let util = vm.intern("util");
+    let sf = vm.intern("f");
+    let util = module.borrow().submodules[&util].clone();
+    let Some(Val::Function(f)) = util.borrow().lookup(&vm, sf) else {
+        panic!("f should be a function");
+    };
+    let Ok(result) = vm.call(&f, vec![]) else {
+        panic!("f should call .x/g");
+    };
+    assert_eq!("2", result.format(&vm));
+}
+
+#[test]
+fn missing_and_mismatched() {
+    let dir = project(
+        "missing",
+        &[
+            ("main.lia", "(mod app submods: ['util 'nope])"),
+            ("util.lia", "(mod app.utils)"),
+        ],
+    );
+    let mut vm = Vm::new();
+    let mut loader = Loader::new(&mut vm);
+    assert!(loader.load_entry(&dir.join("main.lia")).is_none());
+    let rendered = loader.render(false);
+    assert!(
+        rendered.contains("error: expected module app.util, but this is app.utils"),
+        "{rendered}"
+    );
+    assert!(
+        rendered.contains("error: can't find module app.nope"),
+        "{rendered}"
+    );
+}
+
+#[cfg(unix)]
+#[test]
+fn cycles_are_reported() {
+    let dir = project("cycle", &[("main.lia", "(mod app submods: ['again])")]);
+    std::os::unix::fs::symlink(dir.join("main.lia"), dir.join("again.lia")).unwrap();
+    let mut vm = Vm::new();
+    let mut loader = Loader::new(&mut vm);
+    assert!(loader.load_entry(&dir.join("main.lia")).is_none());
+    let rendered = loader.render(false);
+    assert!(
+        rendered.contains("error: loading module app.again would be circular"),
+        "{rendered}"
+    );
+    assert!(rendered.contains("cycle: app -> app.again"), "{rendered}");
+}
+
+#[test]
+fn exports() {
+    let dir = project(
+        "exports",
+        &[
+            (
+                "main.lia",
+                "(mod app submods: ['util])\n(refer app.util)\n(defn main [] (f))",
+            ),
+            (
+                "util.lia",
+                "(mod app.util exports: [f])\n(defn f [] (g))\n(defn g [] 2)",
+            ),
+        ],
+    );
+    let mut vm = Vm::new();
+    let mut loader = Loader::new(&mut vm);
+    let module = loader
+        .load_entry(&dir.join("main.lia"))
+        .expect("should load");
+    let smain = vm.intern("main");
+    let sg = vm.intern("g");
+    assert!(module.borrow().lookup(&vm, sg).is_none(), "g is private");
+    let Some(Val::Function(main)) = module.borrow().lookup(&vm, smain) else {
+        panic!("main should be a function");
+    };
+    let Ok(result) = vm.call(&main, vec![]) else {
+        panic!("main should call the referred f");
+    };
+    assert_eq!("2", result.format(&vm));
+
+    let dir = project(
+        "exports-missing",
+        &[(
+            "main.lia",
+            "(mod app exports: [main nope])\n(defn main [] 1)",
+        )],
+    );
+    let mut vm = Vm::new();
+    let mut loader = Loader::new(&mut vm);
+    assert!(loader.load_entry(&dir.join("main.lia")).is_none());
+    let rendered = loader.render(false);
+    assert!(
+        rendered.contains("error: app exports nope, but never defines it"),
+        "{rendered}"
+    );
+}
+
+#[test]
+fn macros_from_submodules() {
+    let dir = project(
+        "macros",
+        &[
+            (
+                "main.lia",
+                "(mod app submods: ['util])\n(refer app.util)\n(defn main [] [(twice 1) (.util/twice 2)])",
+            ),
+            (
+                "util.lia",
+                "(mod app.util)\n(defmacro twice [x] (list 'builtins/list x x))",
+            ),
+        ],
+    );
+    let mut vm = Vm::new();
+    let mut loader = Loader::new(&mut vm);
+    let module = loader.load_entry(&dir.join("main.lia"));
+    assert_eq!("", loader.render(false));
+    let module = module.expect("should load");
+    let smain = vm.intern("main");
+    let Some(Val::Function(main)) = module.borrow().lookup(&vm, smain) else {
+        panic!("main should be a function");
+    };
+    let result = vm.call(&main, vec![]).unwrap_or_else(|e| panic!("{e}"));
+    assert_eq!("[(1 1) (2 2)]", result.format(&vm));
+}
+
+#[test]
+fn crashes_are_reported() {
+    let dir = project(
+        "crashes",
+        &[(
+            "main.lia",
+            "(mod app)\n(spawn (fn [] (nope)))\n(receive _ () (after 10 ()))",
+        )],
+    );
+    let mut vm = Vm::new();
+    let mut loader = Loader::new(&mut vm);
+    // Someone else crashing doesn't stop the load.
+    assert!(loader.load_entry(&dir.join("main.lia")).is_some());
+    let rendered = loader.render(false);
+    assert!(
+        rendered.starts_with("error: <pid 2> crashed: unbound symbol"),
+        "{rendered}"
+    );
+    assert!(rendered.contains("main.lia:2:16"), "{rendered}");
+}
+
+#[test]
+fn compiling_runs_nothing() {
+    let dir = project(
+        "sideeffects",
+        &[
+            (
+                "main.lia",
+                "(mod app submods: ['util])\n(refer app.util only: [f])\n(set ran true)\n(defn main [] [(f) (app.util/f) ran])",
+            ),
+            (
+                "util.lia",
+                "(mod app.util exports: [f])\n(defn f [] 2)\n(set ran true)",
+            ),
+        ],
+    );
+    let mut vm = Vm::new();
+    let mut loader = Loader::new(&mut vm);
+    loader.compile_only();
+    let module = loader.load_entry(&dir.join("main.lia"));
+    assert_eq!("", loader.render(false));
+    let module = module.expect("should compile");
+    let objects = loader.take_objects();
+    assert_eq!(2, objects.len());
+    let (sran, smain, sutil) = (vm.intern("ran"), vm.intern("main"), vm.intern("app.util"));
+    assert!(module.borrow().get(sran).is_none());
+    assert!(module.borrow().get(smain).is_none());
+    let util = vm.lookup_module(sutil).unwrap();
+    assert!(util.borrow().get(sran).is_none());
+    for (path, bytes) in objects {
+        fs::write(path, bytes).unwrap();
+    }
+
+    // Running it is another matter.
+    let mut vm = Vm::new();
+    let mut loader = Loader::new(&mut vm);
+    let module = loader.load_entry(&dir.join("main.liac"));
+    assert_eq!("", loader.render(false));
+    let module = module.expect("should load");
+    let smain = vm.intern("main");
+    let Some(Val::Function(main)) = module.borrow().lookup(&vm, smain) else {
+        panic!("main should be a function");
+    };
+    let result = vm.call(&main, vec![]).unwrap_or_else(|e| panic!("{e}"));
+    assert_eq!("[2 2 true]", result.format(&vm));
+}
+
+#[test]
+fn compiled_projects_load() {
+    let dir = project(
+        "compiled",
+        &[
+            (
+                "main.lia",
+                "(mod app submods: ['util])\n(refer app.util)\n(defn main [] [(twice 1) (f)])",
+            ),
+            (
+                "util.lia",
+                "(mod app.util exports: [f twice])\n(defmacro twice [x] (list 'builtins/list x x))\n(defn f [] 2)",
+            ),
+        ],
+    );
+    let mut vm = Vm::new();
+    let mut loader = Loader::new(&mut vm);
+    loader.compile_only();
+    assert!(loader.load_entry(&dir.join("main.lia")).is_some());
+    assert_eq!("", loader.render(false));
+    for (path, bytes) in loader.take_objects() {
+        fs::write(path, bytes).unwrap();
+    }
+    // Nothing should need the source now.
+    fs::remove_file(dir.join("main.lia")).unwrap();
+    fs::remove_file(dir.join("util.lia")).unwrap();
+
+    let mut vm = Vm::new();
+    let mut loader = Loader::new(&mut vm);
+    let module = loader.load_entry(&dir.join("main.liac"));
+    assert_eq!("", loader.render(false));
+    let module = module.expect("should load");
+    let smain = vm.intern("main");
+    let Some(Val::Function(main)) = module.borrow().lookup(&vm, smain) else {
+        panic!("main should be a function");
+    };
+    let result = vm.call(&main, vec![]).unwrap_or_else(|e| panic!("{e}"));
+    assert_eq!("[(1 1) 2]", result.format(&vm));
+
+    fs::write(dir.join("util.liac"), b"alia\x01").unwrap();
+    let mut vm = Vm::new();
+    let mut loader = Loader::new(&mut vm);
+    assert!(loader.load_entry(&dir.join("main.liac")).is_none());
+    let rendered = loader.render(false);
+    assert!(
+        rendered.contains("util.liac: bad number at byte 5"),
+        "{rendered}"
+    );
+}
@@ -1,10 +1,15 @@
-use lsp_server::ResponseError;
+use lsp_server::{Message, Notification, ResponseError};
+use lsp_types::notification::{Notification as _, ShowMessage};
 use lsp_types::{
     CodeActionParams, CodeActionProviderCapability, CodeActionResponse, Command,
-    ExecuteCommandOptions, ExecuteCommandParams,
+    ExecuteCommandOptions, ExecuteCommandParams, MessageType, ShowMessageParams, Url,
 };
 
 use super::LspState;
+use crate::compiler::Compiler;
+use crate::disasm::disassemble;
+use crate::parser::{Document, Loc, Node};
+use crate::vm::Vm;
 
 const COMMAND_START_VM: &str = "startVm";
 const COMMAND_START_VM_FRIENDLY: &str = "Start alia VM";
@@ -15,6 +20,9 @@ const COMMAND_STOP_VM_FRIENDLY: &str = "Stop alia VM";
 const COMMAND_EXEC_TOPLEVEL: &str = "execToplevel";
 const COMMAND_EXEC_TOPLEVEL_FRIENDLY: &str = "Execute top-level form under cursor in running VM";
 
+const COMMAND_DISASM_TOPLEVEL: &str = "disasmToplevel";
+const COMMAND_DISASM_TOPLEVEL_FRIENDLY: &str = "Disassemble top-level form under cursor";
+
 pub(super) fn code_action_provider() -> Option<CodeActionProviderCapability> {
     Some(true.into())
 }
@@ -25,6 +33,7 @@ pub(super) fn execute_command_provider() -> Option<ExecuteCommandOptions> {
             COMMAND_START_VM.to_string(),
             COMMAND_STOP_VM.to_string(),
             COMMAND_EXEC_TOPLEVEL.to_string(),
+            COMMAND_DISASM_TOPLEVEL.to_string(),
         ],
         ..Default::default()
     })
@@ -35,8 +44,20 @@ pub(super) fn list(
     ls: &mut LspState,
 ) -> Result<Option<CodeActionResponse>, ResponseError> {
     let mut result: CodeActionResponse = vec![];
+    let here = Some(vec![
+        serde_json::to_value(params.text_document.uri).unwrap(),
+        serde_json::to_value(params.range).unwrap(),
+    ]);
 
-    if !ls.vm_running {
+    result.push(
+        Command::new(
+            COMMAND_DISASM_TOPLEVEL_FRIENDLY.to_string(),
+            COMMAND_DISASM_TOPLEVEL.to_string(),
+            here.clone(),
+        )
+        .into(),
+    );
+    if ls.vm.is_none() {
         result.push(
             Command::new(
                 COMMAND_START_VM_FRIENDLY.to_string(),
@@ -50,7 +71,7 @@ pub(super) fn list(
             Command::new(
                 COMMAND_EXEC_TOPLEVEL_FRIENDLY.to_string(),
                 COMMAND_EXEC_TOPLEVEL.to_string(),
-                Some(vec![serde_json::to_value(params.range).unwrap()]),
+                here,
             )
             .into(),
         );
@@ -71,17 +92,94 @@ pub(super) fn execute(
     ls: &mut LspState,
 ) -> Result<bool, ResponseError> {
     if params.command == COMMAND_START_VM {
-        assert!(!ls.vm_running);
-        ls.vm_running = true;
+        assert!(ls.vm.is_none());
+        let mut vm = Vm::new();
+        let module = vm.anonymous_module("*lsp*");
+        ls.vm = Some((vm, module));
     } else if params.command == COMMAND_STOP_VM {
-        assert!(ls.vm_running);
-        ls.vm_running = false;
-    } else if params.command == COMMAND_EXEC_TOPLEVEL {
-        assert!(ls.vm_running);
-        assert!(params.arguments.len() == 1);
-        let _range: lsp_types::Range = serde_json::from_value(params.arguments[0].clone()).unwrap();
+        assert!(ls.vm.is_some());
+        ls.vm = None;
+    } else if params.command == COMMAND_EXEC_TOPLEVEL || params.command == COMMAND_DISASM_TOPLEVEL {
+        assert!(params.arguments.len() == 2);
+        let uri: Url = serde_json::from_value(params.arguments[0].clone()).unwrap();
+        let range: lsp_types::Range = serde_json::from_value(params.arguments[1].clone()).unwrap();
+        let result = if params.command == COMMAND_EXEC_TOPLEVEL {
+            assert!(ls.vm.is_some());
+            exec_toplevel(ls, &uri, range.start.into())
+        } else {
+            disasm_toplevel(ls, &uri, range.start.into())
+        };
+        let (typ, message) = match result {
+            Ok(message) => (MessageType::INFO, message),
+            Err(message) => (MessageType::ERROR, message),
+        };
+        ls.connection
+            .sender
+            .send(Message::Notification(Notification::new(
+                ShowMessage::METHOD.to_string(),
+                ShowMessageParams { typ, message },
+            )))
+            .unwrap();
     } else {
         panic!("unknown command {:?}", params.command);
     }
     Ok(true)
 }
+
+// The document's source, and the top-level form under loc in it.
+fn toplevel(ls: &LspState, uri: &Url, loc: Loc) -> Result<(String, Node), String> {
+    let content = ls
+        .documents
+        .get_document_content(uri, None)
+        .ok_or("document isn't open")?;
+    let doc = content
+        .parse::<Document>()
+        .map_err(|err| format!("error: {err}"))?;
+    let toplevel = doc
+        .toplevels
+        .into_iter()
+        .find(|n| loc >= n.range.0 && loc < n.range.1)
+        .ok_or("no top-level form under cursor")?;
+    Ok((content.to_string(), toplevel))
+}
+
+fn exec_toplevel(ls: &mut LspState, uri: &Url, loc: Loc) -> Result<String, String> {
+    let (_, toplevel) = toplevel(ls, uri, loc)?;
+    let (vm, module) = ls.vm.as_mut().expect("vm should be running");
+    let mut compiler = Compiler::in_module(vm, module.clone());
+    compiler.toplevel(&toplevel);
+    let code = compiler.finish().map_err(|err| format!("error: {err}"))?;
+
+    let result = vm.run_to_completion(module.clone(), code);
+    let crashes = vm
+        .take_crashes()
+        .into_iter()
+        .map(|(pid, err)| format!("\n{pid} crashed: {err}"))
+        .collect::<String>();
+    match result {
+        Ok(val) => Ok(format!("{}{crashes}", val.format(vm))),
+        Err(err) => Err(format!("error: {err}{crashes}")),
+    }
+}
+
+// Compiled as exec_toplevel would compile it, in the running VM, so globals
+// come out as the slots they'd use (and a defmacro or refer takes effect
+// there, as compiling one does).  With no VM running there's nothing to
+// resolve them against: they're shown looked up by name, as Eval, and VM
+// macros aren't expanded.
+fn disasm_toplevel(ls: &mut LspState, uri: &Url, loc: Loc) -> Result<String, String> {
+    let (content, toplevel) = toplevel(ls, uri, loc)?;
+    let (mut compiler, caveat) = match ls.vm.as_mut() {
+        Some((vm, module)) => (Compiler::in_module(vm, module.clone()), ""),
+        None => (
+            Compiler::new(),
+            "; no VM running: globals are shown unresolved\n",
+        ),
+    };
+    compiler.toplevel(&toplevel);
+    let code = compiler.finish().map_err(|err| format!("error: {err}"))?;
+    Ok(format!(
+        "{caveat}{}",
+        disassemble(&code).render(Some(&content))
+    ))
+}
@@ -0,0 +1,75 @@
+use lsp_server::{Message, Notification};
+use lsp_types::notification::{Notification as _, PublishDiagnostics};
+use lsp_types::{
+    Diagnostic, DiagnosticRelatedInformation, DiagnosticSeverity, Location,
+    PublishDiagnosticsParams, Url,
+};
+
+use crate::compiler::{self, Compiler, Severity};
+use crate::parser::Document;
+
+use super::LspState;
+
+// Parses and compiles the whole document, publishing whatever comes out.
+pub(super) fn publish(ls: &mut LspState, uri: &Url) {
+    let Some(content) = ls.documents.get_document_content(uri, None) else {
+        return;
+    };
+    let diagnostics = match content.parse::<Document>() {
+        Ok(doc) => {
+            let mut c = Compiler::new();
+            c.doc(&doc);
+            match c.finish() {
+                Ok(_) => c.warnings().iter().map(|d| convert(uri, d)).collect(),
+                Err(err) => err.diagnostics.iter().map(|d| convert(uri, d)).collect(),
+            }
+        }
+        Err(err) => vec![Diagnostic {
+            range: err.range.into(),
+            severity: Some(DiagnosticSeverity::ERROR),
+            source: Some("alia".to_string()),
+            message: err.kind.to_string(),
+            ..Default::default()
+        }],
+    };
+
+    ls.connection
+        .sender
+        .send(Message::Notification(Notification::new(
+            PublishDiagnostics::METHOD.to_string(),
+            PublishDiagnosticsParams {
+                uri: uri.clone(),
+                diagnostics,
+                version: None,
+            },
+        )))
+        .unwrap();
+}
+
+fn convert(uri: &Url, d: &compiler::Diagnostic) -> Diagnostic {
+    let mut message = d.message.clone();
+    let mut related = vec![];
+    for note in &d.notes {
+        match note.range {
+            Some(range) => related.push(DiagnosticRelatedInformation {
+                location: Location::new(uri.clone(), range.into()),
+                message: note.message.clone(),
+            }),
+            None => {
+                message.push_str("\nnote: ");
+                message.push_str(&note.message);
+            }
+        }
+    }
+    Diagnostic {
+        range: d.range.into(),
+        severity: Some(match d.severity {
+            Severity::Error => DiagnosticSeverity::ERROR,
+            Severity::Warning => DiagnosticSeverity::WARNING,
+        }),
+        source: Some("alia".to_string()),
+        message,
+        related_information: (!related.is_empty()).then_some(related),
+        ..Default::default()
+    }
+}
@@ -1,9 +1,13 @@
+use std::cell::RefCell;
 use std::fmt::Write;
+use std::rc::Rc;
 
 use lsp_server::ResponseError;
 use lsp_types::{Hover, HoverContents, HoverParams, MarkupContent, MarkupKind, Position};
 
-use crate::parser::Document;
+use crate::compiler::to_val;
+use crate::parser::{Document, Node, NodeValue};
+use crate::vm::{Error, Module, Val, Vm};
 
 use super::LspState;
 
@@ -36,6 +40,24 @@ pub(super) fn handle(
     )
     .unwrap();
 
+    // With a VM running, show what a macro call under point turns into.
+    if let Some((vm, module)) = ls.vm.as_mut() {
+        let call = doc
+            .nodes_at((line as usize, character as usize))
+            .into_iter()
+            .find(|n| matches!(n.value, NodeValue::List(_)));
+        match call.map(|n| expand(vm, module, n)) {
+            Some(Ok(Some(form))) => {
+                writeln!(value, "## expansion").unwrap();
+                writeln!(value, "```lisp").unwrap();
+                writeln!(value, "{}", form.format(vm)).unwrap();
+                writeln!(value, "```").unwrap();
+            }
+            Some(Err(err)) => writeln!(value, "## expansion failed\n{err}").unwrap(),
+            Some(Ok(None)) | None => {}
+        }
+    }
+
     let mut first = true;
     for node in nodes {
         if first {
@@ -55,3 +77,14 @@ pub(super) fn handle(
         range: Some(closest.range.into()),
     }))
 }
+
+// Like (macroexpand 'n), but None if n isn't a macro call at all.
+fn expand(vm: &mut Vm, module: &Rc<RefCell<Module>>, n: &Node) -> Result<Option<Val>, Error> {
+    let mut form = to_val(vm, n);
+    let mut expanded = false;
+    while let Some(v) = vm.macroexpand_1(module, &form)? {
+        form = v;
+        expanded = true;
+    }
+    Ok(expanded.then_some(form))
+}
@@ -1,16 +1,23 @@
+use std::cell::RefCell;
 use std::error::Error;
+use std::rc::Rc;
 
 use lsp_server::{Connection, ExtractError, Message, Request, RequestId, Response, ResponseError};
 use lsp_textdocument::TextDocuments;
+use lsp_types::notification::{DidChangeTextDocument, DidOpenTextDocument, Notification as _};
 use lsp_types::request::{CodeActionRequest, ExecuteCommand, GotoDefinition, HoverRequest};
 use lsp_types::{
     InitializeParams, OneOf, ServerCapabilities, TextDocumentSyncCapability, TextDocumentSyncKind,
+    Url,
 };
 
 mod action;
+mod diagnostics;
 mod goto;
 mod hover;
 
+use crate::vm::{Module, Vm};
+
 pub(crate) fn main(args: Vec<String>) -> Result<(), Box<dyn Error + Send + Sync>> {
     eprintln!("alia lsp server starting");
 
@@ -75,7 +82,7 @@ macro_rules! lsp_handler {
 struct LspState<'c, 'd> {
     connection: &'c Connection,
     documents: &'d mut TextDocuments,
-    vm_running: bool,
+    vm: Option<(Vm, Rc<RefCell<Module>>)>,
 }
 
 impl<'c, 'd> LspState<'c, 'd> {
@@ -83,7 +90,7 @@ impl<'c, 'd> LspState<'c, 'd> {
         LspState {
             connection,
             documents,
-            vm_running: false,
+            vm: None,
         }
     }
 }
@@ -122,6 +129,15 @@ fn main_loop(
             Message::Notification(not) => {
                 if !ls.documents.listen(not.method.as_str(), &not.params) {
                     eprintln!("got notification: {not:?}");
+                    continue;
+                }
+                if let (DidOpenTextDocument::METHOD | DidChangeTextDocument::METHOD, Some(uri)) = (
+                    not.method.as_str(),
+                    not.params["textDocument"]["uri"]
+                        .as_str()
+                        .and_then(|u| Url::parse(u).ok()),
+                ) {
+                    diagnostics::publish(&mut ls, &uri);
                 }
             }
         }
@@ -1,25 +1,41 @@
+mod asm;
+mod cli;
 mod compiler;
 mod disasm;
+mod loader;
 #[cfg(feature = "lsp")]
 mod lsp;
+mod object;
 mod parser;
 #[cfg(feature = "repl")]
 mod repl;
+mod report;
 mod vm;
 
 use std::error::Error;
+use std::process::ExitCode;
 
-fn main() -> Result<(), Box<dyn Error + Send + Sync>> {
+fn main() -> Result<ExitCode, Box<dyn Error + Send + Sync>> {
     let mut args_it = std::env::args().skip(1);
     if let Some(arg) = args_it.next() {
         if arg == "lsp" {
             #[cfg(feature = "lsp")]
-            return lsp::main(args_it.collect());
+            return lsp::main(args_it.collect()).map(|()| ExitCode::SUCCESS);
             #[cfg(not(feature = "lsp"))]
             return Err("lsp feature not built".into());
+        } else if arg == "run" {
+            return cli::run(args_it.collect());
+        } else if arg == "asm" {
+            return cli::asm(args_it.collect()).map(|()| ExitCode::SUCCESS);
+        } else if arg == "disasm" {
+            return cli::disasm(args_it.collect()).map(|()| ExitCode::SUCCESS);
+        } else if arg == "compile" {
+            return cli::compile(args_it.collect()).map(|()| ExitCode::SUCCESS);
+        } else if arg == "check" {
+            return cli::check(args_it.collect()).map(|()| ExitCode::SUCCESS);
         } else if arg == "repl" {
             #[cfg(feature = "repl")]
-            return repl::main(args_it.collect());
+            return repl::main(args_it.collect()).map(|()| ExitCode::SUCCESS);
             #[cfg(not(feature = "repl"))]
             return Err("repl feature not built".into());
         } else {
@@ -27,5 +43,5 @@ fn main() -> Result<(), Box<dyn Error + Send + Sync>> {
         }
     }
 
-    Err("no usage yet, try alia repl".into())
+    Err("usage: alia run <file> [args...] | alia check <file>... | alia compile <file> | alia asm <file> | alia disasm <file> | alia repl | alia lsp".into())
 }
@@ -0,0 +1,347 @@
+mod tests;
+
+use num_derive::FromPrimitive;
+use num_traits::FromPrimitive;
+use std::fmt::{Debug, Display};
+use std::str;
+
+use crate::parser::{Loc, Range};
+use crate::vm::{varint, Code, Const, Global};
+
+// A compiled module, as written by alia compile (to name.liac, alongside
+// name.lia) and loaded by the loader in place of the source.
+//
+// The format, all numbers varints unless noted:
+//
+//   magic      b"alia"
+//   version    FORMAT_VERSION; anything else is refused
+//   name       string: kcx.util
+//   file       option<string>: the source it was compiled from
+//   deps       count, string...: modules to load before this one
+//   exports    option<(count, string...)>
+//   consts     count, const...
+//   globals    count, (option<string> module, string name)...
+//   code       count, byte...
+//   source map count, (offset, line, col, line, col)...
+//
+// A string is a length and that many bytes of utf-8; an option is 0 for
+// None, or 1 and the value.  Consts are tagged; see Tag.  No name has a / in
+// it, and module names are dotted paths with no empty parts.
+pub(crate) struct Object {
+    pub(crate) name: String,
+    pub(crate) deps: Vec<String>,
+    pub(crate) exports: Option<Vec<String>>,
+    pub(crate) code: Code,
+}
+
+pub(crate) const MAGIC: &[u8] = b"alia";
+pub(crate) const FORMAT_VERSION: u64 = 1;
+pub(crate) const EXTENSION: &str = "liac";
+
+// Quoted data nests; don't let a bad file blow the stack.
+const MAX_DEPTH: usize = 256;
+
+#[derive(FromPrimitive)]
+#[repr(u8)]
+enum Tag {
+    Symbol = 0,
+    QualifiedSymbol = 1,
+    Integer = 2,
+    Float = 3,
+    String = 4,
+    List = 5,
+    Vec = 6,
+}
+
+pub(crate) struct Error {
+    // Into the file.
+    pub(crate) offset: usize,
+    pub(crate) message: String,
+}
+
+impl Display for Error {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{} at byte {}", self.message, self.offset)
+    }
+}
+
+impl Debug for Error {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        Display::fmt(self, f)
+    }
+}
+
+impl std::error::Error for Error {}
+
+impl Object {
+    pub(crate) fn write(&self) -> Vec<u8> {
+        let mut w = Writer {
+            out: MAGIC.to_vec(),
+        };
+        let code = &self.code;
+        w.n(FORMAT_VERSION as usize);
+        w.string(&self.name);
+        w.option(code.file.as_deref(), Writer::string);
+        w.seq(&self.deps, |w, d| w.string(d));
+        w.option(self.exports.as_deref(), |w, es| {
+            w.seq(es, |w, e| w.string(e))
+        });
+        w.seq(&code.consts, Writer::constant);
+        w.seq(&code.globals, |w, g| {
+            w.option(g.module.as_deref(), Writer::string);
+            w.string(&g.name);
+        });
+        w.n(code.bytes.len());
+        w.out.extend_from_slice(&code.bytes);
+        w.seq(&code.source_map, |w, &(offset, Range(start, end))| {
+            for n in [offset, start.0, start.1, end.0, end.1] {
+                w.n(n);
+            }
+        });
+        w.out
+    }
+
+    // Everything is checked but the bytecode itself.
+    pub(crate) fn read(bytes: &[u8]) -> Result<Object, Error> {
+        let mut r = Reader { bytes, at: 0 };
+        if !bytes.starts_with(MAGIC) {
+            return Err(r.error("not a compiled alia module"));
+        }
+        r.at = MAGIC.len();
+        let version = r.n()?;
+        if version as u64 != FORMAT_VERSION {
+            return Err(r.error(format!(
+                "unsupported format version {version}; expected {FORMAT_VERSION}"
+            )));
+        }
+        let name = r.module()?;
+        let file = r.option(Reader::string)?;
+        let deps = r.seq(Reader::module)?;
+        let exports = r.option(|r| r.seq(Reader::name))?;
+        let consts = r.seq(|r| r.constant(0))?;
+        let globals = r.seq(|r| {
+            Ok(Global {
+                module: r.option(Reader::module)?,
+                name: r.name()?,
+            })
+        })?;
+        let len = r.n()?;
+        let code = r.take(len)?.to_vec();
+        let source_map = r.seq(|r| {
+            let offset = r.n()?;
+            let start = Loc(r.n()?, r.n()?);
+            let end = Loc(r.n()?, r.n()?);
+            Ok((offset, Range(start, end)))
+        })?;
+        if !source_map.windows(2).all(|w| w[0].0 <= w[1].0)
+            || source_map.last().is_some_and(|&(o, _)| o > code.len())
+        {
+            return Err(r.error("source map out of order or out of range"));
+        }
+        if r.at != bytes.len() {
+            return Err(r.error("trailing data"));
+        }
+
+        let mut code = Code::new(code);
+        code.file = file;
+        code.consts = consts;
+        code.globals = globals;
+        code.source_map = source_map;
+        Ok(Object {
+            name,
+            deps,
+            exports,
+            code,
+        })
+    }
+}
+
+struct Writer {
+    out: Vec<u8>,
+}
+
+impl Writer {
+    fn n(&mut self, n: usize) {
+        varint::put(&mut self.out, n as u64);
+    }
+
+    fn string(&mut self, s: &str) {
+        self.n(s.len());
+        self.out.extend_from_slice(s.as_bytes());
+    }
+
+    fn option<T: ?Sized, F: FnOnce(&mut Self, &T)>(&mut self, v: Option<&T>, f: F) {
+        match v {
+            None => self.n(0),
+            Some(v) => {
+                self.n(1);
+                f(self, v);
+            }
+        }
+    }
+
+    fn seq<T, F: FnMut(&mut Self, &T)>(&mut self, vs: &[T], mut f: F) {
+        self.n(vs.len());
+        for v in vs {
+            f(self, v);
+        }
+    }
+
+    fn constant(&mut self, c: &Const) {
+        match c {
+            Const::Symbol(None, s) => {
+                self.out.push(Tag::Symbol as u8);
+                self.string(s);
+            }
+            Const::Symbol(Some(m), s) => {
+                self.out.push(Tag::QualifiedSymbol as u8);
+                self.string(m);
+                self.string(s);
+            }
+            Const::Integer(i) => {
+                self.out.push(Tag::Integer as u8);
+                varint::put_signed(&mut self.out, *i);
+            }
+            Const::Float(f) => {
+                // 8 bytes, little-endian.
+                self.out.push(Tag::Float as u8);
+                self.out.extend_from_slice(&f.to_le_bytes());
+            }
+            Const::String(s) => {
+                self.out.push(Tag::String as u8);
+                self.string(s);
+            }
+            Const::List(cs) => {
+                self.out.push(Tag::List as u8);
+                self.seq(cs, Self::constant);
+            }
+            Const::Vec(cs) => {
+                self.out.push(Tag::Vec as u8);
+                self.seq(cs, Self::constant);
+            }
+        }
+    }
+}
+
+struct Reader<'b> {
+    bytes: &'b [u8],
+    at: usize,
+}
+
+impl<'b> Reader<'b> {
+    fn error<S: Into<String>>(&self, message: S) -> Error {
+        Error {
+            offset: self.at,
+            message: message.into(),
+        }
+    }
+
+    fn n(&mut self) -> Result<usize, Error> {
+        let at = self.at;
+        varint::get(self.bytes, &mut self.at)
+            .and_then(|n| usize::try_from(n).ok())
+            .ok_or_else(|| Error {
+                offset: at,
+                message: "bad number".to_string(),
+            })
+    }
+
+    fn take(&mut self, n: usize) -> Result<&'b [u8], Error> {
+        let bytes = self
+            .at
+            .checked_add(n)
+            .and_then(|end| self.bytes.get(self.at..end))
+            .ok_or_else(|| self.error("unexpected end of file"))?;
+        self.at += n;
+        Ok(bytes)
+    }
+
+    fn string(&mut self) -> Result<String, Error> {
+        let n = self.n()?;
+        let at = self.at;
+        let bytes = self.take(n)?;
+        str::from_utf8(bytes)
+            .map(str::to_string)
+            .map_err(|_| Error {
+                offset: at,
+                message: "string isn't utf-8".to_string(),
+            })
+    }
+
+    // A symbol's name, or its module's as written: anything but a /, which
+    // only ever separates the two.
+    fn name(&mut self) -> Result<String, Error> {
+        let at = self.at;
+        let s = self.string()?;
+        if s.contains('/') {
+            return Err(Error {
+                offset: at,
+                message: format!("bad name {s:?}"),
+            });
+        }
+        Ok(s)
+    }
+
+    // kcx.util: names, none of them empty, joined by dots.
+    fn module(&mut self) -> Result<String, Error> {
+        let at = self.at;
+        let s = self.string()?;
+        if s.split('.').any(|seg| seg.is_empty() || seg.contains('/')) {
+            return Err(Error {
+                offset: at,
+                message: format!("bad module name {s:?}"),
+            });
+        }
+        Ok(s)
+    }
+
+    fn option<T, F: FnOnce(&mut Self) -> Result<T, Error>>(
+        &mut self,
+        f: F,
+    ) -> Result<Option<T>, Error> {
+        match self.n()? {
+            0 => Ok(None),
+            1 => f(self).map(Some),
+            _ => Err(self.error("bad option")),
+        }
+    }
+
+    fn seq<T, F: FnMut(&mut Self) -> Result<T, Error>>(
+        &mut self,
+        mut f: F,
+    ) -> Result<Vec<T>, Error> {
+        let n = self.n()?;
+        // Every element takes at least a byte; don't trust n any further.
+        let mut vs = Vec::with_capacity(n.min(self.bytes.len() - self.at));
+        for _ in 0..n {
+            vs.push(f(self)?);
+        }
+        Ok(vs)
+    }
+
+    fn constant(&mut self, depth: usize) -> Result<Const, Error> {
+        if depth == MAX_DEPTH {
+            return Err(self.error("constant nested too deeply"));
+        }
+        let at = self.at;
+        let tag = *self.take(1)?.first().unwrap();
+        let Some(tag) = Tag::from_u8(tag) else {
+            return Err(Error {
+                offset: at,
+                message: format!("bad constant tag {tag}"),
+            });
+        };
+        Ok(match tag {
+            Tag::Symbol => Const::Symbol(None, self.name()?),
+            Tag::QualifiedSymbol => Const::Symbol(Some(self.name()?), self.name()?),
+            Tag::Integer => Const::Integer(
+                varint::get_signed(self.bytes, &mut self.at)
+                    .ok_or_else(|| self.error("bad integer"))?,
+            ),
+            Tag::Float => Const::Float(f64::from_le_bytes(self.take(8)?.try_into().unwrap())),
+            Tag::String => Const::String(self.string()?),
+            Tag::List => Const::List(self.seq(|r| r.constant(depth + 1))?),
+            Tag::Vec => Const::Vec(self.seq(|r| r.constant(depth + 1))?),
+        })
+    }
+}
@@ -0,0 +1,152 @@
+#![cfg(test)]
+
+use super::{Object, Writer, FORMAT_VERSION, MAGIC};
+use crate::compiler::Compiler;
+use crate::parser::Document;
+use crate::vm::{Const, Global, Vm};
+
+fn object(source: &str) -> Object {
+    let doc = source.parse::<Document>().unwrap();
+    let mut c = Compiler::new();
+    c.doc(&doc);
+    let mut code = c.finish().unwrap();
+    code.file = Some("test.lia".to_string());
+    Object {
+        name: "app".to_string(),
+        deps: vec!["app.util".to_string()],
+        exports: Some(vec!["f".to_string()]),
+        code,
+    }
+}
+
+#[test]
+fn roundtrip() {
+    let source = "(defn f [x] [x 'sym 'm/qual \"str\" 300 1.5 '(1 [2 (3)])]) (f ())";
+    let written = object(source);
+    let bytes = written.write();
+    let read = Object::read(&bytes).unwrap_or_else(|e| panic!("{e}"));
+    assert_eq!(written.name, read.name);
+    assert_eq!(written.deps, read.deps);
+    assert_eq!(written.exports, read.exports);
+    assert_eq!(written.code.bytes, read.code.bytes);
+    assert_eq!(written.code.consts, read.code.consts);
+    assert_eq!(written.code.globals, read.code.globals);
+    assert_eq!(written.code.source_map, read.code.source_map);
+    assert_eq!(written.code.file, read.code.file);
+    assert_eq!(bytes, read.write());
+
+    let mut vm = Vm::new();
+    let module = vm.anonymous_module("*test*");
+    let result = vm.run_to_completion(module, read.code).unwrap();
+    assert_eq!(
+        "[() sym m/qual str 300 1.5 (1 [2 (3)])]",
+        result.format(&vm)
+    );
+}
+
+#[test]
+fn bad_files_are_refused() {
+    let bytes = object("(defn f [] 1)").write();
+    let refused = |bytes: &[u8]| match Object::read(bytes) {
+        Ok(_) => panic!("{bytes:?} should be refused"),
+        Err(err) => err.to_string(),
+    };
+
+    assert_eq!("not a compiled alia module at byte 0", refused(b"alib\x01"));
+    let mut version = bytes.clone();
+    version[MAGIC.len()] = FORMAT_VERSION as u8 + 1;
+    assert_eq!(
+        "unsupported format version 2; expected 1 at byte 5",
+        refused(&version)
+    );
+    for len in 0..bytes.len() {
+        refused(&bytes[..len]);
+    }
+    let mut trailing = bytes.clone();
+    trailing.push(0);
+    assert!(refused(&trailing).starts_with("trailing data"));
+
+    let mut w = Writer {
+        out: MAGIC.to_vec(),
+    };
+    w.n(FORMAT_VERSION as usize);
+    w.string("app");
+    for _ in 0..3 {
+        w.n(0); // no file, deps or exports
+    }
+    w.n(1);
+    w.out.push(99);
+    assert_eq!("bad constant tag 99 at byte 13", refused(&w.out));
+}
+
+#[test]
+fn bad_names_are_refused() {
+    let refused = |object: Object| match Object::read(&object.write()) {
+        Ok(_) => panic!("should be refused"),
+        Err(err) => err.message,
+    };
+    let with = |f: fn(&mut Object)| {
+        let mut object = object("(defn f [] 1)");
+        f(&mut object);
+        object
+    };
+
+    assert_eq!(
+        "bad module name \"a/b\"",
+        refused(with(|o| o.name = "a/b".into()))
+    );
+    assert_eq!(
+        "bad module name \"a..b\"",
+        refused(with(|o| o.name = "a..b".into()))
+    );
+    assert_eq!(
+        "bad module name \".a\"",
+        refused(with(|o| o.name = ".a".into()))
+    );
+    assert_eq!(
+        "bad module name \"\"",
+        refused(with(|o| o.name = String::new()))
+    );
+    assert_eq!(
+        "bad module name \"x/y\"",
+        refused(with(|o| o.deps = vec!["x/y".into()]))
+    );
+    assert_eq!(
+        "bad name \"f/g\"",
+        refused(with(|o| o.exports = Some(vec!["f/g".into()])))
+    );
+    assert_eq!(
+        "bad name \"a/b\"",
+        refused(with(
+            |o| o.code.consts = vec![Const::Symbol(None, "a/b".into())]
+        ))
+    );
+    assert_eq!(
+        "bad name \"m/n\"",
+        refused(with(
+            |o| o.code.consts = vec![Const::Symbol(Some("m/n".into()), "x".into())]
+        ))
+    );
+    assert_eq!(
+        "bad name \"x/y\"",
+        refused(with(|o| o.code.consts =
+            vec![Const::List(vec![Const::Symbol(
+                Some("m".into()),
+                "x/y".into()
+            )])]))
+    );
+    assert_eq!(
+        "bad name \"a/b\"",
+        refused(with(|o| o.code.globals = vec![Global {
+            module: None,
+            name: "a/b".into()
+        }]))
+    );
+    assert_eq!(
+        "bad module name \"m/x\"",
+        refused(with(|o| o.code.globals = vec![Global {
+            module: Some("m/x".into()),
+            name: "f".into()
+        }]))
+    );
+}
@@ -10,10 +10,16 @@ pub(crate) struct Document {
 }
 
 impl Document {
-    pub(crate) fn compile(&self) -> Result<Vec<u8>, compiler::Error> {
+    // Real callers want Compiler::in_module, so macros have somewhere to live.
+    #[cfg(test)]
+    pub(crate) fn compile(&self) -> Result<crate::vm::Code, compiler::Error> {
         let mut c = compiler::Compiler::new();
         c.doc(self);
-        Ok(c.finish())
+        c.finish()
+    }
+
+    pub(crate) fn mod_decl(&self) -> Option<Result<compiler::ModDecl, compiler::Diagnostic>> {
+        compiler::ModDecl::from_node(self.toplevels.first()?)
     }
 
     pub(crate) fn nodes_at<L: Into<Loc>>(&self, loc: L) -> Vec<&Node> {
@@ -5,6 +5,8 @@ use super::Range;
 pub(crate) struct Error {
     pub(crate) kind: ErrorKind,
     pub(crate) range: Range,
+    // For Unfinished: where the innermost unclosed form (or string) began.
+    pub(crate) opened: Option<Range>,
 }
 
 impl Error {
@@ -12,6 +14,14 @@ impl Error {
         Error {
             kind,
             range: range.into(),
+            opened: None,
+        }
+    }
+
+    pub(super) fn unfinished<R: Into<Range>>(range: R, opened: Range) -> Self {
+        Error {
+            opened: Some(opened),
+            ..Self::new(ErrorKind::Unfinished, range)
         }
     }
 }
@@ -13,6 +13,9 @@ pub(crate) enum TokenKind {
     VecStart,
     VecEnd,
     Quote,
+    Quasiquote,
+    Unquote,
+    UnquoteSplicing,
 }
 
 pub(crate) struct Token<'a> {
@@ -22,7 +25,7 @@ pub(crate) struct Token<'a> {
     pub(crate) end: Loc,
 }
 
-fn token(kind: TokenKind, s: &[u8], n: usize, mut loc: Loc) -> Token {
+fn token(kind: TokenKind, s: &[u8], n: usize, mut loc: Loc) -> Token<'_> {
     let start = loc;
     let excerpt = &s[..n];
     for c in excerpt {
@@ -39,15 +42,15 @@ fn token(kind: TokenKind, s: &[u8], n: usize, mut loc: Loc) -> Token {
     }
 }
 
-fn skip(s: &[u8], n: usize, loc: Loc) -> Token {
+fn skip(s: &[u8], n: usize, loc: Loc) -> Token<'_> {
     token(TokenKind::Whitespace, s, n, loc)
 }
 
-fn err(s: &[u8]) -> Token {
+fn err(s: &[u8]) -> Token<'_> {
     skip(s, 0, Loc(0, 0))
 }
 
-pub(super) fn lex_one(s: &[u8], loc: Loc) -> Token {
+pub(super) fn lex_one(s: &[u8], loc: Loc) -> Token<'_> {
     let mut cursor = 0;
     let mut marker = 0;
     let len = s.len();
@@ -123,6 +126,18 @@ pub(super) fn lex_one(s: &[u8], loc: Loc) -> Token {
                             yystate = 20;
                             continue 'yyl;
                         }
+                        0x2E => {
+                            yystate = 100;
+                            continue 'yyl;
+                        }
+                        0x60 => {
+                            yystate = 102;
+                            continue 'yyl;
+                        }
+                        0x2C | 0x7E => {
+                            yystate = 103;
+                            continue 'yyl;
+                        }
                         _ => {
                             yystate = 1;
                             continue 'yyl;
@@ -132,6 +147,93 @@ pub(super) fn lex_one(s: &[u8], loc: Loc) -> Token {
                 1 => {
                     return err(s);
                 }
+                100 => {
+                    yych = unsafe {
+                        if cursor < len {
+                            *s.get_unchecked(cursor)
+                        } else {
+                            0
+                        }
+                    };
+                    match yych {
+                        0x21
+                        | 0x2A..=0x2B
+                        | 0x2D
+                        | 0x3C..=0x3E
+                        | 0x41..=0x5A
+                        | 0x5F
+                        | 0x61..=0x7A => {
+                            cursor += 1;
+                            yystate = 101;
+                            continue 'yyl;
+                        }
+                        _ => {
+                            yystate = 1;
+                            continue 'yyl;
+                        }
+                    }
+                }
+                101 => {
+                    yych = unsafe {
+                        if cursor < len {
+                            *s.get_unchecked(cursor)
+                        } else {
+                            0
+                        }
+                    };
+                    match yych {
+                        0x21
+                        | 0x2A..=0x2B
+                        | 0x2D..=0x2E
+                        | 0x30..=0x39
+                        | 0x3C..=0x3E
+                        | 0x41..=0x5A
+                        | 0x5F
+                        | 0x61..=0x7A => {
+                            cursor += 1;
+                            yystate = 101;
+                            continue 'yyl;
+                        }
+                        0x2F => {
+                            cursor += 1;
+                            yystate = 21;
+                            continue 'yyl;
+                        }
+                        _ => {
+                            yystate = 1;
+                            continue 'yyl;
+                        }
+                    }
+                }
+                102 => {
+                    return token(TokenKind::Quasiquote, s, cursor, loc);
+                }
+                103 => {
+                    yych = unsafe {
+                        if cursor < len {
+                            *s.get_unchecked(cursor)
+                        } else {
+                            0
+                        }
+                    };
+                    match yych {
+                        0x40 => {
+                            cursor += 1;
+                            yystate = 105;
+                            continue 'yyl;
+                        }
+                        _ => {
+                            yystate = 104;
+                            continue 'yyl;
+                        }
+                    }
+                }
+                104 => {
+                    return token(TokenKind::Unquote, s, cursor, loc);
+                }
+                105 => {
+                    return token(TokenKind::UnquoteSplicing, s, cursor, loc);
+                }
                 2 => {
                     yych = unsafe {
                         if cursor < len {
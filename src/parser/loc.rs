@@ -15,6 +15,12 @@ impl From<(usize, usize)> for Loc {
     }
 }
 
+impl From<lsp_types::Position> for Loc {
+    fn from(value: lsp_types::Position) -> Self {
+        Loc(value.line as usize, value.character as usize)
+    }
+}
+
 impl From<Loc> for lsp_types::Position {
     fn from(value: Loc) -> Self {
         Self::new(value.0 as u32, value.1 as u32)
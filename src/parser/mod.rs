@@ -22,7 +22,8 @@ struct Parser {
 enum PE {
     List(Vec<Node>, Range),
     Vec(Vec<Node>, Range),
-    Quote(Range),
+    // 'x, `x, ~x or ~@x, waiting for x; the str is what to wrap it in.
+    Quote(&'static str, Range),
 }
 
 impl Parser {
@@ -48,7 +49,7 @@ impl Parser {
                     ns.push(node);
                     return Ok(());
                 }
-                Some(PE::Quote(range)) => {
+                Some(PE::Quote(name, range)) => {
                     let range_all = if range.0 < node.range.1 {
                         (range.0, node.range.1)
                     } else {
@@ -56,7 +57,7 @@ impl Parser {
                     };
                     node = Node::new(
                         NodeValue::List(vec![
-                            Node::new(NodeValue::Symbol(None, "quote".to_string()), *range),
+                            Node::new(NodeValue::Symbol(None, name.to_string()), *range),
                             node,
                         ]),
                         range_all,
@@ -103,21 +104,21 @@ impl Parser {
         self.atom(Node::new(NodeValue::Vec(ns), (srange.0, range.into().1)))
     }
 
-    fn quote<R: Into<Range>>(&mut self, range: R) -> Result<(), Error> {
+    fn quote<R: Into<Range>>(&mut self, name: &'static str, range: R) -> Result<(), Error> {
         if let Some(result) = self.result.take() {
-            self.result = Some(Self::quoted_form(result, range));
+            self.result = Some(Self::quoted_form(name, result, range));
         } else {
-            self.stack.push(PE::Quote(range.into()));
+            self.stack.push(PE::Quote(name, range.into()));
         }
         Ok(())
     }
 
-    fn quoted_form<R: Into<Range>>(node: Node, range: R) -> Node {
+    fn quoted_form<R: Into<Range>>(name: &str, node: Node, range: R) -> Node {
         let range = range.into();
         let range_all = (range.0, node.range.1);
         Node::new(
             NodeValue::List(vec![
-                Node::new(NodeValue::Symbol(None, "quote".to_string()), range),
+                Node::new(NodeValue::Symbol(None, name.to_string()), range),
                 node,
             ]),
             range_all,
@@ -134,8 +135,10 @@ impl Parser {
 
     fn eof<L: Into<Loc>>(self, loc: L) -> Result<Node, Error> {
         let loc = loc.into();
-        if !self.stack.is_empty() {
-            return Err(parse_error(ErrorKind::Unfinished, (loc, loc)));
+        if let Some(PE::List(_, opened) | PE::Vec(_, opened) | PE::Quote(_, opened)) =
+            self.stack.last()
+        {
+            return Err(Error::unfinished((loc, loc), *opened));
         }
         self.result
             .ok_or_else(|| parse_error(ErrorKind::Empty, ((0, 0).into(), loc)))
@@ -153,14 +156,15 @@ fn parse(s: &str, mut offset: usize, mut loc: Loc) -> Result<(Node, usize, Loc),
             start,
             end,
         } = lex_one(&s[offset..], loc);
-        loc = end;
         let consume = excerpt.len();
         if consume == 0 {
+            // The lexer doesn't know where it is when nothing matches.
             return Err(Error::new(
                 ErrorKind::Unexpected(s[offset] as char),
-                (start, end),
+                (loc, Loc(loc.0, loc.1 + 1)),
             ));
         }
+        loc = end;
 
         offset += consume;
 
@@ -176,7 +180,7 @@ fn parse(s: &str, mut offset: usize, mut loc: Loc) -> Result<(Node, usize, Loc),
                 // modulo all the Ruby 3 kwargs improvements).
                 // Here we do [a: b] => ['a b].
                 let kwend = (end.0, end.1 - 1).into();
-                parser.quote((kwend, end))?;
+                parser.quote("quote", (kwend, end))?;
                 parser.atom(Node::new(
                     parse_symbol(&excerpt[..excerpt.len() - 1], (start, kwend))?,
                     (start, kwend),
@@ -194,7 +198,10 @@ fn parse(s: &str, mut offset: usize, mut loc: Loc) -> Result<(Node, usize, Loc),
             TokenKind::ListEnd => parser.list_end((start, end))?,
             TokenKind::VecStart => parser.vec_start((start, end))?,
             TokenKind::VecEnd => parser.vec_end((start, end))?,
-            TokenKind::Quote => parser.quote((start, end))?,
+            TokenKind::Quote => parser.quote("quote", (start, end))?,
+            TokenKind::Quasiquote => parser.quote("quasiquote", (start, end))?,
+            TokenKind::Unquote => parser.quote("unquote", (start, end))?,
+            TokenKind::UnquoteSplicing => parser.quote("unquote-splicing", (start, end))?,
         }
 
         if let Some(result) = parser.try_finish() {
@@ -212,6 +219,10 @@ fn parse_symbol<R: Into<Range>>(s: &[u8], range: R) -> Result<NodeValue, Error>
         Err(parse_error(ErrorKind::Symbol, range))
     } else if let Some((m, s)) = s.split_once('/') {
         assert!(!s.contains('/'));
+        if m.ends_with('.') {
+            return Err(parse_error(ErrorKind::Symbol, range));
+        }
+        // m may start with '.', making it relative to the current module.
         Ok(NodeValue::Symbol(Some(m.to_string()), s.to_string()))
     } else {
         Ok(NodeValue::Symbol(None, s.to_string()))
@@ -275,12 +286,11 @@ fn parse_string<R: Into<Range>>(s: &[u8], range: R) -> Result<String, Error> {
         i += 1;
     }
 
-    Err(parse_error(ErrorKind::Unfinished, range))
+    let range = range.into();
+    let opened = (range.0, Loc(range.0 .0, range.0 .1 + 1));
+    Err(Error::unfinished(range, opened.into()))
 }
 
 fn parse_error<R: Into<Range>>(kind: ErrorKind, range: R) -> Error {
-    Error {
-        kind,
-        range: range.into(),
-    }
+    Error::new(kind, range)
 }
@@ -14,6 +14,18 @@ impl Node {
             range: range.into(),
         }
     }
+
+    // For diagnostics: "top-level integer has no effect".
+    pub(crate) fn kind(&self) -> &'static str {
+        match self.value {
+            NodeValue::Symbol(..) => "symbol",
+            NodeValue::Integer(_) => "integer",
+            NodeValue::Float(_) => "float",
+            NodeValue::String(_) => "string",
+            NodeValue::List(_) => "list",
+            NodeValue::Vec(_) => "vector",
+        }
+    }
 }
 
 impl PartialEq for Node {
@@ -30,6 +42,8 @@ impl Display for Node {
 
 #[derive(PartialEq)]
 pub(crate) enum NodeValue {
+    // (module, name).  A module starting with '.' is relative to the module
+    // the code lives in: .util/p is util's p, where util is a submodule.
     Symbol(Option<String>, String),
     Integer(i64),
     Float(f64),
@@ -23,17 +23,21 @@ impl EditorHelper {
 impl Helper for EditorHelper {}
 
 fn symbol_char(c: u8) -> bool {
-    // matches lexer.re, except we add '/'
-    matches!(c, b'a' ..= b'z' | b'A' ..= b'Z' | b'*' | b'_' | b'<' | b'>' | b'!' | b'=' | b'+' | b'-' | b'/')
+    // matches lexer.re's symchartail, except we add '/'
+    matches!(c, b'a' ..= b'z' | b'A' ..= b'Z' | b'0' ..= b'9' | b'.' | b'*' | b'_' | b'<' | b'>' | b'!' | b'=' | b'+' | b'-' | b'/')
 }
 
 fn complete_in_module(vm: &Vm, module: &Module, entry: &str) -> Vec<String> {
     // matches Module::lookup
     let mut results = vec![];
-    complete_from(vm, module.binds.keys().cloned(), entry, &mut results);
+    complete_from(vm, module.names().into_iter(), entry, &mut results);
     complete_from(vm, vm.modules.keys().cloned(), entry, &mut results);
-    for rm in &module.refers {
-        complete_from(vm, rm.borrow().binds.keys().cloned(), entry, &mut results);
+    for r in &module.refers {
+        complete_from(vm, r.names().into_iter(), entry, &mut results);
+    }
+    if let Some(prelude) = &module.prelude {
+        let names = prelude.borrow().public_names();
+        complete_from(vm, names.into_iter(), entry, &mut results);
     }
     results
 }
@@ -82,20 +86,42 @@ impl Completer for EditorHelper {
 
         // matches Proc::eval
         let mut vm = self.vm.borrow_mut();
+        let am = self.active_module.borrow();
         match entry.split_once('/') {
             Some((m, s)) => {
                 let m = vm.intern(m);
-                let module = match vm.lookup_module(m) {
+                let module = match vm.resolve_module(&am, m) {
                     Some(v) => v,
                     None => return Ok((0, Vec::with_capacity(0))),
                 };
-                let module = module.borrow();
-                Ok((pos, complete_in_module(&vm, &module, s)))
-            }
-            None => {
-                let am = self.active_module.borrow();
-                Ok((pos, complete_in_module(&vm, &am, entry)))
+                // Only what m/s can actually reach.
+                let mut results = vec![];
+                let names = module.borrow().public_names();
+                complete_from(&vm, names.into_iter(), s, &mut results);
+                Ok((pos, results))
             }
+            None => match entry.rsplit_once('.') {
+                // .ut => .util
+                Some(("", sub)) => {
+                    let mut results = vec![];
+                    complete_from(&vm, am.submodules.keys().cloned(), sub, &mut results);
+                    Ok((pos, results))
+                }
+                // kcx.ut => kcx.util, .util.x => .util.xyz
+                // Dots are fine in plain symbols too, so fall back to those.
+                Some((parent, sub)) => {
+                    let parent = vm.intern(parent);
+                    let module = match vm.resolve_module(&am, parent) {
+                        Some(v) => v,
+                        None => return Ok((pos, complete_in_module(&vm, &am, entry))),
+                    };
+                    let mut results = vec![];
+                    let module = module.borrow();
+                    complete_from(&vm, module.submodules.keys().cloned(), sub, &mut results);
+                    Ok((pos, results))
+                }
+                None => Ok((pos, complete_in_module(&vm, &am, entry))),
+            },
         }
     }
 
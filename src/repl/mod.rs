@@ -2,12 +2,15 @@ mod editor;
 
 use std::cell::RefCell;
 use std::error::Error;
+use std::io::{self, IsTerminal};
 use std::rc::Rc;
 
 use rustyline::error::ReadlineError;
 
+use crate::compiler::Compiler;
 use crate::disasm::disasm;
 use crate::parser::{self, Document};
+use crate::report::Report;
 use crate::vm::{Val, Vm};
 
 use self::editor::{Editor, EditorHelper};
@@ -33,6 +36,7 @@ pub(crate) fn main(_args: Vec<String>) -> Result<(), Box<dyn Error + Send + Sync
     // XXX currently there's no sync of EditorHelper and our active_module here.
     _ = rl.load_history(HISTORY_FILE);
 
+    let color = io::stdout().is_terminal();
     let mut acc = String::new();
     loop {
         let promptchar = if acc.is_empty() { '>' } else { '*' };
@@ -43,16 +47,39 @@ pub(crate) fn main(_args: Vec<String>) -> Result<(), Box<dyn Error + Send + Sync
                     Ok(doc) => {
                         _ = rl.add_history_entry(&full);
                         acc.clear();
-                        let code = doc.compile()?;
                         let mut vm = vm.borrow_mut();
+                        // Warnings are for files; at the REPL every
+                        // top-level form is evaluated for its value.
+                        let mut c = Compiler::in_module(&mut vm, active_module.clone());
+                        c.doc(&doc);
+                        let code = match c.finish() {
+                            Ok(code) => code,
+                            Err(err) => {
+                                for d in &err.diagnostics {
+                                    print!("{}", Report::compile(d).render(&full, None, color));
+                                }
+                                continue;
+                            }
+                        };
                         match active_module.borrow().lookup(&vm, sareb) {
                             Some(Val::Symbol(None, s)) if s == strue => {
-                                disasm(&code)?;
+                                disasm(&code, Some(&full))?;
                             }
                             _ => {}
                         }
-                        let val = vm.run_to_completion(active_module.clone(), code);
-                        eprintln!("{}", val.format(&vm));
+                        let result = vm.run_to_completion(active_module.clone(), code);
+                        for (pid, err) in vm.take_crashes() {
+                            print!(
+                                "{}",
+                                Report::crash(pid, &err, None).render(&full, None, color)
+                            );
+                        }
+                        match result {
+                            Ok(val) => println!("{}", val.format(&vm)),
+                            Err(err) => {
+                                print!("{}", Report::runtime(&err, None).render(&full, None, color))
+                            }
+                        }
                     }
                     Err(parser::Error {
                         kind: parser::ErrorKind::Unfinished,
@@ -66,7 +93,7 @@ pub(crate) fn main(_args: Vec<String>) -> Result<(), Box<dyn Error + Send + Sync
                         ..
                     }) => {}
                     Err(err) => {
-                        println!("error: {err}");
+                        print!("{}", Report::parse(&err, &full).render(&full, None, color));
                         acc.clear();
                     }
                 }
@@ -0,0 +1,248 @@
+mod tests;
+
+use std::fmt::Write;
+
+use crate::compiler::{self, Severity};
+use crate::parser::{self, Loc, Range};
+use crate::vm;
+
+// A parse, compile or runtime error (or warning), ready to be rendered
+// against the source it came from.
+pub(crate) struct Report {
+    severity: Severity,
+    message: String,
+    range: Option<Range>,
+    // Secondary ranges, drawn with their own snippet.
+    labels: Vec<(Range, String)>,
+    // Unlocated trailers: hints, backtraces.
+    notes: Vec<String>,
+}
+
+impl Report {
+    // Not about any source in particular.
+    pub(crate) fn error<S: Into<String>>(message: S) -> Self {
+        Report {
+            severity: Severity::Error,
+            message: message.into(),
+            range: None,
+            labels: vec![],
+            notes: vec![],
+        }
+    }
+
+    pub(crate) fn note<S: Into<String>>(mut self, note: S) -> Self {
+        self.notes.push(format!("note: {}", note.into()));
+        self
+    }
+
+    pub(crate) fn parse(err: &parser::Error, source: &str) -> Self {
+        let mut labels = vec![];
+        if let Some(opened) = err.opened {
+            let what = excerpt(source, opened).unwrap_or("form");
+            labels.push((opened, format!("unclosed `{what}` opened here")));
+        }
+        let mut notes = vec![];
+        match err.kind {
+            parser::ErrorKind::Unexpected(')' | ']') => {
+                notes.push("hint: nothing here is open to close".to_string());
+            }
+            parser::ErrorKind::Multiple => {
+                notes.push("hint: only one form is expected here".to_string());
+            }
+            _ => {}
+        }
+        Report {
+            severity: Severity::Error,
+            message: err.kind.to_string(),
+            range: Some(err.range),
+            labels,
+            notes,
+        }
+    }
+
+    pub(crate) fn compile(d: &compiler::Diagnostic) -> Self {
+        let mut labels = vec![];
+        let mut notes = vec![];
+        for note in &d.notes {
+            match note.range {
+                Some(range) => labels.push((range, note.message.clone())),
+                None => notes.push(format!("note: {}", note.message)),
+            }
+        }
+        Report {
+            severity: d.severity,
+            message: d.message.clone(),
+            range: Some(d.range),
+            labels,
+            notes,
+        }
+    }
+
+    // The snippet is taken from the innermost frame that ran code from `file`
+    // (None being "whatever was typed in").
+    pub(crate) fn runtime(err: &vm::Error, file: Option<&str>) -> Self {
+        let range = err
+            .trace
+            .iter()
+            .find(|f| f.file.as_deref() == file && f.range.is_some())
+            .and_then(|f| f.range);
+        Report {
+            severity: Severity::Error,
+            message: err.to_string(),
+            range,
+            labels: vec![],
+            notes: err.trace.iter().map(|f| format!("at {f}")).collect(),
+        }
+    }
+
+    // A process that died with no one waiting on it.
+    pub(crate) fn crash(pid: vm::Pid, err: &vm::Error, file: Option<&str>) -> Self {
+        let mut report = Report::runtime(err, file);
+        report.message = format!("{pid} crashed: {}", report.message);
+        report
+    }
+
+    pub(crate) fn is_error(&self) -> bool {
+        self.severity == Severity::Error
+    }
+
+    pub(crate) fn render(&self, source: &str, file: Option<&str>, color: bool) -> String {
+        let style = Style(color);
+        let lines = source.lines().collect::<Vec<_>>();
+        let range = self.range.map(|r| clamp(r, &lines));
+        let labels = self
+            .labels
+            .iter()
+            .map(|(r, l)| (clamp(*r, &lines), l))
+            .collect::<Vec<_>>();
+        let last_line = range
+            .iter()
+            .chain(labels.iter().map(|(r, _)| r))
+            .map(|r| r.1 .0 + 1)
+            .max()
+            .unwrap_or(0);
+        let gutter = " ".repeat(last_line.to_string().len());
+
+        let mut out = String::new();
+        let colour = match self.severity {
+            Severity::Error => RED,
+            Severity::Warning => YELLOW,
+        };
+        _ = writeln!(
+            out,
+            "{}: {}",
+            style.paint(colour, &self.severity.to_string()),
+            style.paint(BOLD, &self.message)
+        );
+
+        if let Some(range) = range {
+            _ = writeln!(
+                out,
+                "{gutter}{} {}:{}:{}",
+                style.paint(BLUE, "-->"),
+                file.unwrap_or("<input>"),
+                range.0 .0 + 1,
+                range.0 .1 + 1,
+            );
+            _ = writeln!(out, "{gutter} {}", style.paint(BLUE, "|"));
+            snippet(&mut out, &lines, &gutter, range, ('^', colour), "", style);
+        }
+        for (range, label) in labels {
+            if self.range.is_none() {
+                _ = writeln!(out, "{gutter} {}", style.paint(BLUE, "|"));
+            }
+            snippet(&mut out, &lines, &gutter, range, ('-', BLUE), label, style);
+        }
+        if self.range.is_some() || !self.labels.is_empty() {
+            _ = writeln!(out, "{gutter} {}", style.paint(BLUE, "|"));
+        }
+        for note in &self.notes {
+            _ = writeln!(out, "{gutter} {} {note}", style.paint(BLUE, "="));
+        }
+        out
+    }
+}
+
+// Draws each source line `range` touches, underlining the part it covers.
+fn snippet(
+    out: &mut String,
+    lines: &[&str],
+    gutter: &str,
+    range: Range,
+    (mark, colour): (char, &str),
+    label: &str,
+    style: Style,
+) {
+    let Range(start, end) = range;
+    for lineno in start.0..=end.0 {
+        let line = lines.get(lineno).copied().unwrap_or("");
+        let from = if lineno == start.0 { start.1 } else { 0 };
+        let to = if lineno == end.0 { end.1 } else { line.len() };
+        let from = width(line, from);
+        let to = width(line, to).max(from + 1);
+        _ = writeln!(
+            out,
+            "{} {} {line}",
+            style.paint(BLUE, &format!("{:>w$}", lineno + 1, w = gutter.len())),
+            style.paint(BLUE, "|"),
+        );
+        let underline = mark.to_string().repeat(to - from);
+        let label = if lineno == end.0 && !label.is_empty() {
+            format!(" {label}")
+        } else {
+            String::new()
+        };
+        _ = writeln!(
+            out,
+            "{gutter} {} {}{}",
+            style.paint(BLUE, "|"),
+            " ".repeat(from),
+            style.paint(colour, &format!("{underline}{label}")),
+        );
+    }
+}
+
+// Unfinished input points just past the end of the source; pull anything
+// beyond the last line back to its end.
+fn clamp(Range(start, end): Range, lines: &[&str]) -> Range {
+    let last = lines.len().saturating_sub(1);
+    let eol = Loc(last, lines.last().map_or(0, |l| l.len()));
+    let clamp = |l: Loc| if l.0 > last { eol } else { l };
+    Range(clamp(start), clamp(end))
+}
+
+// Display width of the first `col` bytes of line (past its end counts too).
+fn width(line: &str, col: usize) -> usize {
+    match line.get(..col) {
+        Some(s) => s.chars().count(),
+        None if col >= line.len() => line.chars().count() + (col - line.len()),
+        None => col,
+    }
+}
+
+fn excerpt(source: &str, range: Range) -> Option<&str> {
+    let Range(start, end) = range;
+    if start.0 != end.0 {
+        return None;
+    }
+    source.lines().nth(start.0)?.get(start.1..end.1)
+}
+
+const BOLD: &str = "1";
+const RED: &str = "1;31";
+const YELLOW: &str = "1;33";
+const BLUE: &str = "1;34";
+
+// Whether to emit ANSI colour.
+#[derive(Clone, Copy)]
+struct Style(bool);
+
+impl Style {
+    fn paint(self, code: &str, s: &str) -> String {
+        if self.0 {
+            format!("\x1b[{code}m{s}\x1b[0m")
+        } else {
+            s.to_string()
+        }
+    }
+}
@@ -0,0 +1,90 @@
+#![cfg(test)]
+
+use super::Report;
+use crate::parser::Document;
+use crate::vm::Vm;
+
+fn render_parse(source: &str) -> String {
+    let err = source
+        .parse::<Document>()
+        .expect_err("should fail to parse");
+    Report::parse(&err, source).render(source, Some("t.lia"), false)
+}
+
+#[test]
+fn unfinished_points_at_opener() {
+    assert_eq!(
+        render_parse("(print 1)\n(print [1 2\n"),
+        "error: input appears unfinished
+ --> t.lia:2:12
+  |
+2 | (print [1 2
+  |            ^
+2 | (print [1 2
+  |        - unclosed `[` opened here
+  |
+"
+    );
+}
+
+#[test]
+fn unexpected_gets_a_hint() {
+    assert_eq!(
+        render_parse("(print 1))"),
+        "error: unexpected ')'
+ --> t.lia:1:10
+  |
+1 | (print 1))
+  |          ^
+  |
+  = hint: nothing here is open to close
+"
+    );
+}
+
+#[test]
+fn compile_notes_are_labelled() {
+    let source = "(fn [x\n     x] x)";
+    let err = source
+        .parse::<Document>()
+        .unwrap()
+        .compile()
+        .err()
+        .expect("should fail to compile");
+    assert_eq!(
+        Report::compile(&err.diagnostics[0]).render(source, None, false),
+        "error: duplicate parameter x
+ --> <input>:2:6
+  |
+2 |      x] x)
+  |      ^
+1 | (fn [x
+  |      - first declared here
+  |
+"
+    );
+}
+
+#[test]
+fn runtime_errors_show_the_call() {
+    let source = "(defn f [] (nth 1 2))\n(f)";
+    let code = source.parse::<Document>().unwrap().compile().unwrap();
+    let mut vm = Vm::new();
+    let module = vm.anonymous_module("*test*");
+    let err = vm
+        .run_to_completion(module, code)
+        .err()
+        .expect("should fail");
+    assert_eq!(
+        Report::runtime(&err, None).render(source, None, false),
+        "error: type mismatch: trying to nth 1 by 2
+ --> <input>:1:12
+  |
+1 | (defn f [] (nth 1 2))
+  |            ^^^^^^^^^
+  |
+  = at *test*/f (<input>:1:12)
+  = at *test* (<input>:2:1)
+"
+    );
+}
@@ -1,68 +1,346 @@
+use crate::compiler::Compiler;
 use crate::parser::Document;
 
-use super::{proc::Proc, Module, Val, Vm};
+use std::cell::RefCell;
+use std::rc::Rc;
 
-fn eval_args(vm: &mut Vm, proc: &mut Proc, args: &[Val]) -> Vec<Val> {
-    args.into_iter().map(|f| proc.eval(vm, f)).collect()
+use super::{proc::Proc, Error, ErrorKind, InternedSymbol, Module, Refer, Val, Vm};
+
+// Arguments arrive evaluated.  Those that name things rather than being
+// values (refer, alias, import) are passed as data by the compiler.
+
+fn arity(name: &str, args: &[Val], n: usize) -> Result<(), Error> {
+    if args.len() != n {
+        return Err(Error::new(
+            ErrorKind::Arity,
+            format!("{name} takes {n} argument(s), got {}", args.len()),
+        ));
+    }
+    Ok(())
+}
+
+type Keywords<'a> = Vec<(&'static str, &'a Val)>;
+
+// Splits (f x key: v ...) into [x] and the keyword pairs; `key:` reads as
+// (quote key).
+fn keywords<'a>(
+    vm: &Vm,
+    name: &str,
+    args: &'a [Val],
+    allowed: &[&'static str],
+) -> Result<(&'a [Val], Keywords<'a>), Error> {
+    let quote = vm.interns.find("quote");
+    let start = args
+        .iter()
+        .position(|a| matches!(a, Val::List(q) if q.len() == 2 && matches!(q[0], Val::Symbol(None, s) if Some(s) == quote)))
+        .unwrap_or(args.len());
+    let mut kvs = vec![];
+    for pair in args[start..].chunks(2) {
+        let key = match &pair[0] {
+            Val::List(q) => match q[..] {
+                [_, Val::Symbol(None, k)] => allowed.iter().find(|a| **a == vm.resolve(k)),
+                _ => None,
+            },
+            _ => None,
+        }
+        .ok_or_else(|| {
+            Error::new(
+                ErrorKind::Syntax,
+                format!("{name} doesn't take {}", pair[0].format(vm)),
+            )
+        })?;
+        let Some(v) = pair.get(1) else {
+            return Err(Error::new(
+                ErrorKind::Syntax,
+                format!("{name}: {key}: has no value"),
+            ));
+        };
+        kvs.push((*key, v));
+    }
+    Ok((&args[..start], kvs))
+}
+
+// The module named by m (kcx.util, .util, or an alias), from where proc is.
+fn module_arg(vm: &Vm, proc: &Proc, name: &str, m: &Val) -> Result<Rc<RefCell<Module>>, Error> {
+    let &Val::Symbol(None, s) = m else {
+        return Err(Error::new(
+            ErrorKind::Type,
+            format!("{name} expects a module name, not {}", m.format(vm)),
+        ));
+    };
+    vm.resolve_module(&proc.module().borrow(), s)
+        .ok_or_else(|| {
+            Error::new(
+                ErrorKind::UnboundSymbol,
+                format!("unknown module {}", vm.resolve(s)),
+            )
+        })
+}
+
+fn add_alias(
+    vm: &Vm,
+    proc: &Proc,
+    s: InternedSymbol,
+    module: Rc<RefCell<Module>>,
+) -> Result<Val, Error> {
+    let own = proc.module();
+    let mut own = own.borrow_mut();
+    if let Some(existing) = own.aliases.get(&s) {
+        if !Rc::ptr_eq(existing, &module) {
+            return Err(Error::new(
+                ErrorKind::Clash,
+                format!(
+                    "{} is already an alias for {}",
+                    vm.resolve(s),
+                    existing.borrow().name
+                ),
+            ));
+        }
+    }
+    own.aliases.insert(s, module.clone());
+    Ok(Val::Module(module))
 }
 
 pub(super) fn add_all(vm: &mut Vm, m: &mut Module) {
     m.add_bind_builtin(vm, "print", print);
-    m.add_bind_builtin(vm, "quote", quote);
-    m.add_bind_builtin(vm, "set", set);
     m.add_bind_builtin(vm, "eval", eval);
+    m.add_bind_builtin(vm, "=", eq);
+    m.add_bind_builtin(vm, "nth", nth);
+    m.add_bind_builtin(vm, "list", list);
+    m.add_bind_builtin(vm, "modules", modules);
+    m.add_bind_builtin(vm, "submodules", submodules);
+    m.add_bind_builtin(vm, "refer", refer);
+    m.add_bind_builtin(vm, "alias", alias);
+    m.add_bind_builtin(vm, "import", import);
+    m.add_bind_builtin(vm, "macroexpand-1", macroexpand_1);
+    m.add_bind_builtin(vm, "macroexpand", macroexpand);
+    m.add_bind_builtin(vm, "spawn", spawn);
+    m.add_bind_builtin(vm, "self", self_);
+    m.add_bind_builtin(vm, "exit", exit);
+    m.add_bind_builtin(vm, "send", send);
 }
 
-fn print(vm: &mut Vm, proc: &mut Proc, args: &[Val]) -> Val {
+fn print(vm: &mut Vm, _proc: &mut Proc, args: &[Val]) -> Result<Val, Error> {
     // (print "a") => ()
     //   ; prints 'a' as a side-effect
 
-    for arg in eval_args(vm, proc, args) {
+    for arg in args {
         println!("{}", arg.format(vm));
     }
-    Val::List(Vec::with_capacity(0))
+    Ok(Val::List(Vec::with_capacity(0)))
+}
+
+fn eval(vm: &mut Vm, proc: &mut Proc, args: &[Val]) -> Result<Val, Error> {
+    // (eval "print") => builtins/print
+
+    arity("eval", args, 1)?;
+    let s = match &args[0] {
+        Val::String(s) => s,
+        _ => {
+            return Err(Error::new(
+                ErrorKind::Type,
+                format!("trying to eval {}", args[0].format(vm)),
+            ))
+        }
+    };
+    let doc = s
+        .parse::<Document>()
+        .map_err(|err| Error::new(ErrorKind::Syntax, format!("failed to parse {s:?}: {err}")))?;
+    let mut c = Compiler::in_module(vm, proc.module());
+    c.doc(&doc);
+    let code = c
+        .finish()
+        .map_err(|err| Error::new(ErrorKind::Syntax, format!("failed to compile {s:?}: {err}")))?;
+    vm.run_to_completion(proc.module(), code)
+}
+
+fn eq(_vm: &mut Vm, _proc: &mut Proc, args: &[Val]) -> Result<Val, Error> {
+    // (= 1 1 1) => true
+
+    Ok(Val::Boolean(args.windows(2).all(|w| w[0] == w[1])))
+}
+
+fn nth(vm: &mut Vm, _proc: &mut Proc, args: &[Val]) -> Result<Val, Error> {
+    // (nth [1 2 3] 0) => 1
+
+    arity("nth", args, 2)?;
+    let (ns, i) = match (&args[0], &args[1]) {
+        (Val::List(ns) | Val::Vec(ns), &Val::Integer(i)) => (ns, i),
+        _ => {
+            return Err(Error::new(
+                ErrorKind::Type,
+                format!(
+                    "trying to nth {} by {}",
+                    args[0].format(vm),
+                    args[1].format(vm)
+                ),
+            ))
+        }
+    };
+    usize::try_from(i)
+        .ok()
+        .and_then(|i| ns.get(i))
+        .cloned()
+        .ok_or_else(|| Error::new(ErrorKind::Type, format!("index {i} out of range")))
+}
+
+fn list(_vm: &mut Vm, _proc: &mut Proc, args: &[Val]) -> Result<Val, Error> {
+    // (list 'if c x) => (if <c> <x>)
+
+    Ok(Val::List(args.to_vec()))
+}
+
+fn modules(vm: &mut Vm, _proc: &mut Proc, args: &[Val]) -> Result<Val, Error> {
+    // (modules) => [<module builtins> <module kcx>]
+
+    arity("modules", args, 0)?;
+    Ok(Val::Vec(
+        vm.top_modules().into_iter().map(Val::Module).collect(),
+    ))
 }
 
-fn quote(_vm: &mut Vm, _proc: &mut Proc, args: &[Val]) -> Val {
-    // (quote x) => x
+fn submodules(vm: &mut Vm, _proc: &mut Proc, args: &[Val]) -> Result<Val, Error> {
+    // (submodules kcx) => [<module kcx.util>]
 
-    let mut args = args.into_iter().cloned();
-    let head = args.next().expect("quote shouldn't be empty");
-    assert!(args.next().is_none(), "quote shouldn't have multiple items");
-    head.clone()
+    arity("submodules", args, 1)?;
+    match &args[0] {
+        Val::Module(m) => Ok(Val::Vec(
+            m.borrow().children().into_iter().map(Val::Module).collect(),
+        )),
+        v => Err(Error::new(
+            ErrorKind::Type,
+            format!("trying to list submodules of {}", v.format(vm)),
+        )),
+    }
 }
 
-fn set(vm: &mut Vm, proc: &mut Proc, args: &[Val]) -> Val {
-    // (set x 1) => 1
-    //   ; sets the local bind x to 1 as a side-effect
+fn refer(vm: &mut Vm, proc: &mut Proc, args: &[Val]) -> Result<Val, Error> {
+    // (refer kcx.util) => ()
+    // (refer kcx.util only: [p]) => ()
+    //   ; makes kcx.util's exports (or just p) usable unqualified
 
-    if args.len() != 2 {
-        panic!("set should take two arguments");
+    let (args, kvs) = keywords(vm, "refer", args, &["only"])?;
+    arity("refer", args, 1)?;
+    let module = module_arg(vm, proc, "refer", &args[0])?;
+    let mut only = None;
+    for (_, v) in kvs {
+        let names = match v {
+            Val::Vec(ns) => ns
+                .iter()
+                .map(|n| match n {
+                    &Val::Symbol(None, s) => Some(s),
+                    _ => None,
+                })
+                .collect::<Option<Vec<_>>>(),
+            _ => None,
+        };
+        only = Some(names.ok_or_else(|| {
+            Error::new(
+                ErrorKind::Type,
+                format!("only: should be a vector of names, not {}", v.format(vm)),
+            )
+        })?);
     }
-    let s = match args[0] {
-        Val::Symbol(None, s) => s,
-        _ => panic!("trying to set {}", args[0].format(vm)),
+    proc.module()
+        .borrow_mut()
+        .refer(vm, Refer { module, only })?;
+    Ok(Val::unit())
+}
+
+fn alias(vm: &mut Vm, proc: &mut Proc, args: &[Val]) -> Result<Val, Error> {
+    // (alias u kcx.util) => <module kcx.util>
+    //   ; u/p now means kcx.util/p
+
+    arity("alias", args, 2)?;
+    let &Val::Symbol(None, s) = &args[0] else {
+        return Err(Error::new(
+            ErrorKind::Type,
+            format!("trying to alias {}", args[0].format(vm)),
+        ));
     };
-    let v = proc.eval(vm, &args[1]);
-    _ = proc.module.borrow_mut().binds.insert(s, v.clone());
-    v
+    let module = module_arg(vm, proc, "alias", &args[1])?;
+    add_alias(vm, proc, s, module)
 }
 
-fn eval(vm: &mut Vm, proc: &mut Proc, args: &[Val]) -> Val {
-    // (eval "print") => builtins/print
+fn import(vm: &mut Vm, proc: &mut Proc, args: &[Val]) -> Result<Val, Error> {
+    // (import kcx.util) => <module kcx.util>
+    //   ; (alias util kcx.util)
+
+    arity("import", args, 1)?;
+    let module = module_arg(vm, proc, "import", &args[0])?;
+    let last = {
+        let name = &module.borrow().name;
+        name.rsplit('.').next().unwrap_or(name).to_string()
+    };
+    let s = vm.intern(&last);
+    add_alias(vm, proc, s, module)
+}
+
+fn macroexpand_1(vm: &mut Vm, proc: &mut Proc, args: &[Val]) -> Result<Val, Error> {
+    // (macroexpand-1 '(unless c x)) => (if c () x)
+    //   ; given (defmacro unless [c x] ...); non-macro forms are returned as-is
+
+    arity("macroexpand-1", args, 1)?;
+    let form = args[0].clone();
+    let module = proc.module();
+    let expanded = vm.macroexpand_1(&module, &form)?;
+    Ok(expanded.unwrap_or(form))
+}
 
-    if args.len() != 1 {
-        panic!("eval should take one argument");
+fn macroexpand(vm: &mut Vm, proc: &mut Proc, args: &[Val]) -> Result<Val, Error> {
+    // (macroexpand '(m x)) => expands repeatedly until the head isn't a macro
+
+    arity("macroexpand", args, 1)?;
+    let mut form = args[0].clone();
+    let module = proc.module();
+    while let Some(expanded) = vm.macroexpand_1(&module, &form)? {
+        form = expanded;
     }
+    Ok(form)
+}
 
-    let s = match &args[0] {
-        Val::String(s) => s,
-        _ => panic!("trying to eval {}", args[0].format(vm)),
+fn spawn(vm: &mut Vm, _proc: &mut Proc, args: &[Val]) -> Result<Val, Error> {
+    // (spawn f 1 2) => <pid 2>
+    //   ; (f 1 2) runs in the new process, alongside this one
+
+    let Some(Val::Function(f)) = args.first() else {
+        return Err(Error::new(
+            ErrorKind::Type,
+            match args.first() {
+                Some(v) => format!("trying to spawn {}", v.format(vm)),
+                None => "spawn takes a function".to_string(),
+            },
+        ));
     };
-    let doc = match s.parse::<Document>() {
-        Ok(node) => node,
-        _ => panic!("failed to parse {s:?}"),
+    Ok(Val::Pid(vm.spawn(f, args[1..].to_vec())))
+}
+
+fn self_(_vm: &mut Vm, proc: &mut Proc, args: &[Val]) -> Result<Val, Error> {
+    // (self) => <pid 1>
+
+    arity("self", args, 0)?;
+    Ok(Val::Pid(proc.pid))
+}
+
+fn exit(vm: &mut Vm, _proc: &mut Proc, args: &[Val]) -> Result<Val, Error> {
+    // (exit 'done)
+    //   ; ends this process, with reason done
+
+    arity("exit", args, 1)?;
+    Err(Error::exit(vm, args[0].clone()))
+}
+
+fn send(vm: &mut Vm, _proc: &mut Proc, args: &[Val]) -> Result<Val, Error> {
+    // (send pid [(self) 'ping]) => [<pid 1> ping]
+    //   ; lands in pid's mailbox, for it to receive
+
+    arity("send", args, 2)?;
+    let Val::Pid(pid) = args[0] else {
+        return Err(Error::new(
+            ErrorKind::Type,
+            format!("trying to send to {}", args[0].format(vm)),
+        ));
     };
-    let code = doc.compile().unwrap();
-    vm.run_to_completion(proc.module.clone(), code)
+    vm.send(pid, args[1].clone());
+    Ok(args[1].clone())
 }
@@ -0,0 +1,169 @@
+use std::cell::RefCell;
+use std::fmt::Display;
+use std::rc::Rc;
+
+use super::verify::verify;
+use super::{Error, ErrorKind, Module, Val, Vm};
+use crate::parser::Range;
+
+pub(crate) struct Code {
+    pub(crate) bytes: Vec<u8>,
+    // Referred to by index: Constant n pushes consts[n].
+    pub(crate) consts: Vec<Const>,
+    // consts as values, symbols interned, for the VM the code was last loaded
+    // into; see load.
+    pub(crate) pool: Vec<Val>,
+    // Referred to by index: LoadGlobal n and StoreGlobal n use globals[n].
+    pub(crate) globals: Vec<Global>,
+    // globals, linked to their slots by load.
+    pub(crate) links: Vec<(Rc<RefCell<Module>>, usize)>,
+    pub(crate) file: Option<String>,
+    // (offset, range): ops from offset onwards came from range, until the
+    // next entry.  Sorted by offset.
+    pub(crate) source_map: Vec<(usize, Range)>,
+}
+
+// A constant pool entry: anything the code needs that doesn't fit in an
+// operand.  Independent of any VM until loaded.
+#[derive(Clone, Debug)]
+pub(crate) enum Const {
+    Symbol(Option<String>, String),
+    Integer(i64),
+    Float(f64),
+    String(String),
+    // Quoted data.
+    List(Vec<Const>),
+    Vec(Vec<Const>),
+}
+
+// A bind in some module, which the compiler determined statically.
+#[derive(Clone, PartialEq, Debug)]
+pub(crate) struct Global {
+    // None is whichever module the code is loaded into.
+    pub(crate) module: Option<String>,
+    pub(crate) name: String,
+}
+
+impl Code {
+    pub(crate) fn new(bytes: Vec<u8>) -> Self {
+        Code {
+            bytes,
+            consts: vec![],
+            pool: vec![],
+            globals: vec![],
+            links: vec![],
+            file: None,
+            source_map: vec![],
+        }
+    }
+
+    // Verifies the code, interns everything in the pool and finds every
+    // global's slot, once, before the code runs in module.
+    pub(crate) fn load(&mut self, vm: &mut Vm, module: &Rc<RefCell<Module>>) -> Result<(), Error> {
+        verify(self, &module.borrow().name)?;
+        self.pool = self.consts.iter().map(|c| c.to_val(vm)).collect();
+        self.links = self
+            .globals
+            .iter()
+            .map(|g| {
+                let target = match &g.module {
+                    None => module.clone(),
+                    Some(m) => {
+                        let sm = vm.intern(m);
+                        vm.lookup_module(sm).ok_or_else(|| {
+                            Error::new(ErrorKind::UnboundSymbol, format!("unknown module {m}"))
+                        })?
+                    }
+                };
+                let s = vm.intern(&g.name);
+                let slot = target.borrow_mut().slot(s);
+                Ok((target, slot))
+            })
+            .collect::<Result<_, _>>()?;
+        Ok(())
+    }
+
+    pub(crate) fn range_at(&self, offset: usize) -> Option<Range> {
+        let i = self.source_map.partition_point(|&(o, _)| o <= offset);
+        i.checked_sub(1).map(|i| self.source_map[i].1)
+    }
+}
+
+impl From<Vec<u8>> for Code {
+    fn from(bytes: Vec<u8>) -> Self {
+        Code::new(bytes)
+    }
+}
+
+impl Const {
+    pub(crate) fn to_val(&self, vm: &mut Vm) -> Val {
+        match self {
+            Const::Symbol(None, s) => Val::Symbol(None, vm.intern(s)),
+            Const::Symbol(Some(m), s) => Val::Symbol(Some(vm.intern(m)), vm.intern(s)),
+            Const::Integer(i) => Val::Integer(*i),
+            Const::Float(f) => Val::Float(*f),
+            Const::String(s) => Val::String(s.clone()),
+            Const::List(cs) => Val::List(cs.iter().map(|c| c.to_val(vm)).collect()),
+            Const::Vec(cs) => Val::Vec(cs.iter().map(|c| c.to_val(vm)).collect()),
+        }
+    }
+
+    // s as Display writes an integer or float, if it's one.
+    pub(crate) fn number(s: &str) -> Option<Const> {
+        if let Ok(i) = s.parse::<i64>() {
+            return Some(Const::Integer(i));
+        }
+        s.parse::<f64>().ok().map(Const::Float)
+    }
+}
+
+// Floats compare by bits, so 0.0 and -0.0 get separate entries and NaN can
+// share one.
+impl PartialEq for Const {
+    fn eq(&self, other: &Self) -> bool {
+        match (self, other) {
+            (Const::Symbol(m1, s1), Const::Symbol(m2, s2)) => m1 == m2 && s1 == s2,
+            (Const::Integer(i1), Const::Integer(i2)) => i1 == i2,
+            (Const::Float(f1), Const::Float(f2)) => f1.to_bits() == f2.to_bits(),
+            (Const::String(s1), Const::String(s2)) => s1 == s2,
+            (Const::List(cs1), Const::List(cs2)) | (Const::Vec(cs1), Const::Vec(cs2)) => cs1 == cs2,
+            _ => false,
+        }
+    }
+}
+
+impl Display for Global {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match &self.module {
+            None => f.write_str(&self.name),
+            Some(m) => write!(f, "{m}/{}", self.name),
+        }
+    }
+}
+
+// As the assembler reads it back: the other way around, a symbol that looks
+// like a number (-3 is a symbol to the reader) is marked with a backslash.
+impl Display for Const {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let seq = |f: &mut std::fmt::Formatter<'_>, cs: &[Const], open, close| {
+            f.write_str(open)?;
+            for (i, c) in cs.iter().enumerate() {
+                if i > 0 {
+                    f.write_str(" ")?;
+                }
+                write!(f, "{c}")?;
+            }
+            f.write_str(close)
+        };
+        match self {
+            Const::Symbol(None, s) if Const::number(s).is_some() => write!(f, "\\{s}"),
+            Const::Symbol(None, s) => f.write_str(s),
+            Const::Symbol(Some(m), s) => write!(f, "{m}/{s}"),
+            Const::Integer(i) => write!(f, "{i}"),
+            Const::Float(d) => write!(f, "{d:?}"),
+            Const::String(s) => write!(f, "{s:?}"),
+            Const::List(cs) => seq(f, cs, "(", ")"),
+            Const::Vec(cs) => seq(f, cs, "[", "]"),
+        }
+    }
+}
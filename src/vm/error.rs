@@ -0,0 +1,141 @@
+use std::fmt::{Debug, Display};
+
+use super::{Val, Vm};
+use crate::parser::Range;
+
+pub(crate) struct Error {
+    pub(crate) kind: ErrorKind,
+    pub(crate) message: String,
+    // Only for ErrorKind::Thrown and ErrorKind::Exit.
+    pub(crate) value: Option<Box<Val>>,
+    pub(crate) trace: Vec<TraceFrame>,
+}
+
+impl Error {
+    pub(crate) fn new<S: Into<String>>(kind: ErrorKind, message: S) -> Self {
+        Error {
+            kind,
+            message: message.into(),
+            value: None,
+            trace: vec![],
+        }
+    }
+
+    pub(crate) fn thrown(vm: &Vm, value: Val) -> Self {
+        Error {
+            kind: ErrorKind::Thrown,
+            message: value.format(vm),
+            value: Some(Box::new(value)),
+            trace: vec![],
+        }
+    }
+
+    // (exit reason): the process ends, and nothing can catch it.
+    pub(crate) fn exit(vm: &Vm, reason: Val) -> Self {
+        Error {
+            kind: ErrorKind::Exit,
+            ..Error::thrown(vm, reason)
+        }
+    }
+
+    // What a catch clause sees: whatever was thrown, or [kind "message"] for
+    // errors raised by the VM itself.
+    pub(crate) fn to_val(&self, vm: &mut Vm) -> Val {
+        match &self.value {
+            Some(v) => (**v).clone(),
+            None => Val::Vec(vec![
+                Val::Symbol(None, vm.intern(self.kind.name())),
+                Val::String(self.message.clone()),
+            ]),
+        }
+    }
+}
+
+impl std::error::Error for Error {}
+
+impl Display for Error {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}: {}", self.kind, self.message)
+    }
+}
+
+impl Debug for Error {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        Display::fmt(self, f)
+    }
+}
+
+pub(crate) struct TraceFrame {
+    pub(crate) name: String,
+    pub(crate) file: Option<String>,
+    pub(crate) range: Option<Range>,
+}
+
+impl Display for TraceFrame {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", self.name)?;
+        match (&self.file, self.range) {
+            // 1-based, as editors and terminals expect.
+            (file, Some(Range(start, _))) => write!(
+                f,
+                " ({}:{}:{})",
+                file.as_deref().unwrap_or("<input>"),
+                start.0 + 1,
+                start.1 + 1
+            ),
+            (Some(file), None) => write!(f, " ({file})"),
+            (None, None) => Ok(()),
+        }
+    }
+}
+
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub(crate) enum ErrorKind {
+    UnboundSymbol,
+    Arity,
+    Type,
+    NotCallable,
+    Syntax,
+    Bytecode,
+    Clash,
+    Thrown,
+    Exit,
+    Deadlock,
+    Budget,
+}
+
+impl ErrorKind {
+    pub(crate) fn name(&self) -> &'static str {
+        match self {
+            Self::UnboundSymbol => "unbound-symbol",
+            Self::Arity => "arity",
+            Self::Type => "type",
+            Self::NotCallable => "not-callable",
+            Self::Syntax => "syntax",
+            Self::Bytecode => "bytecode",
+            Self::Clash => "clash",
+            Self::Thrown => "thrown",
+            Self::Exit => "exit",
+            Self::Deadlock => "deadlock",
+            Self::Budget => "budget",
+        }
+    }
+}
+
+impl Display for ErrorKind {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::UnboundSymbol => f.write_str("unbound symbol"),
+            Self::Arity => f.write_str("wrong number of arguments"),
+            Self::Type => f.write_str("type mismatch"),
+            Self::NotCallable => f.write_str("not callable"),
+            Self::Syntax => f.write_str("syntax error"),
+            Self::Bytecode => f.write_str("bad bytecode"),
+            Self::Clash => f.write_str("name clash"),
+            Self::Thrown => f.write_str("uncaught throw"),
+            Self::Exit => f.write_str("exited"),
+            Self::Deadlock => f.write_str("deadlock"),
+            Self::Budget => f.write_str("out of reductions"),
+        }
+    }
+}
@@ -35,6 +35,11 @@ impl Interns {
         }))
     }
 
+    // Like intern, but doesn't add anything.
+    pub(super) fn find<S: AsRef<[u8]>>(&self, s: S) -> Option<InternedSymbol> {
+        self.sym_to_ix.get(s.as_ref()).map(|&ix| InternedSymbol(ix))
+    }
+
     pub(super) fn resolve(&self, i: InternedSymbol) -> &[u8] {
         self.ix_to_sym
             .get(i.0 - 1)
@@ -1,35 +1,78 @@
 mod builtins;
+mod code;
+mod error;
 mod interns;
 mod module;
 mod ops;
 mod proc;
+mod tests;
 mod val;
+pub(crate) mod varint;
+mod verify;
 
 use std::cell::RefCell;
-use std::collections::HashMap;
+use std::collections::{HashMap, VecDeque};
+use std::mem;
 use std::rc::Rc;
 use std::str;
+use std::thread;
+use std::time::Instant;
 
+pub(crate) use self::code::{Code, Const, Global};
+pub(crate) use self::error::{Error, ErrorKind, TraceFrame};
 pub(crate) use self::interns::InternedSymbol;
-pub(crate) use self::module::Module;
+pub(crate) use self::module::{Module, Refer};
 pub(crate) use self::ops::Op;
-pub(crate) use self::val::{BuiltinVal, Val};
+pub(crate) use self::proc::Pid;
+pub(crate) use self::val::{BuiltinVal, FunctionVal, Val};
 
 use self::interns::Interns;
-use self::proc::{Pid, Proc, Step};
+use self::proc::{Proc, Step};
 
 pub(crate) struct Vm {
     pub(super) modules: HashMap<InternedSymbol, Rc<RefCell<Module>>>,
     pub(super) interns: Interns,
     last_pid: Pid,
+    // Runnable processes, taken in turn; see run_until.
+    run_queue: VecDeque<Proc>,
+    // Blocked in receive, until something's sent to them or they time out.
+    waiting: Vec<Proc>,
+    // One per live process, oldest message first.
+    mailboxes: HashMap<Pid, VecDeque<Val>>,
+    // What run_until is waiting on, innermost last, and how those that have
+    // ended did.
+    awaited: Vec<Pid>,
+    exits: HashMap<Pid, Exit>,
+    // Processes that died of an error with no one waiting on them, for
+    // whoever's in charge to report; see take_crashes.
+    crashes: Vec<(Pid, Error)>,
+    // How many more ops may run, across every process, before they all fail;
+    // see set_budget.
+    budget: Option<usize>,
 }
 
+// How a process ended: what its function returned, or the error that killed
+// it, (exit reason) included.
+type Exit = Result<Val, Error>;
+
+// How many ops a process gets to run before it goes to the back of the queue.
+// A builtin that calls back into the VM (eval, say) counts as one, however
+// long it takes.
+const REDUCTIONS: usize = 1000;
+
 impl Vm {
     pub(crate) fn new() -> Self {
         let mut vm = Vm {
             modules: HashMap::new(),
             interns: Interns::new(),
             last_pid: Pid(0),
+            run_queue: VecDeque::new(),
+            waiting: vec![],
+            mailboxes: HashMap::new(),
+            awaited: vec![],
+            exits: HashMap::new(),
+            crashes: vec![],
+            budget: None,
         };
 
         let builtins = Module::builtins(&mut vm);
@@ -48,9 +91,32 @@ impl Vm {
         str::from_utf8(self.interns.resolve(s)).expect("all interned symbols should be utf-8")
     }
 
+    // Like anonymous_module, but registered so other modules can find it:
+    // kcx.util becomes submodule util of kcx, which is top-level.  Missing
+    // ancestors are created (empty) along the way.
+    pub(crate) fn named_module(&mut self, name: &str) -> Rc<RefCell<Module>> {
+        let module = self.anonymous_module(name);
+        match name.rsplit_once('.') {
+            None => {
+                let sym = self.interns.intern(name);
+                self.modules.insert(sym, module.clone());
+            }
+            Some((parent, last)) => {
+                let sparent = self.interns.intern(parent);
+                let parent = match self.lookup_module(sparent) {
+                    Some(parent) => parent,
+                    None => self.named_module(parent),
+                };
+                let sym = self.interns.intern(last);
+                parent.borrow_mut().submodules.insert(sym, module.clone());
+            }
+        }
+        module
+    }
+
     pub(crate) fn anonymous_module(&mut self, name: &str) -> Rc<RefCell<Module>> {
         let mut module = Module::new(name.to_string());
-        module.refer(
+        module.prelude = Some(
             self.modules
                 .get(&self.interns.intern("builtins"))
                 .unwrap()
@@ -59,28 +125,275 @@ impl Vm {
         Rc::new(RefCell::new(module))
     }
 
-    pub(crate) fn run_to_completion(&mut self, module: Rc<RefCell<Module>>, code: Vec<u8>) -> Val {
-        let proc = self.schedule(module, code);
-        self.step_to_end(proc)
+    pub(crate) fn run_to_completion(
+        &mut self,
+        module: Rc<RefCell<Module>>,
+        code: Code,
+    ) -> Result<Val, Error> {
+        let proc = self.schedule(module, code)?;
+        let pid = proc.pid;
+        self.run_queue.push_back(proc);
+        self.run_until(pid)
+    }
+
+    // Starts f on already-evaluated arguments in a new process, which runs
+    // alongside the rest once something calls run_until.
+    pub(crate) fn spawn(&mut self, f: &FunctionVal, args: Vec<Val>) -> Pid {
+        let pid = self.next_pid();
+        self.run_queue.push_back(Proc::spawn(pid, f, args));
+        pid
     }
 
+    // Runs every process in turn until pid exits, and returns what it
+    // returned.  Anything else still running is left in the queue for next
+    // time.
+    pub(crate) fn run_until(&mut self, pid: Pid) -> Result<Val, Error> {
+        self.awaited.push(pid);
+        let result = loop {
+            if let Some(exit) = self.exits.remove(&pid) {
+                break exit;
+            }
+            match self.turn(None) {
+                Ok(Some((p, exit))) => self.reap(p, exit),
+                Ok(None) => {}
+                Err(mut err) => {
+                    // Deadlocked: pid's likely among those stuck, and it's
+                    // where the blame goes.
+                    if let Some(i) = self.waiting.iter().position(|p| p.pid == pid) {
+                        let proc = self.waiting.swap_remove(i);
+                        err.trace = proc.backtrace();
+                        self.mailboxes.remove(&pid);
+                    }
+                    break Err(err);
+                }
+            }
+        };
+        self.awaited.pop();
+        result
+    }
+
+    // Messages are Vals like any other, copied into to's mailbox.  Sending
+    // to a process that's gone does nothing.
+    pub(crate) fn send(&mut self, to: Pid, msg: Val) {
+        let Some(mailbox) = self.mailboxes.get_mut(&to) else {
+            return;
+        };
+        mailbox.push_back(msg);
+        if let Some(i) = self.waiting.iter().position(|p| p.pid == to) {
+            let proc = self.waiting.swap_remove(i);
+            self.run_queue.push_back(proc);
+        }
+    }
+
+    // For a process blocked in receive that can't yield, being called from
+    // Rust: someone else's turn, or a sleep until the first timeout, whether
+    // the others' or its own (deadline).
+    fn idle(&mut self, deadline: Option<Instant>) -> Result<(), Error> {
+        if let Some((pid, exit)) = self.turn(deadline)? {
+            self.reap(pid, exit);
+        }
+        Ok(())
+    }
+
+    // Calls f with already-evaluated arguments, in a process of its own, and
+    // waits for it.  Nothing else runs unless f blocks in receive; then the
+    // other processes take their turns (see idle) until it can go on, and
+    // may well finish.
+    pub(crate) fn call(&mut self, f: &FunctionVal, args: Vec<Val>) -> Result<Val, Error> {
+        let mut proc = self.schedule(f.module.clone(), Code::new(vec![]))?;
+        let result = proc.apply(self, f, args);
+        self.mailboxes.remove(&proc.pid);
+        result
+    }
+
+    // s is a qualified name: kcx, kcx.util, ...
     pub(crate) fn lookup_module(&self, s: InternedSymbol) -> Option<Rc<RefCell<Module>>> {
-        self.modules.get(&s).cloned()
+        let name = self.resolve(s);
+        let (top, path) = match name.split_once('.') {
+            Some((top, path)) => (top, Some(path)),
+            None => (name, None),
+        };
+        let top = self.modules.get(&self.interns.find(top)?)?.clone();
+        match path {
+            None => Some(top),
+            Some(path) => self.descend(&top.borrow(), path),
+        }
+    }
+
+    // m is either one of from's aliases, absolute (kcx.util), or relative to
+    // from (.util).
+    pub(crate) fn resolve_module(
+        &self,
+        from: &Module,
+        m: InternedSymbol,
+    ) -> Option<Rc<RefCell<Module>>> {
+        if let Some(module) = from.aliases.get(&m) {
+            return Some(module.clone());
+        }
+        match self.resolve(m).strip_prefix('.') {
+            Some(path) => self.descend(from, path),
+            None => self.lookup_module(m),
+        }
+    }
+
+    // util.x => from's submodule util's submodule x.
+    fn descend(&self, from: &Module, path: &str) -> Option<Rc<RefCell<Module>>> {
+        let mut parts = path.split('.');
+        let first = self.interns.find(parts.next()?)?;
+        let mut module = from.submodules.get(&first)?.clone();
+        for part in parts {
+            let part = self.interns.find(part)?;
+            let next = module.borrow().submodules.get(&part)?.clone();
+            module = next;
+        }
+        Some(module)
     }
 
-    fn schedule(&mut self, module: Rc<RefCell<Module>>, code: Vec<u8>) -> Proc {
+    // The macro a form's head names, as seen from module.
+    pub(crate) fn lookup_macro(&self, module: &Module, head: &Val) -> Option<FunctionVal> {
+        let v = match *head {
+            Val::Symbol(None, s) => module.lookup(self, s),
+            Val::Symbol(Some(m), s) => self.resolve_module(module, m)?.borrow().lookup_public(s),
+            _ => None,
+        };
+        match v {
+            Some(Val::Macro(f)) => Some(f),
+            _ => None,
+        }
+    }
+
+    // (m a b) => whatever m returns given the forms a and b, unevaluated.
+    // None if form isn't a macro call.
+    pub(crate) fn macroexpand_1(
+        &mut self,
+        module: &Rc<RefCell<Module>>,
+        form: &Val,
+    ) -> Result<Option<Val>, Error> {
+        let Val::List(ns) = form else {
+            return Ok(None);
+        };
+        let f = ns
+            .first()
+            .and_then(|h| self.lookup_macro(&module.borrow(), h));
+        let Some(f) = f else {
+            return Ok(None);
+        };
+        self.call(&f, ns[1..].to_vec()).map(Some)
+    }
+
+    // Top-level modules, sorted by name.
+    pub(crate) fn top_modules(&self) -> Vec<Rc<RefCell<Module>>> {
+        let mut modules = self.modules.values().cloned().collect::<Vec<_>>();
+        modules.sort_by(|a, b| a.borrow().name.cmp(&b.borrow().name));
+        modules
+    }
+
+    fn schedule(&mut self, module: Rc<RefCell<Module>>, mut code: Code) -> Result<Proc, Error> {
+        code.load(self, &module)?;
+        Ok(Proc::new(self.next_pid(), module, code))
+    }
+
+    fn next_pid(&mut self) -> Pid {
         self.last_pid = Pid(self.last_pid.0 + 1);
-        Proc::new(self.last_pid, module, code)
+        self.mailboxes.insert(self.last_pid, VecDeque::new());
+        self.last_pid
     }
 
-    fn step_to_end(&mut self, mut proc: Proc) -> Val {
-        loop {
+    // A timeslice for whoever's next, and who ended during it (if anyone).
+    // If everyone's waiting on a message, it's a sleep until the first of
+    // their timeouts (or also) instead; if none of them have one, nothing
+    // will ever change, and that's an error.
+    fn turn(&mut self, also: Option<Instant>) -> Result<Option<(Pid, Exit)>, Error> {
+        let now = Instant::now();
+        let (expired, waiting) = mem::take(&mut self.waiting)
+            .into_iter()
+            .partition(|p| p.deadline().is_some_and(|d| d <= now));
+        self.waiting = waiting;
+        self.run_queue.extend::<Vec<_>>(expired);
+
+        let Some(mut proc) = self.run_queue.pop_front() else {
+            let first = self
+                .waiting
+                .iter()
+                .filter_map(Proc::deadline)
+                .chain(also)
+                .min();
+            let Some(first) = first else {
+                return Err(Error::new(
+                    ErrorKind::Deadlock,
+                    "every process is waiting for a message",
+                ));
+            };
+            thread::sleep(first.saturating_duration_since(now));
+            return Ok(None);
+        };
+        let exit = match self.timeslice(&mut proc) {
+            Step::Running => {
+                self.run_queue.push_back(proc);
+                return Ok(None);
+            }
+            Step::Waiting => {
+                self.waiting.push(proc);
+                return Ok(None);
+            }
+            Step::Finished => proc
+                .last
+                .take()
+                .ok_or_else(|| Error::new(ErrorKind::Bytecode, "finished without a value")),
+            Step::Errored(err) => Err(err),
+        };
+        self.mailboxes.remove(&proc.pid);
+        Ok(Some((proc.pid, exit)))
+    }
+
+    // pid has ended.  If run_until's waiting on it, that's where the exit
+    // goes; otherwise nobody's going to look, so if it crashed, it's kept for
+    // take_crashes.  An explicit (exit reason) goes quietly.
+    fn reap(&mut self, pid: Pid, exit: Exit) {
+        if self.awaited.contains(&pid) {
+            self.exits.insert(pid, exit);
+            return;
+        }
+        if let Err(err) = exit {
+            if err.kind != ErrorKind::Exit {
+                self.crashes.push((pid, err));
+            }
+        }
+    }
+
+    // Limits how much more code runs, for code nobody asked to run (macros,
+    // as an editor compiles them) and that mustn't hang whoever's waiting.
+    // Running out is an error nothing can catch.  None lifts the limit.
+    pub(crate) fn set_budget(&mut self, reductions: Option<usize>) {
+        self.budget = reductions;
+    }
+
+    // One op's worth of the budget, if there is one.
+    fn spend(&mut self) -> Result<(), Error> {
+        match &mut self.budget {
+            Some(0) => Err(Error::new(ErrorKind::Budget, "took too long")),
+            Some(n) => {
+                *n -= 1;
+                Ok(())
+            }
+            None => Ok(()),
+        }
+    }
+
+    // Crashes since last asked, oldest first.
+    pub(crate) fn take_crashes(&mut self) -> Vec<(Pid, Error)> {
+        mem::take(&mut self.crashes)
+    }
+
+    // Up to REDUCTIONS steps of proc, stopping early if it finishes or has
+    // to wait.
+    fn timeslice(&mut self, proc: &mut Proc) -> Step {
+        for _ in 0..REDUCTIONS {
             match proc.step(self) {
                 Step::Running => {}
-                Step::Finished => {
-                    return proc.last.expect("proc should return (drop) a value");
-                }
+                step => return step,
             }
         }
+        Step::Running
     }
 }
@@ -1,27 +1,77 @@
-use std::{cell::RefCell, collections::HashMap, rc::Rc};
+use std::{
+    cell::RefCell,
+    collections::{HashMap, HashSet},
+    rc::Rc,
+};
 
 use super::{
     val::{Builtin, BuiltinVal},
-    InternedSymbol, Val, Vm,
+    Error, ErrorKind, InternedSymbol, Val, Vm,
 };
 
-#[derive(Clone)]
 pub(crate) struct Module {
     // consts // fns // macros
     // ^--- these all occupy the same namespace!
+    // Qualified: kcx.util, not util.
     pub(crate) name: String,
-    pub(crate) _submodules: HashMap<InternedSymbol, Rc<RefCell<Module>>>,
-    pub(crate) refers: Vec<Rc<RefCell<Module>>>,
-    pub(crate) binds: HashMap<InternedSymbol, Val>, // XXX
+    pub(crate) submodules: HashMap<InternedSymbol, Rc<RefCell<Module>>>,
+    // builtins, referred implicitly.  Unlike refers, these may be shadowed.
+    pub(crate) prelude: Option<Rc<RefCell<Module>>>,
+    pub(crate) refers: Vec<Refer>,
+    // (alias u kcx.util) makes u/p mean kcx.util/p.
+    pub(crate) aliases: HashMap<InternedSymbol, Rc<RefCell<Module>>>,
+    // From (mod name exports: [...]).  None means everything is public.
+    pub(crate) exports: Option<HashSet<InternedSymbol>>,
+    // Name to slot.  Compiled code is linked to slots when loaded, which
+    // reserves them before anything's necessarily bound there: code can refer
+    // to a name defined later.
+    binds: HashMap<InternedSymbol, usize>,
+    slots: Vec<(InternedSymbol, Option<Val>)>,
+}
+
+#[derive(Clone)]
+pub(crate) struct Refer {
+    pub(crate) module: Rc<RefCell<Module>>,
+    // (refer m only: [a b]); None refers all of m's exports.
+    pub(crate) only: Option<Vec<InternedSymbol>>,
+}
+
+impl Refer {
+    // Whether this refer makes s visible, and what as.
+    pub(crate) fn get(&self, s: InternedSymbol) -> Option<Val> {
+        if let Some(only) = &self.only {
+            if !only.contains(&s) {
+                return None;
+            }
+        }
+        self.module.borrow().lookup_public(s)
+    }
+
+    // Like get, but a name the module has only declared counts too.
+    fn declares(&self, s: InternedSymbol) -> bool {
+        self.only.as_ref().is_none_or(|only| only.contains(&s))
+            && self.module.borrow().declares_public(s)
+    }
+
+    pub(crate) fn names(&self) -> Vec<InternedSymbol> {
+        match &self.only {
+            Some(only) => only.clone(),
+            None => self.module.borrow().public_names(),
+        }
+    }
 }
 
 impl Module {
     pub(super) fn new(name: String) -> Self {
         Module {
             name,
-            _submodules: HashMap::new(),
+            submodules: HashMap::new(),
+            prelude: None,
             refers: vec![],
+            aliases: HashMap::new(),
+            exports: None,
             binds: HashMap::new(),
+            slots: vec![],
         }
     }
 
@@ -35,43 +85,169 @@ impl Module {
         m
     }
 
-    pub(super) fn refer(&mut self, module: Rc<RefCell<Module>>) {
-        self.refers.push(module);
+    // Brings refer's names into scope, unless any of them are already taken.
+    pub(crate) fn refer(&mut self, vm: &Vm, refer: Refer) -> Result<(), Error> {
+        let rname = refer.module.borrow().name.clone();
+        for s in refer.names() {
+            let name = vm.resolve(s);
+            if !refer.declares(s) {
+                return Err(Error::new(
+                    ErrorKind::UnboundSymbol,
+                    format!("{rname} doesn't export {name}"),
+                ));
+            }
+            if self.get(s).is_some() {
+                return Err(Error::new(
+                    ErrorKind::Clash,
+                    format!("referring {rname}/{name} clashes with {}/{name}", self.name),
+                ));
+            }
+            // Referring the same module twice is harmless.
+            if let Some(other) = self.referrer(s) {
+                if !Rc::ptr_eq(&other, &refer.module) {
+                    return Err(Error::new(
+                        ErrorKind::Clash,
+                        format!(
+                            "{name} is referred from both {} and {rname}",
+                            other.borrow().name
+                        ),
+                    ));
+                }
+            }
+        }
+        self.refers.push(refer);
+        Ok(())
+    }
+
+    // The module an explicit refer brings s in from, if any.
+    pub(crate) fn referrer(&self, s: InternedSymbol) -> Option<Rc<RefCell<Module>>> {
+        self.refers
+            .iter()
+            .find(|r| r.get(s).is_some())
+            .map(|r| r.module.clone())
     }
 
     pub(crate) fn lookup(&self, vm: &Vm, s: InternedSymbol) -> Option<Val> {
         // Order
-        // * closure/let binds (TODO)
         // * binds
-        // * top-level modules
+        // * modules, by qualified name (kcx.util)
         // * refers
+        // * prelude
         //
-        // Note that submodules aren't lookup-able
-        if let Some(v) = self.binds.get(&s) {
+        // Note that closure/let binds never get here: the compiler resolves
+        // those to local slots.
+        if let Some(v) = self.get(s) {
             return Some(v.clone());
         }
-        if let Some(m) = vm.modules.get(&s) {
-            return Some(Val::Module(m.clone()));
+        if let Some(m) = vm.lookup_module(s) {
+            return Some(Val::Module(m));
         }
-        for rm in &self.refers {
-            // XXX "restricted" refer lookup, not a full recurse
-            // Not sure if we want this or otherwise.
-            if let Some(v) = rm.borrow().binds.get(&s) {
-                return Some(v.clone());
-            }
+        // XXX "restricted" refer lookup, not a full recurse
+        // Not sure if we want this or otherwise.
+        if let Some(v) = self.refers.iter().find_map(|r| r.get(s)) {
+            return Some(v);
         }
-        None
+        self.prelude.as_ref()?.borrow().lookup_public(s)
+    }
+
+    // What's bound to s here, if anything; nothing else is consulted.
+    pub(crate) fn get(&self, s: InternedSymbol) -> Option<&Val> {
+        self.slots[*self.binds.get(&s)?].1.as_ref()
+    }
+
+    pub(crate) fn find_slot(&self, s: InternedSymbol) -> Option<usize> {
+        self.binds.get(&s).copied()
+    }
+
+    // s's slot, reserving one if it has none yet.
+    pub(crate) fn slot(&mut self, s: InternedSymbol) -> usize {
+        *self.binds.entry(s).or_insert_with(|| {
+            self.slots.push((s, None));
+            self.slots.len() - 1
+        })
+    }
+
+    // The name a slot was reserved for, and what's bound there.
+    pub(crate) fn load(&self, slot: usize) -> Option<(InternedSymbol, Option<&Val>)> {
+        self.slots.get(slot).map(|(s, v)| (*s, v.as_ref()))
+    }
+
+    pub(crate) fn is_public(&self, s: InternedSymbol) -> bool {
+        self.exports.as_ref().is_none_or(|e| e.contains(&s))
+    }
+
+    // What m/s sees from elsewhere: just our own public binds.
+    pub(crate) fn lookup_public(&self, s: InternedSymbol) -> Option<Val> {
+        self.get(s).filter(|_| self.is_public(s)).cloned()
+    }
+
+    // Whether m/s is, or will be once m's top-level code runs, public in m.
+    // alia compile never runs it, so its defns only get this far.
+    pub(crate) fn declares_public(&self, s: InternedSymbol) -> bool {
+        self.find_slot(s).is_some() && self.is_public(s)
+    }
+
+    // Names with something bound to them.
+    pub(crate) fn names(&self) -> Vec<InternedSymbol> {
+        self.slots
+            .iter()
+            .filter(|(_, v)| v.is_some())
+            .map(|(s, _)| *s)
+            .collect()
+    }
+
+    pub(crate) fn public_names(&self) -> Vec<InternedSymbol> {
+        self.names()
+            .into_iter()
+            .filter(|&s| self.is_public(s))
+            .collect()
+    }
+
+    // Sorted by name.
+    pub(crate) fn children(&self) -> Vec<Rc<RefCell<Module>>> {
+        let mut children = self.submodules.values().cloned().collect::<Vec<_>>();
+        children.sort_by(|a, b| a.borrow().name.cmp(&b.borrow().name));
+        children
     }
 
     pub(super) fn add_bind(&mut self, name: InternedSymbol, target: Val) {
-        match self.binds.insert(name, target) {
-            None => {}
-            Some(_) => panic!("duplicate bind"),
+        if self.get(name).is_some() {
+            panic!("duplicate bind");
         }
+        self.sets(name, target);
     }
 
     pub(crate) fn sets(&mut self, s: InternedSymbol, target: Val) {
-        _ = self.binds.insert(s, target);
+        let slot = self.slot(s);
+        self.slots[slot].1 = Some(target);
+    }
+
+    // sets, for user code: a name we've referred can't be redefined.
+    pub(crate) fn define(&mut self, vm: &Vm, s: InternedSymbol, target: Val) -> Result<(), Error> {
+        let slot = self.slot(s);
+        self.define_slot(vm, slot, target)
+    }
+
+    pub(crate) fn define_slot(&mut self, vm: &Vm, slot: usize, target: Val) -> Result<(), Error> {
+        let Some(&(s, _)) = self.slots.get(slot) else {
+            return Err(Error::new(
+                ErrorKind::Bytecode,
+                format!("bad slot {slot} in {}", self.name),
+            ));
+        };
+        if let Some(other) = self.referrer(s) {
+            let name = vm.resolve(s);
+            return Err(Error::new(
+                ErrorKind::Clash,
+                format!(
+                    "defining {}/{name} clashes with {}/{name}, which is referred",
+                    self.name,
+                    other.borrow().name
+                ),
+            ));
+        }
+        self.slots[slot].1 = Some(target);
+        Ok(())
     }
 
     pub(super) fn add_bind_builtin(&mut self, vm: &mut Vm, name: &str, target: Builtin) {
@@ -1,44 +1,82 @@
 use num_derive::FromPrimitive;
 
-#[derive(FromPrimitive)]
+#[derive(FromPrimitive, Clone, Copy)]
 #[repr(u8)]
 pub(crate) enum Op {
     Nop = 0,
     //
-    ImmediateSymbolBare = 1,
-    ImmediateSymbolWithModule = 2,
+    Constant = 1,
     ImmediateBooleanTrue = 3,
     ImmediateBooleanFalse = 4,
     ImmediateInteger = 5,
-    ImmediateFloat = 6,
-    ImmediateString = 7,
     ConsList = 8,
     ConsVec = 9,
     //
     Drop = 10,
     Eval = 11,
     Call = 12,
+    Return = 13,
+    Dup = 14,
+    ConcatList = 15,
+    ConcatVec = 16,
     //
     JumpRelative = 20,
+    JumpForward = 21,
+    JumpIfFalse = 22,
+    //
+    LoadLocal = 30,
+    StoreLocal = 31,
+    LoadGlobal = 32,
+    StoreGlobal = 33,
+    //
+    MakeFunction = 40,
+    Define = 41,
+    DefineMacro = 42,
+    //
+    PushHandler = 50,
+    PopHandler = 51,
+    Throw = 52,
+    //
+    ReceiveStart = 60,
+    ReceiveNext = 61,
+    ReceiveAccept = 62,
+    Match = 63,
 }
 
 impl std::fmt::Display for Op {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         match self {
             Op::Nop => write!(f, "Nop"),
-            Op::ImmediateSymbolBare => write!(f, "ImmediateSymbolBare"),
-            Op::ImmediateSymbolWithModule => write!(f, "ImmediateSymbolWithModule"),
+            Op::Constant => write!(f, "Constant"),
             Op::ImmediateBooleanTrue => write!(f, "ImmediateBooleanTrue"),
             Op::ImmediateBooleanFalse => write!(f, "ImmediateBooleanFalse"),
             Op::ImmediateInteger => write!(f, "ImmediateInteger"),
-            Op::ImmediateFloat => write!(f, "ImmediateFloat"),
-            Op::ImmediateString => write!(f, "ImmediateString"),
             Op::ConsList => write!(f, "ConsList"),
             Op::ConsVec => write!(f, "ConsVec"),
             Op::Drop => write!(f, "Drop"),
             Op::Eval => write!(f, "Eval"),
             Op::Call => write!(f, "Call"),
+            Op::Return => write!(f, "Return"),
+            Op::Dup => write!(f, "Dup"),
+            Op::ConcatList => write!(f, "ConcatList"),
+            Op::ConcatVec => write!(f, "ConcatVec"),
             Op::JumpRelative => write!(f, "JumpRelative"),
+            Op::JumpForward => write!(f, "JumpForward"),
+            Op::JumpIfFalse => write!(f, "JumpIfFalse"),
+            Op::LoadLocal => write!(f, "LoadLocal"),
+            Op::StoreLocal => write!(f, "StoreLocal"),
+            Op::LoadGlobal => write!(f, "LoadGlobal"),
+            Op::StoreGlobal => write!(f, "StoreGlobal"),
+            Op::MakeFunction => write!(f, "MakeFunction"),
+            Op::Define => write!(f, "Define"),
+            Op::DefineMacro => write!(f, "DefineMacro"),
+            Op::PushHandler => write!(f, "PushHandler"),
+            Op::PopHandler => write!(f, "PopHandler"),
+            Op::Throw => write!(f, "Throw"),
+            Op::ReceiveStart => write!(f, "ReceiveStart"),
+            Op::ReceiveNext => write!(f, "ReceiveNext"),
+            Op::ReceiveAccept => write!(f, "ReceiveAccept"),
+            Op::Match => write!(f, "Match"),
         }
     }
 }
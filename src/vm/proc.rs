@@ -1,113 +1,464 @@
-use num_traits::{FromBytes, FromPrimitive};
+use num_traits::FromPrimitive;
+use std::fmt::Display;
+use std::time::{Duration, Instant};
 use std::{cell::RefCell, rc::Rc};
 
-use super::{BuiltinVal, Module, Op, Val, Vm};
+use super::{
+    varint, BuiltinVal, Code, Error, ErrorKind, FunctionVal, Module, Op, TraceFrame, Val, Vm,
+};
 
 #[derive(Copy, Clone, PartialEq, Eq, Hash, Debug)]
-pub(super) struct Pid(pub(super) usize);
+pub(crate) struct Pid(pub(super) usize);
+
+impl Display for Pid {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "<pid {}>", self.0)
+    }
+}
 
 pub(crate) struct Proc {
-    pub(super) _pid: Pid,
-    pub(super) module: Rc<RefCell<Module>>,
+    pub(super) pid: Pid,
     pub(super) last: Option<Val>,
-    code: Vec<u8>,
-    ip: usize,
+    frames: Vec<Frame>,
     stack: Vec<Val>,
+    handlers: Vec<Handler>,
+    receive: Option<Receive>,
+}
+
+struct Frame {
+    name: String,
+    code: Rc<Code>,
+    // start of the op being executed
+    op: usize,
+    ip: usize,
+    module: Rc<RefCell<Module>>,
+    locals: Vec<Val>,
+}
+
+// Installed by (try ...); where to unwind to when an error is raised.
+struct Handler {
+    frames: usize,
+    stack: usize,
+    ip: usize,
+}
+
+// From ReceiveStart until a message is accepted, or the timeout.
+struct Receive {
+    // Messages already looked at, from the front of the mailbox.
+    seen: usize,
+    deadline: Option<Instant>,
 }
 
 impl Proc {
-    pub(super) fn new(pid: Pid, module: Rc<RefCell<Module>>, code: Vec<u8>) -> Proc {
+    pub(super) fn new(pid: Pid, module: Rc<RefCell<Module>>, code: Code) -> Proc {
+        let name = module.borrow().name.clone();
         Proc {
-            _pid: pid,
-            module,
+            pid,
             last: None,
-            code,
-            ip: 0,
+            frames: vec![Frame {
+                name,
+                code: code.into(),
+                op: 0,
+                ip: 0,
+                module,
+                locals: vec![],
+            }],
             stack: vec![],
+            handlers: vec![],
+            receive: None,
         }
     }
 
+    // A process that calls f with args, and finishes with its result.
+    pub(super) fn spawn(pid: Pid, f: &FunctionVal, args: Vec<Val>) -> Proc {
+        let mut bytes = vec![Op::Call as u8];
+        varint::put(&mut bytes, args.len() as u64 + 1);
+        bytes.push(Op::Drop as u8);
+        let mut proc = Proc::new(pid, f.module.clone(), Code::new(bytes));
+        proc.stack.push(Val::Function(f.clone()));
+        proc.stack.extend(args);
+        proc
+    }
+
+    pub(super) fn module(&self) -> Rc<RefCell<Module>> {
+        self.frame().module.clone()
+    }
+
+    // When a receive waiting on a message stops waiting regardless.
+    pub(super) fn deadline(&self) -> Option<Instant> {
+        self.receive.as_ref().and_then(|r| r.deadline)
+    }
+
     pub(crate) fn step(&mut self, vm: &mut Vm) -> Step {
-        let op = Op::from_u8(self.code[self.ip])
-            .ok_or_else(|| format!("should be valid opcode, was {}", self.code[self.ip]))
-            .unwrap();
-        self.ip += 1;
+        match self.exec_handled(vm, 0) {
+            Ok(step) => step,
+            Err(err) => Step::Errored(err),
+        }
+    }
 
-        match op {
-            Op::Nop => {}
-            Op::ImmediateSymbolBare => {
-                let slen = self.n::<usize>();
-                let s = vm.interns.intern(&self.code[self.ip..self.ip + slen]);
-                self.ip += slen;
+    // Errors are caught by the innermost handler, as long as it was installed
+    // above frame depth `floor`; otherwise they propagate.
+    fn exec_handled(&mut self, vm: &mut Vm, floor: usize) -> Result<Step, Error> {
+        match self.exec(vm) {
+            Ok(step) => Ok(step),
+            Err(mut err) => match self.handlers.last() {
+                // (exit reason) can't be caught; it's the whole process.
+                // Nor can running out of budget, or the handler would just
+                // run out again.
+                Some(h)
+                    if h.frames > floor
+                        && !matches!(err.kind, ErrorKind::Exit | ErrorKind::Budget) =>
+                {
+                    let h = self.handlers.pop().unwrap();
+                    self.frames.truncate(h.frames);
+                    self.stack.truncate(h.stack);
+                    self.frame_mut().ip = h.ip;
+                    let v = err.to_val(vm);
+                    self.stack.push(v);
+                    Ok(Step::Running)
+                }
+                _ => {
+                    if err.trace.is_empty() {
+                        err.trace = self.backtrace();
+                    }
+                    Err(err)
+                }
+            },
+        }
+    }
 
-                self.stack.push(Val::Symbol(None, s));
+    fn exec(&mut self, vm: &mut Vm) -> Result<Step, Error> {
+        vm.spend()?;
+        let frame = self.frame_mut();
+        frame.op = frame.ip;
+        let op = match frame.code.bytes.get(frame.ip) {
+            Some(&b) => Op::from_u8(b)
+                .ok_or_else(|| Error::new(ErrorKind::Bytecode, format!("invalid opcode {b}")))?,
+            // Top-level code with nothing in it, as for a file that's just a
+            // (mod ...): done already.
+            None if frame.code.bytes.is_empty() => {
+                self.last.get_or_insert_with(Val::unit);
+                return Ok(Step::Finished);
             }
-            Op::ImmediateSymbolWithModule => {
-                let mlen = self.n::<usize>();
-                let m = vm.interns.intern(&self.code[self.ip..self.ip + mlen]);
-                self.ip += mlen;
+            None => return Err(Error::new(ErrorKind::Bytecode, "ran off the end of code")),
+        };
+        self.frame_mut().ip += 1;
 
-                let slen = self.n::<usize>();
-                let s = vm.interns.intern(&self.code[self.ip..self.ip + slen]);
-                self.ip += slen;
-
-                self.stack.push(Val::Symbol(Some(m), s));
+        match op {
+            Op::Nop => {}
+            Op::Constant => {
+                let v = self.constant()?;
+                self.stack.push(v);
             }
             Op::ImmediateBooleanTrue => self.stack.push(Val::Boolean(true)),
             Op::ImmediateBooleanFalse => self.stack.push(Val::Boolean(false)),
             Op::ImmediateInteger => {
-                let i = self.n::<i64>();
+                let i = self.i()?;
                 self.stack.push(Val::Integer(i));
             }
-            Op::ImmediateFloat => {
-                let f = self.n::<f64>();
-                self.stack.push(Val::Float(f));
-            }
-            Op::ImmediateString => {
-                let n = self.n::<usize>();
-                let str = String::from_utf8(self.code[self.ip..self.ip + n].to_vec())
-                    .expect("should be valid utf-8");
-                self.stack.push(Val::String(str));
-                self.ip += n;
-            }
             Op::ConsList => {
-                let n = self.n::<usize>();
-                let v = self.stack.split_off(self.stack.len() - n);
+                let n = self.n()?;
+                let v = self.pop_n(n)?;
                 self.stack.push(Val::List(v));
             }
             Op::ConsVec => {
-                let n = self.n::<usize>();
-                let v = self.stack.split_off(self.stack.len() - n);
+                let n = self.n()?;
+                let v = self.pop_n(n)?;
                 self.stack.push(Val::Vec(v));
             }
+            Op::ConcatList | Op::ConcatVec => {
+                // For ~@: the top n lists/vectors, joined.
+                let n = self.n()?;
+                let mut joined = vec![];
+                for v in self.pop_n(n)? {
+                    match v {
+                        Val::List(vs) | Val::Vec(vs) => joined.extend(vs),
+                        v => {
+                            return Err(Error::new(
+                                ErrorKind::Type,
+                                format!("trying to splice {}", v.format(vm)),
+                            ))
+                        }
+                    }
+                }
+                self.stack.push(match op {
+                    Op::ConcatList => Val::List(joined),
+                    _ => Val::Vec(joined),
+                });
+            }
             Op::Drop => {
-                self.last = Some(self.stack.pop().expect("stack should not be empty"));
+                self.last = Some(self.pop()?);
             }
             Op::Eval => {
-                let form = self.stack.pop().expect("stack should not be empty");
-                let result = self.eval(vm, &form);
+                let form = self.pop()?;
+                let result = self.eval(vm, &form)?;
                 self.stack.push(result);
             }
             Op::Call => {
-                let n = self.n::<usize>() - 1; // includes callee
-                let args = self.stack.split_off(self.stack.len() - n);
-                let callee = self.stack.pop().unwrap();
-                let result = self.call(vm, &callee, &args);
-                self.stack.push(result);
+                let n = self.n()?;
+                let mut args = self.pop_n(n)?; // includes callee
+                if args.is_empty() {
+                    return Err(Error::new(ErrorKind::Bytecode, "call without callee"));
+                }
+                let callee = args.remove(0);
+                match callee {
+                    // No need to recurse: the callee's frame just runs next.
+                    Val::Function(f) => self.enter(&f, args)?,
+                    _ => {
+                        let result = self.call(vm, &callee, args)?;
+                        self.stack.push(result);
+                    }
+                }
+            }
+            Op::Return => {
+                let v = self.pop()?;
+                if self.frames.len() == 1 {
+                    return Err(Error::new(ErrorKind::Bytecode, "return from top-level"));
+                }
+                self.frames.pop();
+                self.stack.push(v);
+                // Whoever called us decides what happens next, even if that
+                // was apply with nothing left to run.
+                return Ok(Step::Running);
+            }
+            Op::Dup => {
+                let v = self.pop()?;
+                self.stack.push(v.clone());
+                self.stack.push(v);
             }
             Op::JumpRelative => {
-                let sip = self.ip;
-                let n = self.n::<usize>();
-                // So far this is a backwards jump only, relative to the opcode.
-                self.ip = sip - n;
+                let sip = self.frame().ip - 1;
+                let n = self.n()?;
+                // Backwards jump, relative to the opcode.
+                self.jump(sip.checked_sub(n))?;
+            }
+            Op::JumpForward => {
+                let sip = self.frame().ip - 1;
+                let n = self.n()?;
+                // Forwards jump, relative to the opcode.
+                self.jump(sip.checked_add(n))?;
+            }
+            Op::JumpIfFalse => {
+                let sip = self.frame().ip - 1;
+                let n = self.n()?;
+                let v = self.pop()?;
+                if !v.truthy() {
+                    self.jump(sip.checked_add(n))?;
+                }
+            }
+            Op::LoadLocal => {
+                let slot = self.n()?;
+                let v = self.local(slot)?;
+                self.stack.push(v);
+            }
+            Op::StoreLocal => {
+                let slot = self.n()?;
+                let v = self.pop()?;
+                let locals = &mut self.frame_mut().locals;
+                if slot >= locals.len() {
+                    locals.resize(slot + 1, Val::unit());
+                }
+                locals[slot] = v;
+            }
+            Op::LoadGlobal => {
+                let (module, slot) = self.global()?;
+                let own = Rc::ptr_eq(&module, &self.frame().module);
+                let module = module.borrow();
+                let v = match module.load(slot) {
+                    Some((_, Some(v))) => v.clone(),
+                    // Reserved by the compiler but never bound.  If it's our
+                    // own, the name may have been referred since, or be a
+                    // module; look it up the long way.
+                    Some((s, None)) if own => {
+                        drop(module);
+                        self.eval(vm, &Val::Symbol(None, s))?
+                    }
+                    Some((s, None)) => {
+                        return Err(Error::new(
+                            ErrorKind::UnboundSymbol,
+                            format!("{}/{}", module.name, vm.resolve(s)),
+                        ))
+                    }
+                    None => {
+                        return Err(Error::new(
+                            ErrorKind::Bytecode,
+                            format!("bad slot {slot} in {}", module.name),
+                        ))
+                    }
+                };
+                self.stack.push(v);
+            }
+            Op::StoreGlobal => {
+                let (module, slot) = self.global()?;
+                let v = self.pop()?;
+                module.borrow_mut().define_slot(vm, slot, v.clone())?;
+                self.stack.push(v);
+            }
+            Op::MakeFunction => {
+                let name = match self.constant()? {
+                    Val::String(name) => name,
+                    _ => {
+                        return Err(Error::new(
+                            ErrorKind::Bytecode,
+                            "MakeFunction expects a string name",
+                        ))
+                    }
+                };
+                let nparams = self.n()?;
+                let ncaptures = self.n()?;
+                let mut captures = vec![];
+                for _ in 0..ncaptures {
+                    let callee_slot = self.n()?;
+                    let slot = self.n()?;
+                    captures.push((callee_slot, self.local(slot)?));
+                }
+                let len = self.n()?;
+                let frame = self.frame_mut();
+                let entry = frame.ip;
+                if entry + len > frame.code.bytes.len() {
+                    return Err(Error::new(
+                        ErrorKind::Bytecode,
+                        "function body out of range",
+                    ));
+                }
+                frame.ip += len;
+                let f = Val::Function(FunctionVal {
+                    name,
+                    code: frame.code.clone(),
+                    entry,
+                    nparams,
+                    captures,
+                    module: frame.module.clone(),
+                });
+                self.stack.push(f);
+            }
+            Op::Define | Op::DefineMacro => {
+                let v = match (op, self.pop()?) {
+                    (Op::DefineMacro, Val::Function(f)) => Val::Macro(f),
+                    (Op::DefineMacro, _) => {
+                        return Err(Error::new(
+                            ErrorKind::Bytecode,
+                            "DefineMacro expects a function",
+                        ))
+                    }
+                    (_, v) => v,
+                };
+                let s = match self.pop()? {
+                    Val::Symbol(None, s) => s,
+                    _ => {
+                        return Err(Error::new(
+                            ErrorKind::Bytecode,
+                            "Define expects a bare symbol",
+                        ))
+                    }
+                };
+                self.module().borrow_mut().define(vm, s, v.clone())?;
+                self.stack.push(v);
+            }
+            Op::PushHandler => {
+                let sip = self.frame().ip - 1;
+                let n = self.n()?;
+                let ip = sip
+                    .checked_add(n)
+                    .filter(|&ip| ip <= self.frame().code.bytes.len())
+                    .ok_or_else(|| Error::new(ErrorKind::Bytecode, "handler out of range"))?;
+                self.handlers.push(Handler {
+                    frames: self.frames.len(),
+                    stack: self.stack.len(),
+                    ip,
+                });
+            }
+            Op::PopHandler => {
+                if self.handlers.pop().is_none() {
+                    return Err(Error::new(ErrorKind::Bytecode, "no handler to pop"));
+                }
+            }
+            Op::Throw => {
+                let v = self.pop()?;
+                return Err(Error::thrown(vm, v));
+            }
+            Op::ReceiveStart => {
+                let deadline = match self.pop()? {
+                    Val::Boolean(false) => None,
+                    Val::Integer(ms) if ms >= 0 => {
+                        Some(Instant::now() + Duration::from_millis(ms as u64))
+                    }
+                    v => {
+                        return Err(Error::new(
+                            ErrorKind::Type,
+                            format!(
+                                "receive timeout should be milliseconds, not {}",
+                                v.format(vm)
+                            ),
+                        ))
+                    }
+                };
+                self.receive = Some(Receive { seen: 0, deadline });
+            }
+            Op::ReceiveNext => {
+                let sip = self.frame().ip - 1;
+                let n = self.n()?;
+                let Some(r) = self.receive.as_mut() else {
+                    return Err(Error::new(
+                        ErrorKind::Bytecode,
+                        "ReceiveNext outside receive",
+                    ));
+                };
+                let mailbox = vm.mailboxes.get(&self.pid);
+                if let Some(msg) = mailbox.and_then(|m| m.get(r.seen)) {
+                    r.seen += 1;
+                    self.stack.push(msg.clone());
+                } else if r.deadline.is_some_and(|d| Instant::now() >= d) {
+                    self.receive = None;
+                    self.jump(sip.checked_add(n))?;
+                } else {
+                    // Back to this op when something's sent, or the deadline
+                    // passes.
+                    self.frame_mut().ip = sip;
+                    return Ok(Step::Waiting);
+                }
+            }
+            Op::ReceiveAccept => {
+                self.pop()?;
+                let seen = match self.receive.take() {
+                    Some(Receive { seen, .. }) if seen > 0 => seen,
+                    _ => {
+                        return Err(Error::new(
+                            ErrorKind::Bytecode,
+                            "ReceiveAccept without a message",
+                        ))
+                    }
+                };
+                if let Some(mailbox) = vm.mailboxes.get_mut(&self.pid) {
+                    mailbox.remove(seen - 1);
+                }
+            }
+            Op::Match => {
+                let pattern = self.constant()?;
+                let slot = self.n()?;
+                let v = self.pop()?;
+                let mut binds = vec![];
+                let matched = pattern.matches(vm, &v, &mut binds);
+                if matched {
+                    let locals = &mut self.frame_mut().locals;
+                    if slot + binds.len() > locals.len() {
+                        locals.resize(slot + binds.len(), Val::unit());
+                    }
+                    for (i, v) in binds.into_iter().enumerate() {
+                        locals[slot + i] = v;
+                    }
+                }
+                self.stack.push(Val::Boolean(matched));
             }
         }
 
-        if self.ip < self.code.len() {
-            Step::Running
+        let frame = self.frame();
+        if self.frames.len() > 1 || frame.ip < frame.code.bytes.len() {
+            Ok(Step::Running)
+        } else if !self.stack.is_empty() {
+            Err(Error::new(ErrorKind::Bytecode, "stack not empty at end"))
         } else {
-            assert_eq!(0, self.stack.len(), "stack not empty at end");
-            Step::Finished
+            Ok(Step::Finished)
         }
     }
 
@@ -118,64 +469,230 @@ impl Proc {
     // ( |    w     | )     \                                       /
     //   ¯\________/ ¯       ¯¯¯¯¯¯¯¯¯¯¯¯¯¯¯¯¯¯¯¯¯¯¯¯¯¯¯¯¯¯¯¯¯¯¯¯¯¯¯
     //
-    pub(super) fn eval(&mut self, vm: &mut Vm, form: &Val) -> Val {
+    pub(super) fn eval(&mut self, vm: &mut Vm, form: &Val) -> Result<Val, Error> {
         match form {
             &Val::Symbol(None, s) => {
-                let self_module = self.module.borrow();
-                match self_module.lookup(vm, s) {
-                    Some(v) => v,
-                    None => Val::Symbol(None, vm.interns.intern("panic TODO!".as_bytes())),
-                }
+                let module = self.module();
+                let self_module = module.borrow();
+                self_module
+                    .lookup(vm, s)
+                    .ok_or_else(|| Error::new(ErrorKind::UnboundSymbol, vm.resolve(s).to_string()))
             }
             &Val::Symbol(Some(m), s) => {
-                let module = match vm.lookup_module(m) {
-                    Some(v) => v,
-                    None => return Val::Symbol(None, vm.interns.intern("panic TODO!".as_bytes())),
-                };
+                let module = vm
+                    .resolve_module(&self.module().borrow(), m)
+                    .ok_or_else(|| {
+                        Error::new(
+                            ErrorKind::UnboundSymbol,
+                            format!("unknown module {}", vm.resolve(m)),
+                        )
+                    })?;
                 let module = module.borrow();
-                match module.lookup(vm, s) {
-                    Some(v) => v,
-                    None => Val::Symbol(None, vm.interns.intern("panic TODO!".as_bytes())),
+                match module.lookup_public(s) {
+                    Some(v) => Ok(v),
+                    None if module.get(s).is_some() => Err(Error::new(
+                        ErrorKind::UnboundSymbol,
+                        format!("{} is private to {}", form.format(vm), module.name),
+                    )),
+                    None => Err(Error::new(ErrorKind::UnboundSymbol, form.format(vm))),
                 }
             }
             Val::Boolean(_) | Val::Integer(_) | Val::Float(_) | Val::String(_) => {
                 // primitives evaluate to themselves
-                form.clone()
+                Ok(form.clone())
             }
             Val::List(ns) => {
                 let head = match ns.len() {
                     0 => {
                         // empty cons evaluates to itself
-                        return form.clone();
+                        return Ok(form.clone());
                     }
                     _ => &ns[0],
                 };
-                let callee = self.eval(vm, head);
-                self.call(vm, &callee, &ns[1..])
+                let callee = self.eval(vm, head)?;
+                let args = ns[1..]
+                    .iter()
+                    .map(|a| self.eval(vm, a))
+                    .collect::<Result<_, _>>()?;
+                self.call(vm, &callee, args)
             }
-            Val::Vec(ns) => Val::Vec(ns.iter().map(|f| self.eval(vm, f)).collect::<Vec<_>>()),
-            Val::Builtin(..) | Val::Module(..) => {
-                // builtins and modules evaluate to themselves
-                form.clone()
+            Val::Vec(ns) => Ok(Val::Vec(
+                ns.iter()
+                    .map(|f| self.eval(vm, f))
+                    .collect::<Result<Vec<_>, _>>()?,
+            )),
+            Val::Builtin(..)
+            | Val::Function(..)
+            | Val::Macro(..)
+            | Val::Module(..)
+            | Val::Pid(..) => {
+                // builtins, functions, macros, modules and pids evaluate to
+                // themselves
+                Ok(form.clone())
             }
         }
     }
 
-    fn call(&mut self, vm: &mut Vm, callee: &Val, args: &[Val]) -> Val {
+    // Arguments are already evaluated.
+    fn call(&mut self, vm: &mut Vm, callee: &Val, args: Vec<Val>) -> Result<Val, Error> {
         match callee {
-            Val::Builtin(BuiltinVal { code, .. }) => code(vm, self, args),
-            _ => panic!("can't call {}", callee.format(vm)),
+            Val::Builtin(BuiltinVal { code, .. }) => code(vm, self, &args),
+            Val::Function(f) => self.apply(vm, f, args),
+            Val::Macro(f) => Err(Error::new(
+                ErrorKind::NotCallable,
+                format!(
+                    "can't call macro {}/{} at runtime; it should have been expanded",
+                    f.module.borrow().name,
+                    f.name
+                ),
+            )),
+            _ => Err(Error::new(
+                ErrorKind::NotCallable,
+                format!("can't call {}", callee.format(vm)),
+            )),
         }
     }
 
-    fn n<T: FromBytes<Bytes = [u8; 8]>>(&mut self) -> T {
-        let u = T::from_le_bytes(self.code[self.ip..self.ip + 8].try_into().unwrap());
-        self.ip += 8;
-        u
+    // We're being called from Rust (a builtin, eval, or Vm::call), so run the
+    // function to completion right here.  Arguments are already evaluated.
+    pub(super) fn apply(
+        &mut self,
+        vm: &mut Vm,
+        f: &FunctionVal,
+        args: Vec<Val>,
+    ) -> Result<Val, Error> {
+        let depth = self.frames.len();
+        self.enter(f, args)?;
+        while self.frames.len() > depth {
+            if let Step::Waiting = self.exec_handled(vm, depth)? {
+                // We can't yield from here, so let everyone else have a turn
+                // instead, and look again.
+                vm.idle(self.deadline())?;
+            }
+        }
+        self.pop()
+    }
+
+    fn enter(&mut self, f: &FunctionVal, mut locals: Vec<Val>) -> Result<(), Error> {
+        if locals.len() != f.nparams {
+            return Err(Error::new(
+                ErrorKind::Arity,
+                format!(
+                    "{} takes {} argument(s), got {}",
+                    f.name,
+                    f.nparams,
+                    locals.len()
+                ),
+            ));
+        }
+        for (slot, v) in &f.captures {
+            if *slot >= locals.len() {
+                locals.resize(slot + 1, Val::unit());
+            }
+            locals[*slot] = v.clone();
+        }
+        self.frames.push(Frame {
+            name: format!("{}/{}", f.module.borrow().name, f.name),
+            code: f.code.clone(),
+            op: f.entry,
+            ip: f.entry,
+            module: f.module.clone(),
+            locals,
+        });
+        Ok(())
+    }
+
+    // Innermost frame first.
+    pub(super) fn backtrace(&self) -> Vec<TraceFrame> {
+        self.frames
+            .iter()
+            .rev()
+            .map(|f| TraceFrame {
+                name: f.name.clone(),
+                file: f.code.file.clone(),
+                range: f.code.range_at(f.op),
+            })
+            .collect()
+    }
+
+    fn frame(&self) -> &Frame {
+        self.frames.last().expect("proc should have a frame")
+    }
+
+    fn frame_mut(&mut self) -> &mut Frame {
+        self.frames.last_mut().expect("proc should have a frame")
+    }
+
+    fn pop(&mut self) -> Result<Val, Error> {
+        self.stack
+            .pop()
+            .ok_or_else(|| Error::new(ErrorKind::Bytecode, "stack underflow"))
+    }
+
+    fn pop_n(&mut self, n: usize) -> Result<Vec<Val>, Error> {
+        if n > self.stack.len() {
+            return Err(Error::new(ErrorKind::Bytecode, "stack underflow"));
+        }
+        Ok(self.stack.split_off(self.stack.len() - n))
+    }
+
+    fn local(&self, slot: usize) -> Result<Val, Error> {
+        self.frame()
+            .locals
+            .get(slot)
+            .cloned()
+            .ok_or_else(|| Error::new(ErrorKind::Bytecode, format!("bad local slot {slot}")))
+    }
+
+    fn global(&mut self) -> Result<(Rc<RefCell<Module>>, usize), Error> {
+        let i = self.n()?;
+        self.frame()
+            .code
+            .links
+            .get(i)
+            .cloned()
+            .ok_or_else(|| Error::new(ErrorKind::Bytecode, format!("bad global {i}")))
+    }
+
+    fn jump(&mut self, target: Option<usize>) -> Result<(), Error> {
+        let frame = self.frame_mut();
+        match target {
+            Some(ip) if ip <= frame.code.bytes.len() => {
+                frame.ip = ip;
+                Ok(())
+            }
+            _ => Err(Error::new(ErrorKind::Bytecode, "jump out of range")),
+        }
+    }
+
+    fn n(&mut self) -> Result<usize, Error> {
+        let frame = self.frame_mut();
+        varint::get(&frame.code.bytes, &mut frame.ip)
+            .and_then(|n| usize::try_from(n).ok())
+            .ok_or_else(|| Error::new(ErrorKind::Bytecode, "bad operand"))
+    }
+
+    fn i(&mut self) -> Result<i64, Error> {
+        let frame = self.frame_mut();
+        varint::get_signed(&frame.code.bytes, &mut frame.ip)
+            .ok_or_else(|| Error::new(ErrorKind::Bytecode, "bad operand"))
+    }
+
+    fn constant(&mut self) -> Result<Val, Error> {
+        let i = self.n()?;
+        self.frame()
+            .code
+            .pool
+            .get(i)
+            .cloned()
+            .ok_or_else(|| Error::new(ErrorKind::Bytecode, format!("bad constant {i}")))
     }
 }
 
 pub(crate) enum Step {
     Running,
+    // In receive, with nothing to receive yet.
+    Waiting,
     Finished,
+    Errored(Error),
 }
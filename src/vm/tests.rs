@@ -0,0 +1,554 @@
+#![cfg(test)]
+
+use std::cell::RefCell;
+use std::rc::Rc;
+
+use super::{varint, Code, Const, ErrorKind, Module, Val, Vm};
+use crate::compiler::Compiler;
+use crate::parser::Document;
+
+// As the loader does it: globals resolved to slots in module.
+fn compile_in(vm: &mut Vm, module: Rc<RefCell<Module>>, code: &str) -> Code {
+    let doc = code.parse::<Document>().unwrap();
+    let mut c = Compiler::in_module(vm, module);
+    c.doc(&doc);
+    c.finish().unwrap()
+}
+
+fn assert_evals(code: &str, expected: &str) {
+    let mut vm = Vm::new();
+    let module = vm.anonymous_module("*test*");
+    let code = compile_in(&mut vm, module.clone(), code);
+    let result = vm.run_to_completion(module, code).unwrap();
+    assert_eq!(expected, result.format(&vm));
+}
+
+fn assert_errors(code: &str, expected: ErrorKind) {
+    let mut vm = Vm::new();
+    let module = vm.anonymous_module("*test*");
+    let code = compile_in(&mut vm, module.clone(), code);
+    match vm.run_to_completion(module, code) {
+        Ok(val) => panic!("expected {expected}, got {}", val.format(&vm)),
+        Err(err) => assert_eq!(expected, err.kind),
+    }
+}
+
+#[test]
+fn functions_call_functions() {
+    assert_evals(
+        "(defn f [x] [x x]) (defn g [y: int] -> vec (f y)) (g 3)",
+        "[3 3]",
+    );
+}
+
+#[test]
+fn closures_capture() {
+    assert_evals("(defn pair [a] (fn [b] [a b])) ((pair 1) 2)", "[1 2]");
+    assert_evals("((((fn [a] (fn [b] (fn [c] [a b c]))) 1) 2) 3)", "[1 2 3]");
+}
+
+#[test]
+fn let_shadows() {
+    assert_evals("(let [x 1 y [x x] x 2] [x y])", "[2 [1 1]]");
+    assert_evals("(let [x 1] [(let [x 2] x) x])", "[2 1]");
+    assert_evals(
+        "(defn f [x] (let [g (fn [] x) x 5] [(g) x])) (f 3)",
+        "[3 5]",
+    );
+}
+
+#[test]
+fn conditionals() {
+    assert_evals("[(if true 1 2) (if false 1 2) (if false 1)]", "[1 2 ()]");
+    assert_evals(
+        "[(when true 1 2) (when false 1) (unless false 3)]",
+        "[2 () 3]",
+    );
+    assert_evals(
+        "(defn c [x y] (cond x 1 y 2)) [(c true false) (c false true) (c false false)]",
+        "[1 2 ()]",
+    );
+    assert_evals(
+        "[(and) (and 1 2) (and 1 false 2) (or) (or false 2) (or false (when false 1))]",
+        "[true 2 false false 2 ()]",
+    );
+}
+
+#[test]
+fn special_forms() {
+    assert_evals("(do (set x 1) [x 'x (quote (a b))])", "[1 x (a b)]");
+    // Arguments are evaluated left to right before the call.
+    assert_evals("(list (set a 1) (set b [a a]) b)", "(1 [1 1] [1 1])");
+    assert_evals("(defn f [x] (set g x)) [(f 2) g]", "[2 2]");
+}
+
+#[test]
+fn globals_resolve_to_slots() {
+    assert_evals("(defn f [] (g)) (defn g [] 1) (f)", "1");
+    // Declared up front, so f gets ours rather than builtins/list.
+    assert_evals("(defn f [x] (list x)) (defn list [x] [x]) (f 1)", "[1]");
+    assert_evals(
+        "(defn f [] later) (eval \"(set later 5)\") [(f) later]",
+        "[5 5]",
+    );
+    assert_errors("(defn f [] later) (f)", ErrorKind::UnboundSymbol);
+}
+
+#[test]
+fn runtime_errors() {
+    assert_errors("(print nope)", ErrorKind::UnboundSymbol);
+    assert_errors("(nope/print 1)", ErrorKind::UnboundSymbol);
+    assert_errors("((fn [x] x))", ErrorKind::Arity);
+    assert_errors("(nth 1 2)", ErrorKind::Type);
+    assert_errors("(1 2)", ErrorKind::NotCallable);
+    assert_errors("(eval \"(\")", ErrorKind::Syntax);
+}
+
+#[test]
+fn bad_bytecode_errors() {
+    let mut vm = Vm::new();
+    let module = vm.anonymous_module("*test*");
+    for code in [vec![0xff], vec![crate::vm::Op::Drop as u8], vec![5, 0x80]] {
+        let err = vm
+            .run_to_completion(module.clone(), code.into())
+            .err()
+            .expect("bad bytecode should error");
+        assert_eq!(ErrorKind::Bytecode, err.kind);
+    }
+}
+
+#[test]
+fn verifier_rejects_bad_code() {
+    use crate::vm::Op::*;
+    let cases: &[(&[u8], &str)] = &[
+        (&[0xff], "invalid opcode 255 at offset 0"),
+        (&[Drop as u8], "stack underflow at offset 0"),
+        (
+            &[ImmediateInteger as u8, 0x80],
+            "bad operand to ImmediateInteger at offset 0",
+        ),
+        (
+            &[Constant as u8, 1, Drop as u8],
+            "bad constant 1 at offset 0",
+        ),
+        (
+            &[ImmediateBooleanTrue as u8],
+            "leaves 1 value(s) on the stack at the end at offset 0",
+        ),
+        (
+            &[JumpForward as u8, 1, Nop as u8],
+            "jump to 1, which isn't an op here at offset 0",
+        ),
+        (
+            &[
+                ImmediateBooleanTrue as u8,
+                ImmediateBooleanTrue as u8,
+                JumpIfFalse as u8,
+                3,
+                ImmediateBooleanTrue as u8,
+                Drop as u8,
+            ],
+            "reaches 5 with stack depth 2, but elsewhere it's 1 at offset 4",
+        ),
+        (
+            &[
+                MakeFunction as u8,
+                0,
+                0,
+                0,
+                1,
+                ImmediateBooleanTrue as u8,
+                Drop as u8,
+            ],
+            "runs off the end of the function at offset 5",
+        ),
+        (&[Nop as u8], "finished without a value"),
+    ];
+    let mut vm = Vm::new();
+    let module = vm.anonymous_module("*test*");
+    for (bytes, expected) in cases {
+        let mut code = Code::new(bytes.to_vec());
+        code.consts = vec![Const::String("f".to_string())];
+        let err = vm
+            .run_to_completion(module.clone(), code)
+            .err()
+            .unwrap_or_else(|| panic!("{bytes:?} should be refused"));
+        assert_eq!(ErrorKind::Bytecode, err.kind);
+        assert!(err.message.starts_with(expected), "{}", err.message);
+    }
+}
+
+#[test]
+fn varints_roundtrip() {
+    for n in [0, 1, 127, 128, 300, u64::MAX] {
+        let mut out = vec![];
+        varint::put(&mut out, n);
+        let mut at = 0;
+        assert_eq!(Some(n), varint::get(&out, &mut at));
+        assert_eq!(out.len(), at);
+    }
+    for i in [0, 1, -1, 63, 64, -64, -65, i64::MIN, i64::MAX] {
+        let mut out = vec![];
+        varint::put_signed(&mut out, i);
+        let mut at = 0;
+        assert_eq!(Some(i), varint::get_signed(&out, &mut at));
+        assert_eq!(out.len(), at);
+    }
+    let mut out = [0; varint::JUMP_WIDTH];
+    varint::put_padded(&mut out, 5).unwrap();
+    assert_eq!(Some(5), varint::get(&out, &mut 0));
+    assert!(varint::put_padded(&mut out, 1 << 28).is_none());
+    assert_eq!(None, varint::get(&[0xff; 11], &mut 0));
+}
+
+#[test]
+fn try_catch_finally() {
+    assert_evals("(try (nope) (catch e e))", "[unbound-symbol nope]");
+    assert_evals(
+        "(try (nope) (catch e (= (nth e 0) 'unbound-symbol)))",
+        "true",
+    );
+    assert_evals("(try (throw 5) (catch e [e 1]))", "[5 1]");
+    assert_evals("(try 1 (catch e 2))", "1");
+    assert_evals(
+        "(try (try (throw 1) (finally (set y 2))) (catch e [e y]))",
+        "[1 2]",
+    );
+    assert_evals(
+        "(try (try (throw 1) (catch e (throw [e e])) (finally (set y 3))) (catch e [e y]))",
+        "[[1 1] 3]",
+    );
+}
+
+#[test]
+fn throw_unwinds_frames() {
+    assert_evals(
+        "(defn g [] (throw 'oops)) (defn f [] [(g)]) (try (f) (catch e e))",
+        "oops",
+    );
+    // across a builtin re-entering the VM
+    assert_evals(
+        "(defn g [] (throw 'oops)) (try (print (g)) (catch e e))",
+        "oops",
+    );
+    assert_evals(
+        "(defn g [] (try (throw 1) (catch e (+ e)))) (try (print (g)) (catch e (nth e 0)))",
+        "unbound-symbol",
+    );
+    assert_errors("(throw 1)", ErrorKind::Thrown);
+}
+
+#[test]
+fn errors_carry_backtraces() {
+    let mut vm = Vm::new();
+    let module = vm.anonymous_module("*test*");
+    let mut code = "(defn g [] (nope))\n(defn f []\n  (g))\n(f)"
+        .parse::<Document>()
+        .unwrap()
+        .compile()
+        .unwrap();
+    code.file = Some("test.lia".to_string());
+    let err = vm
+        .run_to_completion(module, code)
+        .err()
+        .expect("should error");
+    let trace = err.trace.iter().map(|f| f.to_string()).collect::<Vec<_>>();
+    assert_eq!(
+        vec![
+            "*test*/g (test.lia:1:13)",
+            "*test*/f (test.lia:3:3)",
+            "*test* (test.lia:4:1)",
+        ],
+        trace
+    );
+}
+
+#[test]
+fn call_from_rust() {
+    let mut vm = Vm::new();
+    let module = vm.named_module("called");
+    let code = "(defn f [a b] [b a])"
+        .parse::<Document>()
+        .unwrap()
+        .compile()
+        .unwrap();
+    vm.run_to_completion(module.clone(), code).ok().unwrap();
+    let sf = vm.intern("f");
+    let Some(Val::Function(f)) = module.borrow().lookup(&vm, sf) else {
+        panic!("f should be a function");
+    };
+    let result = vm
+        .call(&f, vec![Val::Integer(1), Val::String("x".to_string())])
+        .ok()
+        .unwrap();
+    assert_eq!("[x 1]", result.format(&vm));
+}
+
+#[test]
+fn modules_nest() {
+    let mut vm = Vm::new();
+    let x = vm.named_module("a.b.x");
+    vm.named_module("a.b.y.z");
+    let code = "(defn f [] 1)"
+        .parse::<Document>()
+        .unwrap()
+        .compile()
+        .unwrap();
+    vm.run_to_completion(x, code).ok().unwrap();
+
+    let module = vm.anonymous_module("*test*");
+    let code = "[a.b (a.b.x/f) (submodules a) (submodules a.b)]"
+        .parse::<Document>()
+        .unwrap()
+        .compile()
+        .unwrap();
+    let result = vm.run_to_completion(module, code).ok().unwrap();
+    assert_eq!(
+        "[<module a.b> 1 [<module a.b>] [<module a.b.x> <module a.b.y>]]",
+        result.format(&vm)
+    );
+
+    let sa = vm.intern("a");
+    let sbz = vm.intern("b.y.z");
+    assert!(vm.lookup_module(sbz).is_none());
+    let a = vm.lookup_module(sa).unwrap();
+    let srel = vm.intern(".b.y.z");
+    assert_eq!(
+        "a.b.y.z",
+        vm.resolve_module(&a.borrow(), srel).unwrap().borrow().name
+    );
+}
+
+#[test]
+fn refers_and_aliases() {
+    let mut vm = Vm::new();
+    for (name, code) in [
+        ("a.lib", "(defn f [] 1) (defn g [] 2)"),
+        ("other", "(defn f [] 3)"),
+    ] {
+        let module = vm.named_module(name);
+        let code = code.parse::<Document>().unwrap().compile().unwrap();
+        vm.run_to_completion(module, code).ok().unwrap();
+    }
+    let slib = vm.intern("a.lib");
+    let lib = vm.lookup_module(slib).unwrap();
+    lib.borrow_mut().exports = Some([vm.intern("f")].into());
+
+    let run = |vm: &mut Vm, code: &str| {
+        let module = vm.anonymous_module("*test*");
+        let code = code.parse::<Document>().unwrap().compile().unwrap();
+        vm.run_to_completion(module, code)
+    };
+    for (code, expected) in [
+        ("(refer a.lib only: [f]) (f)", "1"),
+        ("(refer a.lib) (refer a.lib) (f)", "1"),
+        ("(alias l a.lib) (l/f)", "1"),
+        ("(import a.lib) (lib/f)", "1"),
+        ("(defn print [] 4) (print)", "4"),
+    ] {
+        let result = run(&mut vm, code).unwrap_or_else(|e| panic!("{code}: {e}"));
+        assert_eq!(expected, result.format(&vm), "{code}");
+    }
+    for (code, kind, message) in [
+        (
+            "(a.lib/g)",
+            ErrorKind::UnboundSymbol,
+            "a.lib/g is private to a.lib",
+        ),
+        (
+            "(refer a.lib only: [g])",
+            ErrorKind::UnboundSymbol,
+            "a.lib doesn't export g",
+        ),
+        (
+            "(defn f [] 5) (refer a.lib)",
+            ErrorKind::Clash,
+            "referring a.lib/f clashes with *test*/f",
+        ),
+        (
+            "(refer a.lib) (defn f [] 5)",
+            ErrorKind::Clash,
+            "defining *test*/f clashes with a.lib/f, which is referred",
+        ),
+        (
+            "(refer a.lib) (refer other)",
+            ErrorKind::Clash,
+            "f is referred from both a.lib and other",
+        ),
+        (
+            "(alias l a.lib) (alias l other)",
+            ErrorKind::Clash,
+            "l is already an alias for a.lib",
+        ),
+        (
+            "(refer a.lib nope: [f])",
+            ErrorKind::Syntax,
+            "refer doesn't take (quote nope)",
+        ),
+    ] {
+        let err = run(&mut vm, code).err().expect(code);
+        assert_eq!((kind, message), (err.kind, err.message.as_str()), "{code}");
+    }
+}
+
+#[test]
+fn macros_expand() {
+    assert_evals(
+        "(defmacro unless2 [c x] (list 'if c () x)) [(unless2 false 1) (unless2 true 1)]",
+        "[1 ()]",
+    );
+    assert_evals(
+        "(defmacro twice [x] (list 'do2 x x)) (defmacro do2 [a b] [a b]) (twice 3)",
+        "[3 3]",
+    );
+    assert_evals(
+        "(defmacro m [x] (list 'quote x)) [(macroexpand-1 '(m (m y))) (macroexpand '(m y)) (macroexpand 'z)]",
+        "[(quote (m y)) (quote y) z]",
+    );
+    // Locals shadow macros.
+    assert_evals("(defmacro m [] 1) (let [m (fn [] 2)] (m))", "2");
+}
+
+#[test]
+fn quasiquote() {
+    assert_evals("(let [x 1 ys [2 3]] `(a ~x ~@ys b))", "(a 1 2 3 b)");
+    assert_evals("(let [ys '(2 3)] `[~@ys ~@ys])", "[2 3 2 3]");
+    assert_evals(
+        "`(a `(b ~(c ~(nth [1] 0))))",
+        "(a (quasiquote (b (unquote (c 1)))))",
+    );
+    assert_evals(
+        "(defmacro unless2 [c body] `(if ~c () (do2 ~@body))) (defmacro do2 [a b] b) (unless2 false [1 2])",
+        "2",
+    );
+    assert_errors("`(~@1)", ErrorKind::Type);
+}
+
+// Spins until x isn't 0, so everything else gets a turn first.
+const WAIT: &str = "(set x 0) (defn wait [] (cond (= x 0) (wait) true x))";
+
+#[test]
+fn processes_take_turns() {
+    assert_evals("[(self) (= (self) (self))]", "[<pid 1> true]");
+    assert_evals("(spawn (fn [] 1))", "<pid 2>");
+    assert_evals(&format!("{WAIT} (spawn (fn [a] (set x a)) 7) (wait)"), "7");
+    // One that never stops doesn't stop the rest.
+    assert_evals(
+        &format!("{WAIT} (defn spin [] (spin)) (spawn spin) (spawn (fn [] (set x 1))) (wait)"),
+        "1",
+    );
+    assert_evals(
+        &format!("{WAIT} (spawn (fn [p] (set x [p (self)])) (self)) (wait)"),
+        "[<pid 1> <pid 2>]",
+    );
+    assert_errors("(spawn 1)", ErrorKind::Type);
+}
+
+#[test]
+fn processes_exit() {
+    // A crash or exit elsewhere leaves this process running.
+    assert_evals(
+        &format!("{WAIT} (spawn (fn [] (nope))) (spawn (fn [] (exit 'bye))) (spawn (fn [] (set x 2))) (wait)"),
+        "2",
+    );
+
+    // The crash is kept for whoever's running things to report; the exit
+    // isn't.
+    let mut vm = Vm::new();
+    let module = vm.anonymous_module("*test*");
+    let code = compile_in(
+        &mut vm,
+        module.clone(),
+        &format!("{WAIT} (spawn (fn [] (exit 'bye))) (spawn (fn [] (nope))) (spawn (fn [] (set x 2))) (wait)"),
+    );
+    vm.run_to_completion(module, code).unwrap();
+    let crashes = vm.take_crashes();
+    let [(pid, err)] = crashes.as_slice() else {
+        panic!("expected one crash, got {}", crashes.len());
+    };
+    assert_eq!("<pid 3>", pid.to_string());
+    assert_eq!(ErrorKind::UnboundSymbol, err.kind);
+    assert!(vm.take_crashes().is_empty());
+
+    let mut vm = Vm::new();
+    let module = vm.anonymous_module("*test*");
+    let code = compile_in(
+        &mut vm,
+        module.clone(),
+        "(try (exit 'done) (catch e 'caught))",
+    );
+    let err = vm
+        .run_to_completion(module, code)
+        .err()
+        .expect("should exit");
+    assert_eq!(ErrorKind::Exit, err.kind);
+    assert_eq!("done", err.value.unwrap().format(&vm));
+}
+
+#[test]
+fn receive_matches_selectively() {
+    assert_evals(
+        "(send (self) [1 'a]) (send (self) [2 'b]) [(receive [2 x] x) (receive [n x] [n x])]",
+        "[b [1 a]]",
+    );
+    assert_evals(
+        "(send (self) 'skip) (send (self) 5) [(receive 5 'five) (receive 'skip 'skipped)]",
+        "[five skipped]",
+    );
+    assert_evals(
+        "(send (self) [true \"s\" 1.5]) (receive [false _ _] 1 [true \"s\" f] f)",
+        "1.5",
+    );
+    // Names bound by the pattern are closed over like any other.
+    assert_evals("(send (self) 3) ((receive x (fn [] x)))", "3");
+    assert_errors("(send 1 2)", ErrorKind::Type);
+}
+
+#[test]
+fn receive_waits_for_messages() {
+    assert_evals(
+        "(defn pong [] (receive [from 'ping] (send from 'pong)))
+         (let [p (spawn pong)]
+           (send p [(self) 'ping])
+           (receive 'pong 'got-pong (after 1000 'timeout)))",
+        "got-pong",
+    );
+    assert_evals("(receive x x (after 5 'late))", "late");
+    assert_evals("(send (self) 1) (receive x x (after 0 'none))", "1");
+    assert_evals(
+        "(spawn (fn [p] (receive _ () (after 5 (send p 'woke)))) (self)) (receive x x)",
+        "woke",
+    );
+    assert_errors("(receive x x (after 'soon 1))", ErrorKind::Type);
+    // Nothing will ever be sent.
+    assert_errors("(receive x x)", ErrorKind::Deadlock);
+}
+
+#[test]
+fn receive_blocks_inside_call() {
+    let mut vm = Vm::new();
+    let module = vm.anonymous_module("*test*");
+    let code = compile_in(
+        &mut vm,
+        module.clone(),
+        "(defn f [] (spawn (fn [p] (send p 'hi)) (self)) (receive x x))",
+    );
+    let Val::Function(f) = vm.run_to_completion(module, code).unwrap() else {
+        panic!("defn should evaluate to a function");
+    };
+    let result = vm.call(&f, vec![]).unwrap();
+    assert_eq!("hi", result.format(&vm));
+}
+
+#[test]
+fn receive_in_macros_lets_others_run() {
+    // Expanding m, via eval, has to wait for the sender -- which only runs
+    // after the setter already queued ahead of it.
+    assert_evals(
+        "(set x 0)
+         (defmacro m [] (spawn (fn [p] (send p x)) (self)) (receive v v))
+         (spawn (fn [] (set x 1)))
+         [(eval \"(m)\") x]",
+        "[1 1]",
+    );
+}
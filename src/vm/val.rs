@@ -2,9 +2,9 @@ use std::rc::Rc;
 use std::str;
 use std::{cell::RefCell, fmt::Write};
 
-use super::proc::Proc;
-use super::Vm;
-use super::{module::Module, InternedSymbol};
+use super::proc::{Pid, Proc};
+use super::{interns, module::Module, InternedSymbol};
+use super::{Code, Error, Vm};
 
 #[derive(Clone)]
 pub(crate) enum Val {
@@ -16,7 +16,11 @@ pub(crate) enum Val {
     List(Vec<Val>),
     Vec(Vec<Val>),
     Builtin(BuiltinVal),
+    Function(FunctionVal),
+    // Called by the compiler on unevaluated forms; see Vm::macroexpand_1.
+    Macro(FunctionVal),
     Module(Rc<RefCell<Module>>),
+    Pid(Pid),
 }
 
 #[derive(Clone)]
@@ -25,9 +29,69 @@ pub(crate) struct BuiltinVal {
     pub(crate) code: Builtin,
 }
 
-pub(crate) type Builtin = fn(&mut Vm, &mut Proc, &[Val]) -> Val;
+pub(crate) type Builtin = fn(&mut Vm, &mut Proc, &[Val]) -> Result<Val, Error>;
+
+#[derive(Clone)]
+pub(crate) struct FunctionVal {
+    pub(crate) name: String,
+    // The function body lives inline in the code unit it was compiled as part
+    // of; entry is the offset of its first op.
+    pub(crate) code: Rc<Code>,
+    pub(crate) entry: usize,
+    pub(crate) nparams: usize,
+    // (slot in callee, value) -- closed over at MakeFunction time.
+    pub(crate) captures: Vec<(usize, Val)>,
+    // XXX: this forms a cycle when the function is bound in its own module.
+    pub(crate) module: Rc<RefCell<Module>>,
+}
 
 impl Val {
+    pub(crate) fn unit() -> Val {
+        Val::List(Vec::with_capacity(0))
+    }
+
+    // false and () are falsy; everything else is truthy.
+    pub(crate) fn truthy(&self) -> bool {
+        !matches!(
+            self,
+            Val::Boolean(false) | Val::Symbol(None, interns::FALSE)
+        ) && !matches!(self, Val::List(ns) if ns.is_empty())
+    }
+
+    // Whether v matches self, a pattern as receive compiles it:
+    //
+    //   _            anything
+    //   x            anything, bound to x
+    //   true, false  themselves
+    //   'x           x exactly
+    //   [p ...]      a vector as long, each element matching
+    //
+    // and anything else, itself.  Bound values are pushed to binds in the
+    // order their names appear.
+    pub(crate) fn matches(&self, vm: &Vm, v: &Val, binds: &mut Vec<Val>) -> bool {
+        let quote = vm.interns.find("quote");
+        match self {
+            &Val::Symbol(None, s) if s == interns::TRUE || s == interns::FALSE => {
+                *v == Val::Boolean(s == interns::TRUE) || v == self
+            }
+            &Val::Symbol(None, s) if Some(s) == vm.interns.find("_") => true,
+            Val::Symbol(None, _) => {
+                binds.push(v.clone());
+                true
+            }
+            Val::List(q) if q.len() == 2 && quote.is_some_and(|quote| q[0] == quote.into()) => {
+                q[1] == *v
+            }
+            Val::Vec(ps) => match v {
+                Val::Vec(vs) if ps.len() == vs.len() => {
+                    ps.iter().zip(vs).all(|(p, v)| p.matches(vm, v, binds))
+                }
+                _ => false,
+            },
+            _ => self == v,
+        }
+    }
+
     pub(crate) fn format(&self, vm: &Vm) -> String {
         match self {
             &Val::Symbol(None, s) => str::from_utf8(vm.interns.resolve(s))
@@ -73,10 +137,43 @@ impl Val {
             Val::Builtin(BuiltinVal { name, .. }) => {
                 format!("<builtin {name}>")
             }
+            Val::Function(FunctionVal { name, module, .. }) => {
+                format!("<fn {}/{name}>", module.borrow().name)
+            }
+            Val::Macro(FunctionVal { name, module, .. }) => {
+                format!("<macro {}/{name}>", module.borrow().name)
+            }
             Val::Module(rmod) => {
                 let name = &rmod.borrow().name;
                 format!("<module {name}>")
             }
+            Val::Pid(pid) => pid.to_string(),
+        }
+    }
+}
+
+impl PartialEq for Val {
+    fn eq(&self, other: &Self) -> bool {
+        match (self, other) {
+            (Val::Symbol(m1, s1), Val::Symbol(m2, s2)) => m1 == m2 && s1 == s2,
+            (Val::Boolean(b1), Val::Boolean(b2)) => b1 == b2,
+            (Val::Integer(i1), Val::Integer(i2)) => i1 == i2,
+            (Val::Float(f1), Val::Float(f2)) => f1 == f2,
+            (Val::String(s1), Val::String(s2)) => s1 == s2,
+            (Val::List(ns1), Val::List(ns2)) | (Val::Vec(ns1), Val::Vec(ns2)) => ns1 == ns2,
+            (Val::Builtin(b1), Val::Builtin(b2)) => b1.name == b2.name,
+            (Val::Function(f1), Val::Function(f2)) | (Val::Macro(f1), Val::Macro(f2)) => {
+                Rc::ptr_eq(&f1.code, &f2.code)
+                    && f1.entry == f2.entry
+                    && f1
+                        .captures
+                        .iter()
+                        .map(|c| &c.1)
+                        .eq(f2.captures.iter().map(|c| &c.1))
+            }
+            (Val::Module(m1), Val::Module(m2)) => Rc::ptr_eq(m1, m2),
+            (Val::Pid(p1), Val::Pid(p2)) => p1 == p2,
+            _ => false,
         }
     }
 }
@@ -0,0 +1,86 @@
+// LEB128 operands: seven bits a byte, least significant first, the top bit
+// set on all but the last.  Small numbers -- which most operands are -- take
+// one byte.
+
+// Forward jumps are emitted before we know how far they go, so their
+// operands are padded out to a fixed width and patched in place.
+pub(crate) const JUMP_WIDTH: usize = 4;
+
+pub(crate) fn put(out: &mut Vec<u8>, mut n: u64) {
+    loop {
+        let b = (n & 0x7f) as u8;
+        n >>= 7;
+        if n == 0 {
+            out.push(b);
+            return;
+        }
+        out.push(b | 0x80);
+    }
+}
+
+pub(crate) fn put_signed(out: &mut Vec<u8>, mut n: i64) {
+    loop {
+        let b = (n & 0x7f) as u8;
+        n >>= 7;
+        // Done once the rest is all sign, and b's top bit agrees.
+        if (n == 0 && b & 0x40 == 0) || (n == -1 && b & 0x40 != 0) {
+            out.push(b);
+            return;
+        }
+        out.push(b | 0x80);
+    }
+}
+
+// Fills all of slot, however small n is.  None if n doesn't fit.
+pub(crate) fn put_padded(slot: &mut [u8], mut n: u64) -> Option<()> {
+    let (last, init) = slot.split_last_mut()?;
+    for b in init {
+        *b = (n & 0x7f) as u8 | 0x80;
+        n >>= 7;
+    }
+    if n > 0x7f {
+        return None;
+    }
+    *last = n as u8;
+    Some(())
+}
+
+// Reads from bytes at *at, advancing it.  None if it runs off the end or
+// overflows.
+pub(crate) fn get(bytes: &[u8], at: &mut usize) -> Option<u64> {
+    let mut n = 0u64;
+    let mut shift = 0;
+    loop {
+        let b = *bytes.get(*at)?;
+        *at += 1;
+        let part = u64::from(b & 0x7f);
+        if shift >= 64 || (shift == 63 && part > 1) {
+            return None;
+        }
+        n |= part << shift;
+        shift += 7;
+        if b & 0x80 == 0 {
+            return Some(n);
+        }
+    }
+}
+
+pub(crate) fn get_signed(bytes: &[u8], at: &mut usize) -> Option<i64> {
+    let mut n = 0i64;
+    let mut shift = 0;
+    loop {
+        let b = *bytes.get(*at)?;
+        *at += 1;
+        if shift >= 64 {
+            return None;
+        }
+        n |= i64::from(b & 0x7f) << shift;
+        shift += 7;
+        if b & 0x80 == 0 {
+            if shift < 64 && b & 0x40 != 0 {
+                n |= -1 << shift;
+            }
+            return Some(n);
+        }
+    }
+}
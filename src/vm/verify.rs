@@ -0,0 +1,265 @@
+use std::collections::HashMap;
+
+use num_traits::FromPrimitive;
+
+use super::{varint, Code, Const, Error, ErrorKind, Op, TraceFrame};
+
+// Checks code before anything runs it: every op valid, every operand in
+// range, every jump landing on an op in the same function, and the stack
+// the same depth however each op is reached.  Top-level code must leave the
+// stack empty; function bodies must end in Return with just the result on
+// it.
+//
+// Errors are located at the offending op.
+pub(crate) fn verify(code: &Code, name: &str) -> Result<(), Error> {
+    Verifier { code }
+        .region(0, code.bytes.len(), true)
+        .map_err(|(offset, message)| {
+            let mut err = Error::new(ErrorKind::Bytecode, format!("{message} at offset {offset}"));
+            err.trace = vec![TraceFrame {
+                name: name.to_string(),
+                file: code.file.clone(),
+                range: code.range_at(offset),
+            }];
+            err
+        })
+}
+
+struct Verifier<'c> {
+    code: &'c Code,
+}
+
+// One decoded op.
+struct Insn {
+    pops: usize,
+    pushes: usize,
+    flow: Flow,
+    next: usize,
+}
+
+enum Flow {
+    Next,
+    // Unconditionally.
+    Jump(usize),
+    // Or fall through.
+    Branch(usize),
+    // Falls through, but also lands at the target with the error pushed
+    // if anything raises.
+    Handler(usize),
+    // Pushes the next message and falls through, or lands at the target
+    // without one when the receive times out.
+    Receive(usize),
+    Return,
+    Throw,
+}
+
+type Failure = (usize, String);
+
+struct Region {
+    end: usize,
+    top: bool,
+    insns: HashMap<usize, Insn>,
+    // Depth on arrival at each op we've reached so far.
+    depths: HashMap<usize, usize>,
+    // Reached, but not yet followed.
+    work: Vec<usize>,
+}
+
+impl Region {
+    // Control gets to at, from the op at from.
+    fn arrive(&mut self, at: usize, from: usize, depth: usize) -> Result<(), Failure> {
+        if at == self.end {
+            return match (self.top, depth) {
+                (true, 0) => Ok(()),
+                (true, n) => Err((from, format!("leaves {n} value(s) on the stack at the end"))),
+                (false, _) => Err((from, "runs off the end of the function".to_string())),
+            };
+        }
+        if !self.insns.contains_key(&at) {
+            return Err((from, format!("jump to {at}, which isn't an op here")));
+        }
+        match self.depths.insert(at, depth) {
+            None => self.work.push(at),
+            Some(d) if d != depth => {
+                return Err((
+                    from,
+                    format!("reaches {at} with stack depth {depth}, but elsewhere it's {d}"),
+                ))
+            }
+            Some(_) => {}
+        }
+        Ok(())
+    }
+}
+
+impl<'c> Verifier<'c> {
+    // bytes[start..end] is top-level code, or a function body.
+    fn region(&self, start: usize, end: usize, top: bool) -> Result<(), Failure> {
+        let mut insns = HashMap::new();
+        let mut at = start;
+        while at < end {
+            let insn = self.decode(at, end)?;
+            let next = insn.next;
+            insns.insert(at, insn);
+            at = next;
+        }
+        if !top && start == end {
+            return Err((start, "empty function body".to_string()));
+        }
+
+        let mut r = Region {
+            end,
+            top,
+            insns,
+            depths: HashMap::new(),
+            work: vec![],
+        };
+        if start < end {
+            r.arrive(start, start, 0)?;
+        }
+        while let Some(at) = r.work.pop() {
+            let insn = &r.insns[&at];
+            let depth = r.depths[&at];
+            if insn.pops > depth {
+                return Err((at, "stack underflow".to_string()));
+            }
+            let after = depth - insn.pops + insn.pushes;
+            let next = insn.next;
+            match insn.flow {
+                Flow::Next => r.arrive(next, at, after)?,
+                Flow::Jump(target) => r.arrive(target, at, after)?,
+                Flow::Branch(target) => {
+                    r.arrive(next, at, after)?;
+                    r.arrive(target, at, after)?;
+                }
+                Flow::Handler(target) => {
+                    r.arrive(next, at, after)?;
+                    r.arrive(target, at, after + 1)?;
+                }
+                Flow::Receive(target) => {
+                    r.arrive(next, at, after)?;
+                    r.arrive(target, at, after - 1)?;
+                }
+                Flow::Return if top => return Err((at, "return from top-level".to_string())),
+                Flow::Return if depth != 1 => {
+                    return Err((at, format!("returns with stack depth {depth}, not 1")))
+                }
+                Flow::Return | Flow::Throw => {}
+            }
+        }
+        Ok(())
+    }
+
+    fn decode(&self, at: usize, end: usize) -> Result<Insn, Failure> {
+        let bytes = &self.code.bytes[..end];
+        let b = bytes[at];
+        let op = Op::from_u8(b).ok_or_else(|| (at, format!("invalid opcode {b}")))?;
+        let mut ip = at + 1;
+        let mut n = || {
+            varint::get(bytes, &mut ip)
+                .and_then(|n| usize::try_from(n).ok())
+                .ok_or_else(|| (at, format!("bad operand to {op}")))
+        };
+        let (pops, pushes, flow) = match op {
+            Op::Nop | Op::PopHandler => (0, 0, Flow::Next),
+            Op::ImmediateBooleanTrue | Op::ImmediateBooleanFalse => (0, 1, Flow::Next),
+            Op::ImmediateInteger => {
+                varint::get_signed(bytes, &mut ip)
+                    .ok_or_else(|| (at, format!("bad operand to {op}")))?;
+                (0, 1, Flow::Next)
+            }
+            Op::Constant => {
+                let i = n()?;
+                self.constant(at, i)?;
+                (0, 1, Flow::Next)
+            }
+            Op::ConsList | Op::ConsVec | Op::ConcatList | Op::ConcatVec => (n()?, 1, Flow::Next),
+            Op::Drop => (1, 0, Flow::Next),
+            Op::Eval => (1, 1, Flow::Next),
+            Op::Call => match n()? {
+                0 => return Err((at, "call without callee".to_string())),
+                n => (n, 1, Flow::Next),
+            },
+            Op::Return => (1, 0, Flow::Return),
+            Op::Dup => (1, 2, Flow::Next),
+            Op::JumpRelative => {
+                let target = at
+                    .checked_sub(n()?)
+                    .ok_or_else(|| (at, "jump out of range".to_string()))?;
+                (0, 0, Flow::Jump(target))
+            }
+            Op::JumpForward | Op::JumpIfFalse | Op::PushHandler | Op::ReceiveNext => {
+                let target = at
+                    .checked_add(n()?)
+                    .ok_or_else(|| (at, "jump out of range".to_string()))?;
+                match op {
+                    Op::JumpForward => (0, 0, Flow::Jump(target)),
+                    Op::JumpIfFalse => (1, 0, Flow::Branch(target)),
+                    Op::PushHandler => (0, 0, Flow::Handler(target)),
+                    _ => (0, 1, Flow::Receive(target)),
+                }
+            }
+            Op::LoadLocal => {
+                n()?;
+                (0, 1, Flow::Next)
+            }
+            Op::StoreLocal => {
+                n()?;
+                (1, 0, Flow::Next)
+            }
+            Op::LoadGlobal | Op::StoreGlobal => {
+                let i = n()?;
+                if i >= self.code.globals.len() {
+                    return Err((at, format!("bad global {i}")));
+                }
+                match op {
+                    Op::LoadGlobal => (0, 1, Flow::Next),
+                    _ => (1, 1, Flow::Next),
+                }
+            }
+            Op::MakeFunction => {
+                let name = n()?;
+                if !matches!(self.constant(at, name)?, Const::String(_)) {
+                    return Err((at, "MakeFunction expects a string name".to_string()));
+                }
+                let _nparams = n()?;
+                for _ in 0..n()? {
+                    // (callee slot, slot)
+                    n()?;
+                    n()?;
+                }
+                let len = n()?;
+                let body = ip;
+                let body_end = body
+                    .checked_add(len)
+                    .filter(|&e| e <= end)
+                    .ok_or_else(|| (at, "function body out of range".to_string()))?;
+                self.region(body, body_end, false)?;
+                ip = body_end;
+                (0, 1, Flow::Next)
+            }
+            Op::Define | Op::DefineMacro => (2, 1, Flow::Next),
+            Op::Throw => (1, 0, Flow::Throw),
+            Op::ReceiveStart | Op::ReceiveAccept => (1, 0, Flow::Next),
+            Op::Match => {
+                let pattern = n()?;
+                self.constant(at, pattern)?;
+                n()?;
+                (1, 1, Flow::Next)
+            }
+        };
+        Ok(Insn {
+            pops,
+            pushes,
+            flow,
+            next: ip,
+        })
+    }
+
+    fn constant(&self, at: usize, i: usize) -> Result<&'c Const, Failure> {
+        self.code
+            .consts
+            .get(i)
+            .ok_or_else(|| (at, format!("bad constant {i}")))
+    }
+}